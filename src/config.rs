@@ -0,0 +1,147 @@
+use std::{env, fmt::Display, path::PathBuf, str::FromStr, sync::LazyLock};
+
+use url::Url;
+
+pub static CONFIG: LazyLock<Config> = LazyLock::new(Config::from_env);
+
+/// Seconds between pings sent to WebSocket clients when `WEBSOCKET_PING_INTERVAL` is unset.
+pub const DEFAULT_WEBSOCKET_PING_INTERVAL: u64 = 30;
+/// Seconds a WebSocket client may stay silent when `WEBSOCKET_TIMEOUT` is unset.
+pub const DEFAULT_WEBSOCKET_TIMEOUT: u64 = 75;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum StorageBackend {
+    Fs,
+    Sqlite,
+    Memory,
+}
+
+impl FromStr for StorageBackend {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "fs" => Ok(Self::Fs),
+            "sqlite" => Ok(Self::Sqlite),
+            "memory" => Ok(Self::Memory),
+            _ => Err(format!("unknown storage backend: {s}")),
+        }
+    }
+}
+
+/// Bucket that object blobs are stored in instead of the local storage directory.
+#[derive(Clone, Debug)]
+pub struct S3Config {
+    pub endpoint: Url,
+    pub region: String,
+    pub bucket: String,
+    pub access_key_id: String,
+    pub secret_access_key: String,
+}
+
+impl S3Config {
+    fn from_env() -> Option<Self> {
+        let endpoint = env::var("S3_ENDPOINT").ok()?;
+        Some(Self {
+            endpoint: endpoint
+                .parse()
+                .unwrap_or_else(|e| panic!("Invalid value for S3_ENDPOINT: {e}")),
+            region: env_or("S3_REGION", "us-east-1".to_owned()),
+            bucket: env_required("S3_BUCKET"),
+            access_key_id: env_required("S3_ACCESS_KEY_ID"),
+            secret_access_key: env_required("S3_SECRET_ACCESS_KEY"),
+        })
+    }
+}
+
+pub struct Config {
+    pub storage_backend: StorageBackend,
+    pub s3: Option<S3Config>,
+    /// Total blob bytes the memory backend may hold, unlimited when unset. Only accepted by
+    /// the memory backend.
+    pub memory_limit: Option<u64>,
+    /// Blob bytes a session may hold in the storage directory. This and the other limits
+    /// below are only accepted by the fs and sqlite backends without S3.
+    pub session_max_bytes: Option<u64>,
+    /// Objects a session may hold in the storage directory.
+    pub session_max_objects: Option<u64>,
+    /// Blob bytes the whole storage directory may hold.
+    pub storage_max_bytes: Option<u64>,
+    /// Whether identical blobs in the storage directory are stored only once. Only accepted
+    /// by the fs and sqlite backends without S3.
+    pub blob_dedup: bool,
+    /// Whether blobs are stored zstd compressed when worthwhile. Only accepted by the fs
+    /// backend without S3.
+    pub blob_compression: bool,
+    /// File holding the base64 encoded key that files are sealed at rest with. Only accepted
+    /// by the fs backend without S3. Resumable uploads are refused while it is set, as their
+    /// partial blobs would be stored unsealed.
+    pub master_key_file: Option<PathBuf>,
+    /// Lifetime in seconds of sessions that were created without one, unlimited when unset.
+    pub session_ttl: Option<u64>,
+    /// Seconds between sweeps for expired sessions.
+    pub reap_interval: u64,
+    /// Byte length a single resumable upload may announce, unlimited when unset.
+    pub upload_max_bytes: Option<u64>,
+    /// Seconds an unfinished resumable upload is kept after it was last written to.
+    pub upload_expiry: u64,
+    /// Seconds between pings sent to WebSocket clients.
+    pub websocket_ping_interval: u64,
+    /// Seconds a WebSocket client may stay silent, pongs included, before it is dropped.
+    /// Longer than the ping interval, as clients only answer pings.
+    pub websocket_timeout: u64,
+}
+
+impl Config {
+    pub fn from_env() -> Self {
+        let config = Self {
+            storage_backend: env_or("STORAGE_BACKEND", StorageBackend::Fs),
+            s3: S3Config::from_env(),
+            memory_limit: env_opt("MEMORY_LIMIT"),
+            session_max_bytes: env_opt("SESSION_MAX_BYTES"),
+            session_max_objects: env_opt("SESSION_MAX_OBJECTS"),
+            storage_max_bytes: env_opt("STORAGE_MAX_BYTES"),
+            blob_dedup: env_or("BLOB_DEDUP", false),
+            blob_compression: env_or("BLOB_COMPRESSION", false),
+            master_key_file: env_opt("MASTER_KEY_FILE"),
+            session_ttl: env_opt("SESSION_TTL"),
+            reap_interval: env_or("SESSION_REAP_INTERVAL", 60),
+            upload_max_bytes: env_opt("UPLOAD_MAX_BYTES"),
+            upload_expiry: env_or("UPLOAD_EXPIRY", 24 * 60 * 60),
+            websocket_ping_interval: env_or(
+                "WEBSOCKET_PING_INTERVAL",
+                DEFAULT_WEBSOCKET_PING_INTERVAL,
+            ),
+            websocket_timeout: env_or("WEBSOCKET_TIMEOUT", DEFAULT_WEBSOCKET_TIMEOUT),
+        };
+        // Idle clients would otherwise be dropped before they are even pinged.
+        if config.websocket_timeout <= config.websocket_ping_interval.max(1) {
+            panic!("WEBSOCKET_TIMEOUT must be longer than WEBSOCKET_PING_INTERVAL");
+        }
+        config
+    }
+}
+
+fn env_or<T>(key: &str, default: T) -> T
+where
+    T: FromStr,
+    T::Err: Display,
+{
+    env_opt(key).unwrap_or(default)
+}
+
+fn env_opt<T>(key: &str) -> Option<T>
+where
+    T: FromStr,
+    T::Err: Display,
+{
+    env::var(key).ok().map(|value| {
+        value
+            .parse()
+            .unwrap_or_else(|e| panic!("Invalid value for {key}: {e}"))
+    })
+}
+
+fn env_required(key: &str) -> String {
+    env::var(key).unwrap_or_else(|_| panic!("Missing required variable {key}"))
+}
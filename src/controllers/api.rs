@@ -1,11 +1,15 @@
-use std::sync::Arc;
+use std::{io::Error as IoError, sync::Arc};
 
 use axum::{
+    body::Body,
     extract::{Path, State},
-    http::{HeaderMap, StatusCode},
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
     routing::{get, head, post},
     Json, Router,
 };
+use futures::TryStreamExt;
+use tokio_util::io::StreamReader;
 use tracing::{event, Level};
 
 use crate::{
@@ -15,7 +19,11 @@ use crate::{
         session::{CreateSession, SessionDto, SessionId},
     },
     repositories::session::SessionRepository,
-    services::session::{SessionError, SessionService},
+    services::{
+        archive::{export_session, import_session},
+        session::{SessionError, SessionService},
+    },
+    utils::sync::SyncReader,
     ConcreteSessionService, ObjectServiceFactory,
 };
 
@@ -36,10 +44,12 @@ impl ApiController {
         Router::new()
             .route("/session", post(create_session))
             .route("/session/encrypted", post(create_session_encrypted))
+            .route("/session/import", post(import_session_archive))
             .route(
                 "/session/{sid}",
                 head(head_session).get(get_session).delete(delete_session),
             )
+            .route("/session/{sid}/export", get(export_session_archive))
             .route(
                 "/session/{sid}/objects",
                 get(list_objects).post(create_object),
@@ -54,10 +64,12 @@ impl ApiController {
 
 async fn create_session(
     State(controller): State<Arc<ApiController>>,
+    request: Option<Json<CreateSession>>,
 ) -> Result<Json<SessionDto>, StatusCode> {
+    let request = request.map(|Json(req)| req).unwrap_or_default();
     normalize_json_result(
         "create session",
-        controller.session.create(None).await.map(Into::into),
+        controller.session.create(request).await.map(Into::into),
     )
 }
 
@@ -65,11 +77,24 @@ async fn create_session_encrypted(
     State(controller): State<Arc<ApiController>>,
     Json(request): Json<CreateSession>,
 ) -> Result<Json<SessionDto>, StatusCode> {
+    if request.crypto.is_none() {
+        return Err(StatusCode::UNPROCESSABLE_ENTITY);
+    }
     normalize_json_result(
         "create session encrypted",
-        controller
-            .session
-            .create(Some(request))
+        controller.session.create(request).await.map(Into::into),
+    )
+}
+
+async fn import_session_archive(
+    State(controller): State<Arc<ApiController>>,
+    body: Body,
+) -> Result<Json<SessionDto>, StatusCode> {
+    let reader = StreamReader::new(body.into_data_stream().map_err(IoError::other));
+    let reader = SyncReader::new(reader);
+    normalize_json_result(
+        "import session",
+        import_session(&controller.session, controller.object, reader)
             .await
             .map(Into::into),
     )
@@ -117,6 +142,26 @@ async fn delete_session(
     )
 }
 
+/// Downloads the session as a tar archive that `/session/import` accepts. Objects with a
+/// download limit are not exported, as that would use up a download: the manifest lists
+/// them with `omitted` set, without their content or blob, and importing skips them.
+async fn export_session_archive(
+    State(controller): State<Arc<ApiController>>,
+    Path(sid): Path<SessionId>,
+    headers: HeaderMap,
+) -> Result<Response, StatusCode> {
+    check_auth_key(&controller.session, &sid, &headers).await?;
+    let sess = normalize_result("get session", controller.session.get(&sid).await)?;
+    let service = (controller.object)(&sid);
+    let stream = normalize_result("export session", export_session(service, sess).await)?;
+    let disposition = format!("attachment; filename=\"webdrop-{sid}.tar\"");
+    let headers = [
+        (header::CONTENT_TYPE, "application/x-tar".to_owned()),
+        (header::CONTENT_DISPOSITION, disposition),
+    ];
+    Ok((headers, Body::from_stream(stream)).into_response())
+}
+
 async fn list_objects(
     State(controller): State<Arc<ApiController>>,
     Path(sid): Path<SessionId>,
@@ -140,7 +185,7 @@ async fn get_object(
 ) -> Result<Json<ObjectDto>, StatusCode> {
     check_auth_key(&controller.session, &sid, &headers).await?;
     let service = (controller.object)(&sid);
-    normalize_json_result("get object", service.get(&oid).await.map(Into::into))
+    normalize_json_result("get object", service.access(&oid).await.map(Into::into))
 }
 
 async fn create_object(
@@ -0,0 +1,249 @@
+use std::{convert::Infallible, future, sync::Arc, time::Duration};
+
+use axum::{
+    extract::{Path, Query, State},
+    http::{HeaderMap, StatusCode},
+    response::{
+        sse::{Event as SseEvent, KeepAlive},
+        IntoResponse, Sse,
+    },
+    routing::get,
+    Router,
+};
+use futures::{stream, Stream, StreamExt};
+use tracing::{event, Level};
+
+use crate::{
+    controllers::{AuthParams, ReplayParams},
+    models::{
+        event::{Event, EventId, EventKind},
+        session::SessionId,
+    },
+    utils::sync::{Lagged, Subscriber},
+    ConcreteWebSocketService, WebSocketServiceFactory,
+};
+
+use super::websocket::{check_auth_key, Delivered};
+
+const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(15);
+
+/// Streams the events of a session as Server-Sent Events, for clients that cannot keep a
+/// WebSocket open.
+pub struct EventController {
+    factory: WebSocketServiceFactory,
+}
+
+impl EventController {
+    pub fn new(factory: WebSocketServiceFactory) -> Self {
+        Self { factory }
+    }
+
+    pub fn into_router(self) -> Router {
+        let state = Arc::new(self);
+        Router::new()
+            .route("/{sid}", get(events_handler))
+            .with_state(state)
+    }
+}
+
+async fn events_handler(
+    State(controller): State<Arc<EventController>>,
+    Path(sid): Path<SessionId>,
+    Query(params): Query<AuthParams>,
+    Query(replay): Query<ReplayParams>,
+    headers: HeaderMap,
+) -> Result<impl IntoResponse, StatusCode> {
+    let service = (controller.factory)(&sid);
+    check_auth_key(&service, &sid, &params).await?;
+    let since = last_event_id(&headers)?.or(replay.since);
+    let (missed, subscriber) = service.subscribe(since);
+    let stream = event_stream(service, missed, subscriber).filter_map(|event| {
+        let sse = SseEvent::default().id(event.id.to_string());
+        let sse = match sse.json_data(&event) {
+            Ok(sse) => Some(Ok::<_, Infallible>(sse)),
+            Err(e) => {
+                event!(
+                    Level::ERROR,
+                    "Skipping unserializable event {}: {e}",
+                    event.id
+                );
+                None
+            }
+        };
+        future::ready(sse)
+    });
+    let keep_alive = KeepAlive::new()
+        .interval(KEEP_ALIVE_INTERVAL)
+        .text("keep-alive");
+    Ok(Sse::new(stream).keep_alive(keep_alive))
+}
+
+/// EventSource sends the id of the last event it received when it reconnects.
+fn last_event_id(headers: &HeaderMap) -> Result<Option<EventId>, StatusCode> {
+    let Some(value) = headers.get("Last-Event-ID") else {
+        return Ok(None);
+    };
+    value
+        .to_str()
+        .ok()
+        .and_then(|id| id.parse().ok())
+        .map(Some)
+        .ok_or(StatusCode::BAD_REQUEST)
+}
+
+/// Missed events followed by live ones, ending once the session is deleted. Clients that
+/// lag behind are told to resync.
+fn event_stream(
+    service: Arc<ConcreteWebSocketService>,
+    missed: Vec<Event>,
+    subscriber: Arc<Subscriber<Event>>,
+) -> impl Stream<Item = Event> + Send {
+    let live = stream::unfold(subscriber, move |subscriber| {
+        let service = Arc::clone(&service);
+        async move {
+            let events = match subscriber.pop().await {
+                Ok(popped) => {
+                    let mut events: Vec<Event> = popped.into_iter().collect();
+                    events.sort_by_key(|e| e.id);
+                    events
+                }
+                Err(Lagged(dropped)) => {
+                    event!(
+                        Level::WARN,
+                        "Event stream lagged behind by {dropped} events"
+                    );
+                    vec![service.resync()]
+                }
+            };
+            Some((stream::iter(events), subscriber))
+        }
+    })
+    .flatten();
+    let mut delivered = Delivered::default();
+    stream::iter(missed)
+        .chain(live)
+        .filter(move |event| future::ready(delivered.admit(event)))
+        .scan(false, |deleted, event| {
+            if *deleted {
+                return future::ready(None);
+            }
+            *deleted = matches!(event.kind, EventKind::SessionDeleted);
+            future::ready(Some(event))
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::HeaderValue;
+    use tokio::time;
+
+    use crate::{
+        models::{
+            object::{Object, ObjectId, Upload},
+            session::{CreateSession, Session},
+        },
+        repositories::{
+            memory::MemoryStore,
+            session::{AnySessionRepository, SessionMemoryRepository},
+        },
+        services::websocket::WebSocketService,
+    };
+
+    use super::*;
+
+    fn websocket_service() -> Arc<ConcreteWebSocketService> {
+        let repository = SessionMemoryRepository::new(MemoryStore::default());
+        let repository = Arc::new(AnySessionRepository::Memory(repository));
+        Arc::new(WebSocketService::new(8, repository))
+    }
+
+    fn object_deleted() -> Event {
+        Event::new(EventKind::ObjectDeleted(ObjectId::generate()))
+    }
+
+    #[test]
+    fn parse_last_event_id() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(last_event_id(&headers), Ok(None));
+        headers.insert("Last-Event-ID", HeaderValue::from_static("42"));
+        assert_eq!(last_event_id(&headers), Ok(Some(42)));
+        headers.insert("Last-Event-ID", HeaderValue::from_static("-1"));
+        assert_eq!(last_event_id(&headers), Err(StatusCode::BAD_REQUEST));
+    }
+
+    #[tokio::test]
+    async fn replay_before_live_events() {
+        let service = websocket_service();
+        let (_, subscriber) = service.subscribe(None);
+        service.publish(object_deleted());
+        service.publish(object_deleted());
+        let first = subscriber.pop().await.unwrap()[0].id;
+
+        let (missed, subscriber) = service.subscribe(Some(first));
+        let stream = event_stream(Arc::clone(&service), missed, subscriber);
+        service.publish(object_deleted());
+        let ids = stream.take(2).map(|event| event.id).collect::<Vec<_>>();
+        let ids = time::timeout(Duration::from_secs(1), ids).await.unwrap();
+        assert_eq!(ids, vec![first + 1, first + 2]);
+    }
+
+    #[tokio::test]
+    async fn skip_events_covered_by_resync() {
+        let service = websocket_service();
+        let (missed, subscriber) = service.subscribe(None);
+        let stream = event_stream(Arc::clone(&service), missed, subscriber);
+        // Overflows the backlog, which keeps only the events published past it.
+        for _ in 0..10 {
+            service.publish(object_deleted());
+        }
+        let mut stream = Box::pin(stream);
+        let resync = time::timeout(Duration::from_secs(1), stream.next())
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(matches!(resync.kind, EventKind::Resync));
+
+        // The buffered events are older than the resync and left out.
+        service.publish(object_deleted());
+        let next = time::timeout(Duration::from_secs(1), stream.next())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(next.id, resync.id + 1);
+    }
+
+    #[tokio::test]
+    async fn stream_update_events() {
+        let service = websocket_service();
+        let (missed, subscriber) = service.subscribe(None);
+        let stream = event_stream(Arc::clone(&service), missed, subscriber);
+        let obj: Object = Upload::default().into();
+        let sess = Session::new(SessionId::generate(), CreateSession::default(), None);
+        service.publish(Event::new(EventKind::ObjectUpdated(obj.to_event_dto())));
+        service.publish(Event::new(EventKind::SessionUpdated(sess.into())));
+
+        let events = stream.take(2).collect::<Vec<_>>();
+        let events = time::timeout(Duration::from_secs(1), events).await.unwrap();
+        let names: Vec<_> = events
+            .iter()
+            .map(|e| serde_json::to_value(e).unwrap()["name"].clone())
+            .collect();
+        assert_eq!(names, ["object.updated", "session.updated"]);
+    }
+
+    #[tokio::test]
+    async fn end_on_session_deleted() {
+        let service = websocket_service();
+        let (missed, subscriber) = service.subscribe(None);
+        let stream = event_stream(Arc::clone(&service), missed, subscriber);
+        service.publish(object_deleted());
+        service.publish(Event::new(EventKind::SessionDeleted));
+        service.publish(object_deleted());
+
+        let events: Vec<_> = time::timeout(Duration::from_secs(1), stream.collect())
+            .await
+            .expect("stream should end");
+        assert_eq!(events.len(), 2);
+        assert!(matches!(events[1].kind, EventKind::SessionDeleted));
+    }
+}
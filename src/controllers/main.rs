@@ -3,40 +3,71 @@ use std::sync::Arc;
 use axum::Router;
 use tower_http::services::{ServeDir, ServeFile};
 
-use crate::{ConcreteSessionService, ObjectServiceFactory, WebSocketServiceFactory};
+use crate::{
+    ConcreteSessionService, ConcreteUploadService, ObjectServiceFactory, WebSocketServiceFactory,
+};
 
 use super::{
-    api::ApiController, object::ObjectController, websocket::WebSocketController, PUBLIC_PATH,
+    api::ApiController,
+    events::EventController,
+    object::ObjectController,
+    upload::UploadController,
+    websocket::{Heartbeat, WebSocketController},
+    PUBLIC_PATH,
 };
 
 pub struct MainController {
     session: Arc<ConcreteSessionService>,
+    upload: Arc<ConcreteUploadService>,
     websocket: WebSocketServiceFactory,
     object: ObjectServiceFactory,
+    heartbeat: Heartbeat,
+    resumable_uploads: bool,
 }
 
 impl MainController {
     pub fn new(
         session: ConcreteSessionService,
+        upload: Arc<ConcreteUploadService>,
         websocket: WebSocketServiceFactory,
         object: ObjectServiceFactory,
     ) -> Self {
         Self {
             session: Arc::new(session),
+            upload,
             websocket,
             object,
+            heartbeat: Heartbeat::default(),
+            resumable_uploads: true,
         }
     }
 
+    pub fn with_heartbeat(mut self, heartbeat: Heartbeat) -> Self {
+        self.heartbeat = heartbeat;
+        self
+    }
+
+    /// Whether the tus endpoints for resumable uploads accept uploads, which keep partial
+    /// blobs unsealed until they are complete. They are refused with an error otherwise.
+    pub fn with_resumable_uploads(mut self, enabled: bool) -> Self {
+        self.resumable_uploads = enabled;
+        self
+    }
+
     pub fn into_router(self) -> Router {
         let index = format!("{PUBLIC_PATH}/index.html");
-        let ws = WebSocketController::new(self.websocket);
+        let ws = WebSocketController::new(self.websocket).with_heartbeat(self.heartbeat);
+        let events = EventController::new(self.websocket);
         let api = ApiController::new(Arc::clone(&self.session), self.object);
         let object = ObjectController::new(self.object, Arc::clone(&self.session));
+        let upload = UploadController::new(self.object, Arc::clone(&self.session), self.upload)
+            .with_enabled(self.resumable_uploads);
+        let objects = object.into_router().merge(upload.into_router());
         Router::new()
             .nest("/ws", ws.into_router())
+            .nest("/events", events.into_router())
             .nest("/api", api.into_router())
-            .nest("/objects", object.into_router())
+            .nest("/objects", objects)
             .route_service("/session/{sid}", ServeFile::new(index))
             .fallback_service(ServeDir::new(PUBLIC_PATH))
     }
@@ -1,6 +1,9 @@
 mod api;
+mod events;
 mod main;
 mod object;
+mod range;
+mod upload;
 mod websocket;
 
 use std::error::Error;
@@ -12,9 +15,15 @@ use base64::{
 };
 use serde::Deserialize;
 
-use crate::services::{object::ObjectError, session::SessionError};
+use crate::{
+    models::event::EventId,
+    services::{
+        archive::ImportError, object::ObjectError, session::SessionError, upload::UploadError,
+    },
+};
 
 pub use main::MainController;
+pub use websocket::Heartbeat;
 
 pub(super) const PUBLIC_PATH: &str = "web/build";
 
@@ -26,6 +35,9 @@ impl StatusCodeError for ObjectError {
     fn into_status_code(self) -> StatusCode {
         match self {
             Self::NotFound => StatusCode::NOT_FOUND,
+            Self::QuotaExceeded => StatusCode::PAYLOAD_TOO_LARGE,
+            Self::StorageFull => StatusCode::INSUFFICIENT_STORAGE,
+            Self::DigestMismatch => StatusCode::UNPROCESSABLE_ENTITY,
             _ => StatusCode::INTERNAL_SERVER_ERROR,
         }
     }
@@ -40,6 +52,29 @@ impl StatusCodeError for SessionError {
     }
 }
 
+impl StatusCodeError for ImportError {
+    fn into_status_code(self) -> StatusCode {
+        match self {
+            Self::InvalidArchive(_) => StatusCode::BAD_REQUEST,
+            Self::Session(e) => e.into_status_code(),
+            Self::Object(e) => e.into_status_code(),
+        }
+    }
+}
+
+impl StatusCodeError for UploadError {
+    fn into_status_code(self) -> StatusCode {
+        match self {
+            Self::NotFound => StatusCode::NOT_FOUND,
+            Self::OffsetMismatch => StatusCode::CONFLICT,
+            Self::Locked => StatusCode::LOCKED,
+            Self::TooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+            Self::Object(e) => e.into_status_code(),
+            Self::Other(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
 pub trait AuthKeyExtractor {
     fn extract_auth_key(&self) -> Result<Vec<u8>, StatusCode>;
 }
@@ -72,3 +107,9 @@ impl AuthKeyExtractor for &AuthParams {
         }
     }
 }
+
+#[derive(Deserialize)]
+pub struct ReplayParams {
+    /// Id of the last event the client received before reconnecting.
+    since: Option<EventId>,
+}
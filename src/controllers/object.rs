@@ -1,25 +1,27 @@
-use std::{
-    error::Error,
-    io::{Error as IoError, ErrorKind},
-    sync::Arc,
-};
+use std::{error::Error, io::Error as IoError, sync::Arc};
 
 use axum::{
     body::Body,
     extract::{multipart::MultipartError, DefaultBodyLimit, Multipart, Path, Query, State},
-    http::{header, HeaderMap, Response, StatusCode},
-    response::IntoResponse,
+    http::{header, response::Builder as ResponseBuilder, HeaderMap, Method, Response, StatusCode},
     routing::{get, post},
     Json, Router,
 };
+use base64::{prelude::BASE64_STANDARD, Engine};
 use futures::TryStreamExt;
 use tokio_util::io::{ReaderStream, StreamReader};
 use tracing::{event, Level};
 
 use crate::{
-    controllers::{AuthKeyExtractor, AuthParams},
+    controllers::{
+        range::{
+            etag, http_date, if_range_matches, is_not_modified, multipart_body, parse_range,
+            RangeRequest,
+        },
+        AuthKeyExtractor, AuthParams, StatusCodeError,
+    },
     models::{
-        object::{ObjectDto, ObjectId, Upload},
+        object::{FileInfo, Object, ObjectDto, ObjectId, Upload},
         session::SessionId,
     },
     repositories::{object::ObjectRepository, session::SessionRepository},
@@ -46,37 +48,134 @@ impl ObjectController {
             .route("/{sid}", post(upload_handler))
             .route("/{sid}/{oid}/{name}", get(download_handler))
             .with_state(state)
+            // Uploads are bounded by the storage quotas while they are streamed instead.
             .layer(DefaultBodyLimit::disable())
     }
 }
 
 async fn download_handler(
     State(controller): State<Arc<ObjectController>>,
-    Path((sid, oid, filename)): Path<(SessionId, ObjectId, String)>,
+    // The trailing name only makes download links readable, metadata is authoritative.
+    Path((sid, oid, _)): Path<(SessionId, ObjectId, String)>,
     Query(params): Query<AuthParams>,
-) -> Result<impl IntoResponse, StatusCode> {
+    method: Method,
+    headers: HeaderMap,
+) -> Result<Response<Body>, StatusCode> {
     let service = (controller.factory)(&sid);
     check_object_auth_key(&service, &oid, &params).await?;
 
-    match service.download(&oid).await {
-        Ok(reader) => {
-            let mime = mime_guess::from_path(&filename).first_or_octet_stream();
-            let body = Body::from_stream(ReaderStream::new(reader));
-            Response::builder()
-                .header(header::CONTENT_TYPE, mime.essence_str())
-                .body(body)
-                .map_err(|e| {
-                    event!(Level::ERROR, "Download response error: {e}");
-                    StatusCode::INTERNAL_SERVER_ERROR
-                })
+    let obj = service.get(&oid).await.map_err(download_error)?;
+    let etag = etag(&obj);
+    let builder = Response::builder()
+        .header(header::ETAG, &etag)
+        .header(header::LAST_MODIFIED, http_date(obj.timestamp));
+    let builder = with_digest(builder, &obj);
+    if is_not_modified(&headers, &etag, obj.timestamp) {
+        return build_response(builder.status(StatusCode::NOT_MODIFIED), Body::empty());
+    }
+    // HEAD is routed here as well, and only describing the blob must neither open it nor
+    // count as a download. Ranges are only defined for GET, so HEAD describes the whole blob.
+    if method == Method::HEAD {
+        let builder = match obj.size {
+            Some(size) => builder
+                .header(header::ACCEPT_RANGES, "bytes")
+                .header(header::CONTENT_LENGTH, size),
+            None => builder,
+        };
+        return build_response(
+            builder.header(header::CONTENT_TYPE, object_mime(obj)),
+            Body::empty(),
+        );
+    }
+
+    // Ranges need the blob size, which objects uploaded before it was recorded lack.
+    let Some(size) = obj.size else {
+        let (obj, mut readers) = service.download(&oid, &[]).await.map_err(download_error)?;
+        let body = Body::from_stream(ReaderStream::new(readers.remove(0)));
+        return build_response(builder.header(header::CONTENT_TYPE, object_mime(obj)), body);
+    };
+    let builder = builder.header(header::ACCEPT_RANGES, "bytes");
+    let range = match headers.get(header::RANGE).and_then(|v| v.to_str().ok()) {
+        Some(value) if if_range_matches(&headers, &etag, obj.timestamp) => parse_range(value, size),
+        _ => RangeRequest::Full,
+    };
+    let ranges = match range {
+        RangeRequest::Full => Vec::new(),
+        RangeRequest::Partial(ranges) => ranges,
+        RangeRequest::Unsatisfiable => {
+            let builder = builder
+                .status(StatusCode::RANGE_NOT_SATISFIABLE)
+                .header(header::CONTENT_RANGE, format!("bytes */{size}"));
+            return build_response(builder, Body::empty());
         }
-        Err(err) => match err {
-            ObjectError::Other(e) => {
-                event!(Level::ERROR, "Download error: {e}");
-                Err(StatusCode::INTERNAL_SERVER_ERROR)
-            }
-            _ => Err(StatusCode::NOT_FOUND),
-        },
+    };
+
+    let (obj, mut readers) = service
+        .download(&oid, &ranges)
+        .await
+        .map_err(download_error)?;
+    let mime = object_mime(obj);
+    match ranges.as_slice() {
+        [] => {
+            let body = Body::from_stream(ReaderStream::new(readers.remove(0)));
+            let builder = builder
+                .header(header::CONTENT_TYPE, mime)
+                .header(header::CONTENT_LENGTH, size);
+            build_response(builder, body)
+        }
+        [range] => {
+            let body = Body::from_stream(ReaderStream::new(readers.remove(0)));
+            let content_range = format!("bytes {}-{}/{size}", range.start, range.end - 1);
+            let builder = builder
+                .status(StatusCode::PARTIAL_CONTENT)
+                .header(header::CONTENT_TYPE, mime)
+                .header(header::CONTENT_LENGTH, range.end - range.start)
+                .header(header::CONTENT_RANGE, content_range);
+            build_response(builder, body)
+        }
+        ranges => {
+            let (body, content_type, len) = multipart_body(readers, ranges, size, &mime);
+            let builder = builder
+                .status(StatusCode::PARTIAL_CONTENT)
+                .header(header::CONTENT_TYPE, content_type)
+                .header(header::CONTENT_LENGTH, len);
+            build_response(builder, body)
+        }
+    }
+}
+
+/// Sends the digest of the whole blob, both as the `Repr-Digest` of RFC 9530 and as the
+/// `Digest` of RFC 3230 that it obsoletes.
+fn with_digest(builder: ResponseBuilder, obj: &Object) -> ResponseBuilder {
+    let Some(digest) = obj.sha256.as_deref().and_then(|hex| hex::decode(hex).ok()) else {
+        return builder;
+    };
+    let encoded = BASE64_STANDARD.encode(digest);
+    builder
+        .header("repr-digest", format!("sha-256=:{encoded}:"))
+        .header("digest", format!("SHA-256={encoded}"))
+}
+
+fn object_mime(obj: Object) -> String {
+    obj.mime
+        .or_else(|| guess_mime(obj.filename.as_deref()?))
+        .unwrap_or_else(|| mime_guess::mime::APPLICATION_OCTET_STREAM.to_string())
+}
+
+fn build_response(builder: ResponseBuilder, body: Body) -> Result<Response<Body>, StatusCode> {
+    builder.body(body).map_err(|e| {
+        event!(Level::ERROR, "Download response error: {e}");
+        StatusCode::INTERNAL_SERVER_ERROR
+    })
+}
+
+fn download_error(err: ObjectError) -> StatusCode {
+    match err {
+        ObjectError::Other(e) => {
+            event!(Level::ERROR, "Download error: {e}");
+            StatusCode::INTERNAL_SERVER_ERROR
+        }
+        _ => StatusCode::NOT_FOUND,
     }
 }
 
@@ -88,15 +187,27 @@ async fn upload_handler(
 ) -> Result<Json<ObjectDto>, StatusCode> {
     check_session_auth_key(&controller.session, &sid, &headers).await?;
 
+    let encrypted = is_encrypted(&controller.session, &sid).await?;
     let service = (controller.factory)(&sid);
-    let result = do_upload(service, multipart).await.map_err(|err| {
-        if let Some(e) = err.downcast_ref::<MultipartError>() {
-            event!(Level::ERROR, "Multipart error: {e}");
-            StatusCode::BAD_REQUEST
-        } else {
-            StatusCode::INTERNAL_SERVER_ERROR
-        }
-    });
+    let result = do_upload(service, multipart, encrypted)
+        .await
+        .map_err(|err| {
+            if let Some(e) = err.downcast_ref::<MultipartError>() {
+                event!(Level::ERROR, "Multipart error: {e}");
+                StatusCode::BAD_REQUEST
+            } else {
+                match err.downcast::<ObjectError>() {
+                    Ok(e) => {
+                        event!(Level::ERROR, "Upload error: {e}");
+                        e.into_status_code()
+                    }
+                    Err(e) => {
+                        event!(Level::ERROR, "Upload error: {e}");
+                        StatusCode::INTERNAL_SERVER_ERROR
+                    }
+                }
+            }
+        });
     match result {
         Ok(Some(obj)) => Ok(Json(obj)),
         Ok(None) => Err(StatusCode::BAD_REQUEST),
@@ -107,6 +218,7 @@ async fn upload_handler(
 async fn do_upload<O: ObjectRepository, S: SessionRepository>(
     service: Arc<ObjectService<O, S>>,
     mut multipart: Multipart,
+    encrypted: bool,
 ) -> Result<Option<ObjectDto>, Box<dyn Error>> {
     let mut opt_upload: Option<Upload> = None;
     while let Some(field) = multipart.next_field().await? {
@@ -118,9 +230,19 @@ async fn do_upload<O: ObjectRepository, S: SessionRepository>(
             }
             "file" if opt_upload.is_some() => {
                 let upload = opt_upload.unwrap();
-                let stream = field.map_err(|err| IoError::new(ErrorKind::Other, err));
+                let file = if encrypted {
+                    FileInfo::default()
+                } else {
+                    let filename = field.file_name().map(ToOwned::to_owned);
+                    let mime = field
+                        .content_type()
+                        .map(ToOwned::to_owned)
+                        .or_else(|| guess_mime(filename.as_deref()?));
+                    FileInfo { filename, mime }
+                };
+                let stream = field.map_err(IoError::other);
                 let reader = StreamReader::new(stream);
-                let obj = service.upload(upload, reader).await?;
+                let obj = service.upload(upload, file, reader).await?;
                 return Ok(Some(obj.into()));
             }
             _ => continue,
@@ -129,6 +251,28 @@ async fn do_upload<O: ObjectRepository, S: SessionRepository>(
     Ok(None)
 }
 
+/// Whether the session is end-to-end encrypted, whose clients keep the names and types of
+/// their files in the encrypted content only. Whatever they report alongside the blob is
+/// not stored then.
+pub(super) async fn is_encrypted<S: SessionRepository>(
+    service: &Arc<SessionService<S>>,
+    sid: &SessionId,
+) -> Result<bool, StatusCode> {
+    match service.get(sid).await {
+        Ok(sess) => Ok(sess.crypto.is_some()),
+        Err(SessionError::NotFound) => Err(StatusCode::NOT_FOUND),
+        Err(SessionError::Other(e)) => {
+            event!(Level::ERROR, "Session error: {e}");
+            Err(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+pub(super) fn guess_mime(filename: &str) -> Option<String> {
+    let mime = mime_guess::from_path(filename).first()?;
+    Some(mime.essence_str().to_owned())
+}
+
 async fn check_object_auth_key<O: ObjectRepository, S, E: AuthKeyExtractor>(
     service: &Arc<ObjectService<O, S>>,
     oid: &ObjectId,
@@ -139,14 +283,14 @@ async fn check_object_auth_key<O: ObjectRepository, S, E: AuthKeyExtractor>(
         Ok(true) => Ok(()),
         Ok(false) => Err(StatusCode::UNAUTHORIZED),
         Err(ObjectError::NotFound) => Err(StatusCode::NOT_FOUND),
-        Err(ObjectError::Other(e)) => {
+        Err(e) => {
             event!(Level::ERROR, "Authentication error: {e}");
             Err(StatusCode::INTERNAL_SERVER_ERROR)
         }
     }
 }
 
-async fn check_session_auth_key<S: SessionRepository, E: AuthKeyExtractor>(
+pub(super) async fn check_session_auth_key<S: SessionRepository, E: AuthKeyExtractor>(
     service: &Arc<SessionService<S>>,
     sid: &SessionId,
     extractor: E,
@@ -0,0 +1,217 @@
+use std::{io::Error as IoError, ops::Range, pin::Pin};
+
+use axum::{
+    body::{Body, Bytes},
+    http::{header, HeaderMap},
+};
+use chrono::{DateTime, Utc};
+use futures::{stream, Stream, StreamExt};
+use rand::{rngs::SmallRng, RngCore, SeedableRng};
+use tokio::io::AsyncRead;
+use tokio_util::io::ReaderStream;
+
+use crate::models::object::Object;
+
+/// Most ranges served for a single request, longer lists are answered with the whole blob.
+const MAX_RANGES: usize = 16;
+
+type ByteStream = Pin<Box<dyn Stream<Item = Result<Bytes, IoError>> + Send>>;
+
+#[derive(PartialEq, Eq, Debug)]
+pub(super) enum RangeRequest {
+    Full,
+    Partial(Vec<Range<u64>>),
+    Unsatisfiable,
+}
+
+/// Parses a `Range` header against a blob of `size` bytes. Headers that cannot be parsed are
+/// ignored so that the whole blob is served instead, as RFC 9110 allows. Overlapping and
+/// adjacent ranges are coalesced, so that no byte is served twice.
+pub(super) fn parse_range(value: &str, size: u64) -> RangeRequest {
+    let Some(specs) = value.trim().strip_prefix("bytes=") else {
+        return RangeRequest::Full;
+    };
+    let mut ranges = Vec::new();
+    for spec in specs.split(',') {
+        let Some(range) = parse_range_spec(spec.trim(), size) else {
+            return RangeRequest::Full;
+        };
+        if range.start < size && !range.is_empty() {
+            ranges.push(range);
+        }
+    }
+    ranges.sort_by_key(|range| range.start);
+    let mut coalesced: Vec<Range<u64>> = Vec::with_capacity(ranges.len());
+    for range in ranges {
+        match coalesced.last_mut() {
+            Some(last) if range.start <= last.end => last.end = last.end.max(range.end),
+            _ => coalesced.push(range),
+        }
+    }
+    let ranges = coalesced;
+    match ranges.len() {
+        0 => RangeRequest::Unsatisfiable,
+        n if n > MAX_RANGES => RangeRequest::Full,
+        _ => RangeRequest::Partial(ranges),
+    }
+}
+
+fn parse_range_spec(spec: &str, size: u64) -> Option<Range<u64>> {
+    let (start, end) = spec.split_once('-')?;
+    match (start.trim(), end.trim()) {
+        ("", "") => None,
+        ("", suffix) => Some(size.saturating_sub(suffix.parse().ok()?)..size),
+        (start, "") => Some(start.parse().ok()?..size),
+        (start, end) => {
+            let (start, end): (u64, u64) = (start.parse().ok()?, end.parse().ok()?);
+            (start <= end).then(|| start..end.saturating_add(1).min(size))
+        }
+    }
+}
+
+/// Objects never change once stored, so their id is a strong validator.
+pub(super) fn etag(obj: &Object) -> String {
+    format!("\"{}\"", obj.id)
+}
+
+pub(super) fn http_date(time: DateTime<Utc>) -> String {
+    time.format("%a, %d %b %Y %H:%M:%S GMT").to_string()
+}
+
+/// Whether a request with the given conditional headers can be answered with 304.
+pub(super) fn is_not_modified(headers: &HeaderMap, etag: &str, modified: DateTime<Utc>) -> bool {
+    if let Some(value) = header_str(headers, header::IF_NONE_MATCH) {
+        let weak = |tag: &str| tag.trim().trim_start_matches("W/").to_owned();
+        return value.trim() == "*" || value.split(',').any(|tag| weak(tag) == weak(etag));
+    }
+    match header_str(headers, header::IF_MODIFIED_SINCE).and_then(parse_http_date) {
+        Some(since) => modified.timestamp() <= since.timestamp(),
+        None => false,
+    }
+}
+
+/// Whether the `If-Range` precondition, if any, still allows a partial response.
+pub(super) fn if_range_matches(headers: &HeaderMap, etag: &str, modified: DateTime<Utc>) -> bool {
+    match header_str(headers, header::IF_RANGE).map(str::trim) {
+        None => true,
+        Some(value) if value.starts_with('"') => value == etag,
+        Some(value) if value.starts_with("W/") => false,
+        Some(value) => {
+            parse_http_date(value).is_some_and(|date| date.timestamp() == modified.timestamp())
+        }
+    }
+}
+
+/// Builds a `multipart/byteranges` body out of one reader per range, returning the body
+/// along with its content type and length.
+pub(super) fn multipart_body<R>(
+    readers: Vec<R>,
+    ranges: &[Range<u64>],
+    size: u64,
+    mime: &str,
+) -> (Body, String, u64)
+where
+    R: AsyncRead + Send + 'static,
+{
+    let boundary = format!("{:016x}", SmallRng::from_os_rng().next_u64());
+    let mut len = 0;
+    let mut parts: Vec<ByteStream> = Vec::new();
+    for (reader, range) in readers.into_iter().zip(ranges) {
+        let head = format!(
+            "\r\n--{boundary}\r\nContent-Type: {mime}\r\nContent-Range: bytes {}-{}/{size}\r\n\r\n",
+            range.start,
+            range.end - 1
+        );
+        len += head.len() as u64 + (range.end - range.start);
+        parts.push(Box::pin(bytes_once(head)));
+        parts.push(Box::pin(ReaderStream::new(reader)));
+    }
+    let tail = format!("\r\n--{boundary}--\r\n");
+    len += tail.len() as u64;
+    parts.push(Box::pin(bytes_once(tail)));
+
+    let body = Body::from_stream(stream::iter(parts).flatten());
+    let content_type = format!("multipart/byteranges; boundary={boundary}");
+    (body, content_type, len)
+}
+
+fn bytes_once(s: String) -> impl Stream<Item = Result<Bytes, IoError>> {
+    stream::once(async move { Ok(Bytes::from(s)) })
+}
+
+fn header_str(headers: &HeaderMap, name: header::HeaderName) -> Option<&str> {
+    headers.get(name).and_then(|v| v.to_str().ok())
+}
+
+fn parse_http_date(value: &str) -> Option<DateTime<Utc>> {
+    let date = DateTime::parse_from_rfc2822(value).ok()?;
+    Some(date.with_timezone(&Utc))
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::HeaderValue;
+
+    use crate::models::object::Upload;
+
+    use super::*;
+
+    fn single(range: Range<u64>) -> RangeRequest {
+        RangeRequest::Partial(vec![range])
+    }
+
+    #[test]
+    fn parse_range_header() {
+        use RangeRequest::*;
+
+        assert_eq!(parse_range("bytes=0-99", 1000), single(0..100));
+        assert_eq!(parse_range("bytes=900-", 1000), single(900..1000));
+        assert_eq!(parse_range("bytes=-100", 1000), single(900..1000));
+        assert_eq!(parse_range("bytes=990-2000", 1000), single(990..1000));
+        assert_eq!(
+            parse_range("bytes=0-0, -1", 1000),
+            Partial(vec![0..1, 999..1000])
+        );
+        assert_eq!(
+            parse_range("bytes=500-599, 0-9", 1000),
+            Partial(vec![0..10, 500..600])
+        );
+        assert_eq!(parse_range("bytes=0-9, 5-19, 20-29", 1000), single(0..30));
+        let repeated = vec!["0-"; MAX_RANGES + 1].join(",");
+        assert_eq!(
+            parse_range(&format!("bytes={repeated}"), 1000),
+            single(0..1000)
+        );
+        assert_eq!(parse_range("bytes=1000-", 1000), Unsatisfiable);
+        assert_eq!(parse_range("bytes=-0", 1000), Unsatisfiable);
+        assert_eq!(parse_range("bytes=5-1", 1000), Full);
+        assert_eq!(parse_range("items=0-1", 1000), Full);
+        assert_eq!(parse_range("bytes=abc", 1000), Full);
+    }
+
+    #[test]
+    fn conditional_headers() {
+        let obj: Object = Upload::default().into();
+        let etag = etag(&obj);
+        let date = HeaderValue::from_str(&http_date(obj.timestamp)).unwrap();
+
+        let mut headers = HeaderMap::new();
+        assert!(!is_not_modified(&headers, &etag, obj.timestamp));
+        assert!(if_range_matches(&headers, &etag, obj.timestamp));
+
+        headers.insert(header::IF_MODIFIED_SINCE, date.clone());
+        assert!(is_not_modified(&headers, &etag, obj.timestamp));
+        headers.insert(header::IF_NONE_MATCH, HeaderValue::from_static("\"other\""));
+        assert!(!is_not_modified(&headers, &etag, obj.timestamp));
+        let tags = format!("\"other\", W/{etag}");
+        headers.insert(header::IF_NONE_MATCH, tags.parse().unwrap());
+        assert!(is_not_modified(&headers, &etag, obj.timestamp));
+
+        headers.insert(header::IF_RANGE, date);
+        assert!(if_range_matches(&headers, &etag, obj.timestamp));
+        headers.insert(header::IF_RANGE, format!("W/{etag}").parse().unwrap());
+        assert!(!if_range_matches(&headers, &etag, obj.timestamp));
+        headers.insert(header::IF_RANGE, etag.parse().unwrap());
+        assert!(if_range_matches(&headers, &etag, obj.timestamp));
+    }
+}
@@ -0,0 +1,329 @@
+use std::{collections::HashMap, io::Error as IoError, sync::Arc};
+
+use axum::{
+    body::Body,
+    extract::{Path, Request, State},
+    http::{header, HeaderMap, HeaderValue, Method, Response, StatusCode},
+    middleware::{self, Next},
+    response::IntoResponse,
+    routing::options,
+    Router,
+};
+use base64::{prelude::BASE64_STANDARD, Engine};
+use futures::TryStreamExt;
+use serde_json::json;
+use tokio_util::io::StreamReader;
+use tracing::{event, Level};
+
+use crate::{
+    controllers::{
+        object::{check_session_auth_key, guess_mime, is_encrypted},
+        range::http_date,
+        StatusCodeError,
+    },
+    models::{
+        object::{FileInfo, Upload},
+        session::SessionId,
+        upload::{PendingUpload, UploadId},
+    },
+    services::upload::UploadError,
+    ConcreteSessionService, ConcreteUploadService, ObjectServiceFactory,
+};
+
+const TUS_VERSION: &str = "1.0.0";
+const TUS_EXTENSIONS: &str = "creation,termination,expiration";
+const OFFSET_CONTENT_TYPE: &str = "application/offset+octet-stream";
+
+const TUS_RESUMABLE: &str = "tus-resumable";
+const TUS_MAX_SIZE: &str = "tus-max-size";
+const UPLOAD_LENGTH: &str = "upload-length";
+const UPLOAD_OFFSET: &str = "upload-offset";
+const UPLOAD_METADATA: &str = "upload-metadata";
+const UPLOAD_EXPIRES: &str = "upload-expires";
+
+/// Resumable uploads following the tus 1.0 protocol, see https://tus.io/protocols/resumable-upload.
+pub struct UploadController {
+    factory: ObjectServiceFactory,
+    session: Arc<ConcreteSessionService>,
+    upload: Arc<ConcreteUploadService>,
+    enabled: bool,
+}
+
+impl UploadController {
+    pub fn new(
+        factory: ObjectServiceFactory,
+        session: Arc<ConcreteSessionService>,
+        upload: Arc<ConcreteUploadService>,
+    ) -> Self {
+        Self {
+            factory,
+            session,
+            upload,
+            enabled: true,
+        }
+    }
+
+    /// Whether uploads are accepted, which are refused with an explanation otherwise.
+    pub fn with_enabled(mut self, enabled: bool) -> Self {
+        self.enabled = enabled;
+        self
+    }
+
+    pub fn into_router(self) -> Router {
+        let enabled = self.enabled;
+        let state = Arc::new(self);
+        let router = Router::new()
+            .route(
+                "/{sid}/uploads",
+                options(options_handler).post(create_handler),
+            )
+            .route(
+                "/{sid}/uploads/{uid}",
+                options(options_handler)
+                    .head(head_handler)
+                    .patch(patch_handler)
+                    .delete(delete_handler),
+            );
+        let router = match enabled {
+            true => router,
+            false => router.route_layer(middleware::from_fn(refuse_uploads)),
+        };
+        router
+            .route_layer(middleware::from_fn(check_tus_version))
+            .with_state(state)
+    }
+}
+
+/// Answers in place of every endpoint while uploads are disabled.
+async fn refuse_uploads(_: Request, _: Next) -> Response<Body> {
+    let msg = "Resumable uploads are disabled while encryption at rest is enabled";
+    (StatusCode::FORBIDDEN, msg).into_response()
+}
+
+/// Rejects requests made for another protocol version and marks every response with the
+/// version spoken here.
+async fn check_tus_version(request: Request, next: Next) -> Response<Body> {
+    let version = request.headers().get(TUS_RESUMABLE);
+    let mut response = if request.method() != Method::OPTIONS
+        && version.is_none_or(|v| v.as_bytes() != TUS_VERSION.as_bytes())
+    {
+        let mut response = StatusCode::PRECONDITION_FAILED.into_response();
+        let headers = response.headers_mut();
+        headers.insert("tus-version", HeaderValue::from_static(TUS_VERSION));
+        response
+    } else {
+        next.run(request).await
+    };
+    let headers = response.headers_mut();
+    headers.insert(TUS_RESUMABLE, HeaderValue::from_static(TUS_VERSION));
+    response
+}
+
+async fn options_handler(State(controller): State<Arc<UploadController>>) -> Response<Body> {
+    let mut response = StatusCode::NO_CONTENT.into_response();
+    let headers = response.headers_mut();
+    headers.insert("tus-version", HeaderValue::from_static(TUS_VERSION));
+    headers.insert("tus-extension", HeaderValue::from_static(TUS_EXTENSIONS));
+    if let Some(max) = controller.upload.max_size() {
+        headers.insert(TUS_MAX_SIZE, max.into());
+    }
+    response
+}
+
+async fn create_handler(
+    State(controller): State<Arc<UploadController>>,
+    Path(sid): Path<SessionId>,
+    headers: HeaderMap,
+) -> Result<Response<Body>, StatusCode> {
+    check_session_auth_key(&controller.session, &sid, &headers).await?;
+
+    let length = header_u64(&headers, UPLOAD_LENGTH)?;
+    let encrypted = is_encrypted(&controller.session, &sid).await?;
+    let (upload, file) = parse_metadata(headers.get(UPLOAD_METADATA), encrypted)?;
+    let objects = (controller.factory)(&sid);
+    let (pending, _) = controller
+        .upload
+        .create(&objects, sid, length, upload, file)
+        .await
+        .map_err(upload_error)?;
+    let location = format!("/objects/{sid}/uploads/{}", pending.id);
+    let response = Response::builder()
+        .status(StatusCode::CREATED)
+        .header(header::LOCATION, location)
+        .header(UPLOAD_OFFSET, pending.offset)
+        .header(UPLOAD_EXPIRES, http_date(pending.expires_at));
+    response
+        .body(Body::empty())
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
+}
+
+async fn head_handler(
+    State(controller): State<Arc<UploadController>>,
+    Path((sid, uid)): Path<(SessionId, UploadId)>,
+    headers: HeaderMap,
+) -> Result<Response<Body>, StatusCode> {
+    check_session_auth_key(&controller.session, &sid, &headers).await?;
+
+    let pending = controller
+        .upload
+        .get(&sid, &uid)
+        .await
+        .map_err(upload_error)?;
+    let response = offset_response(StatusCode::OK, &pending)
+        .header(UPLOAD_LENGTH, pending.length)
+        .header(header::CACHE_CONTROL, "no-store");
+    response
+        .body(Body::empty())
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
+}
+
+async fn patch_handler(
+    State(controller): State<Arc<UploadController>>,
+    Path((sid, uid)): Path<(SessionId, UploadId)>,
+    headers: HeaderMap,
+    body: Body,
+) -> Result<Response<Body>, StatusCode> {
+    check_session_auth_key(&controller.session, &sid, &headers).await?;
+
+    let content_type = headers.get(header::CONTENT_TYPE).map(HeaderValue::as_bytes);
+    if content_type != Some(OFFSET_CONTENT_TYPE.as_bytes()) {
+        return Err(StatusCode::UNSUPPORTED_MEDIA_TYPE);
+    }
+    let offset = header_u64(&headers, UPLOAD_OFFSET)?;
+    let reader = StreamReader::new(body.into_data_stream().map_err(IoError::other));
+    let objects = (controller.factory)(&sid);
+    let (pending, obj) = controller
+        .upload
+        .append(&objects, &sid, &uid, offset, reader)
+        .await
+        .map_err(upload_error)?;
+    let response = match obj {
+        // Completed uploads are gone, so there is nothing left to expire.
+        Some(_) => Response::builder()
+            .status(StatusCode::NO_CONTENT)
+            .header(UPLOAD_OFFSET, pending.offset),
+        None => offset_response(StatusCode::NO_CONTENT, &pending),
+    };
+    response
+        .body(Body::empty())
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
+}
+
+async fn delete_handler(
+    State(controller): State<Arc<UploadController>>,
+    Path((sid, uid)): Path<(SessionId, UploadId)>,
+    headers: HeaderMap,
+) -> Result<StatusCode, StatusCode> {
+    check_session_auth_key(&controller.session, &sid, &headers).await?;
+
+    controller
+        .upload
+        .delete(&sid, &uid)
+        .await
+        .map_err(upload_error)?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+fn offset_response(status: StatusCode, pending: &PendingUpload) -> axum::http::response::Builder {
+    Response::builder()
+        .status(status)
+        .header(UPLOAD_OFFSET, pending.offset)
+        .header(UPLOAD_EXPIRES, http_date(pending.expires_at))
+}
+
+fn header_u64(headers: &HeaderMap, name: &str) -> Result<u64, StatusCode> {
+    headers
+        .get(name)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse().ok())
+        .ok_or(StatusCode::BAD_REQUEST)
+}
+
+/// Reads the `Upload-Metadata` header, a comma-separated list of keys each followed by
+/// an optional base64 encoded value. The `meta` key carries the same JSON as the `meta`
+/// field of multipart uploads, and a file content is made up from `filename` and
+/// `filetype` for clients that do not send it. Both are ignored for end-to-end encrypted
+/// sessions.
+fn parse_metadata(
+    value: Option<&HeaderValue>,
+    encrypted: bool,
+) -> Result<(Upload, FileInfo), StatusCode> {
+    let mut pairs = HashMap::new();
+    if let Some(value) = value {
+        let value = value.to_str().map_err(|_| StatusCode::BAD_REQUEST)?;
+        for pair in value.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+            let (key, encoded) = pair.split_once(' ').unwrap_or((pair, ""));
+            let decoded = BASE64_STANDARD
+                .decode(encoded.trim())
+                .ok()
+                .and_then(|buf| String::from_utf8(buf).ok())
+                .ok_or(StatusCode::BAD_REQUEST)?;
+            pairs.insert(key, decoded);
+        }
+    }
+
+    if encrypted {
+        pairs.remove("filename");
+        pairs.remove("filetype");
+    }
+    let filename = pairs.remove("filename");
+    let mime = pairs
+        .remove("filetype")
+        .or_else(|| guess_mime(filename.as_deref()?));
+    let upload = match pairs.remove("meta") {
+        Some(meta) => serde_json::from_str(&meta).map_err(|_| StatusCode::BAD_REQUEST)?,
+        None => {
+            let name = filename.as_deref().unwrap_or("file");
+            let content = json!({ "kind": "file", "name": name, "mime": mime });
+            Upload::new(content, false)
+        }
+    };
+    Ok((upload, FileInfo { filename, mime }))
+}
+
+fn upload_error(err: UploadError) -> StatusCode {
+    match err {
+        UploadError::Other(_) | UploadError::Object(_) => {
+            event!(Level::ERROR, "Upload error: {err}")
+        }
+        _ => (),
+    }
+    err.into_status_code()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_upload_metadata() {
+        let (upload, file) = parse_metadata(None, false).unwrap();
+        assert_eq!(upload.content["name"], "file");
+        assert!(file.filename.is_none());
+
+        let header = HeaderValue::from_static("filename cmVwb3J0LnBkZg==,is_confidential");
+        let (upload, file) = parse_metadata(Some(&header), false).unwrap();
+        assert_eq!(file.filename.as_deref(), Some("report.pdf"));
+        assert_eq!(file.mime.as_deref(), Some("application/pdf"));
+        assert_eq!(upload.content["kind"], "file");
+        assert_eq!(upload.content["mime"], "application/pdf");
+
+        let meta = BASE64_STANDARD.encode(r#"{"content":{"kind":"file","name":"a.txt"}}"#);
+        let header =
+            HeaderValue::from_str(&format!("meta {meta}, filetype dGV4dC9wbGFpbg==")).unwrap();
+        let (upload, file) = parse_metadata(Some(&header), false).unwrap();
+        assert_eq!(upload.content["name"], "a.txt");
+        assert_eq!(file.mime.as_deref(), Some("text/plain"));
+
+        let header = HeaderValue::from_str(&format!("meta {meta},filename cmVwb3J0LnBkZg=="));
+        let (upload, file) = parse_metadata(Some(&header.unwrap()), true).unwrap();
+        assert_eq!(upload.content["name"], "a.txt");
+        assert!(file.filename.is_none() && file.mime.is_none());
+
+        let header = HeaderValue::from_static("filename !!!");
+        assert!(matches!(
+            parse_metadata(Some(&header), false),
+            Err(StatusCode::BAD_REQUEST)
+        ));
+    }
+}
@@ -1,6 +1,7 @@
-use std::{error::Error, sync::Arc};
+use std::{error::Error, sync::Arc, time::Duration};
 
 use axum::{
+    body::Bytes,
     extract::{
         ws::{Message, WebSocket},
         Path, Query, State, WebSocketUpgrade,
@@ -10,28 +11,65 @@ use axum::{
     routing::any,
     Router,
 };
-use futures::SinkExt;
+use futures::{SinkExt, StreamExt};
+use tokio::time::{self, Instant, MissedTickBehavior};
 use tracing::{event, Level};
 
 use crate::{
-    controllers::{AuthKeyExtractor, AuthParams},
+    config::{Config, DEFAULT_WEBSOCKET_PING_INTERVAL, DEFAULT_WEBSOCKET_TIMEOUT},
+    controllers::{AuthKeyExtractor, AuthParams, ReplayParams},
     models::{
-        event::{Event, EventName},
+        event::{Event, EventId, EventKind},
         session::SessionId,
     },
     repositories::session::SessionRepository,
     services::websocket::{WebSocketError, WebSocketService},
-    utils::sync::Subscriber,
-    WebSocketServiceFactory,
+    utils::sync::{Lagged, Subscriber},
+    ConcreteWebSocketService, WebSocketServiceFactory,
 };
 
+/// How often clients are pinged, and how long they may stay silent before they are
+/// considered gone.
+#[derive(Clone, Copy)]
+pub struct Heartbeat {
+    pub interval: Duration,
+    pub timeout: Duration,
+}
+
+impl Default for Heartbeat {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(DEFAULT_WEBSOCKET_PING_INTERVAL),
+            timeout: Duration::from_secs(DEFAULT_WEBSOCKET_TIMEOUT),
+        }
+    }
+}
+
+impl From<&Config> for Heartbeat {
+    fn from(config: &Config) -> Self {
+        Self {
+            interval: Duration::from_secs(config.websocket_ping_interval.max(1)),
+            timeout: Duration::from_secs(config.websocket_timeout.max(1)),
+        }
+    }
+}
+
 pub struct WebSocketController {
     factory: WebSocketServiceFactory,
+    heartbeat: Heartbeat,
 }
 
 impl WebSocketController {
     pub fn new(factory: WebSocketServiceFactory) -> Self {
-        Self { factory }
+        Self {
+            factory,
+            heartbeat: Heartbeat::default(),
+        }
+    }
+
+    pub fn with_heartbeat(mut self, heartbeat: Heartbeat) -> Self {
+        self.heartbeat = heartbeat;
+        self
     }
 
     pub fn into_router(self) -> Router {
@@ -46,42 +84,116 @@ async fn websocket_handler(
     State(controller): State<Arc<WebSocketController>>,
     Path(sid): Path<SessionId>,
     Query(params): Query<AuthParams>,
+    Query(replay): Query<ReplayParams>,
     ws: WebSocketUpgrade,
 ) -> Result<impl IntoResponse, StatusCode> {
     let service = (controller.factory)(&sid);
     check_auth_key(&service, &sid, &params).await?;
-    let subscriber = service.subscribe();
-    let res = ws.on_upgrade(move |socket| async {
-        if let Err(e) = handle_socket(socket, subscriber).await {
+    let (missed, subscriber) = service.subscribe(replay.since);
+    let heartbeat = controller.heartbeat;
+    let res = ws.on_upgrade(move |socket| async move {
+        if let Err(e) = handle_socket(socket, heartbeat, &service, missed, subscriber).await {
             event!(Level::ERROR, "WebSocket error: {e}")
         }
     });
     Ok(res)
 }
 
+/// Delivers events while reading from the client, which keeps pongs and close frames
+/// flowing. Clients that lag behind are told to resync. The socket is closed once the
+/// session is deleted, the client closes it or the client has been silent for longer
+/// than the heartbeat timeout.
 async fn handle_socket(
-    mut socket: WebSocket,
+    socket: WebSocket,
+    heartbeat: Heartbeat,
+    service: &ConcreteWebSocketService,
+    missed: Vec<Event>,
     subscriber: Arc<Subscriber<Event>>,
-) -> Result<(), Box<dyn Error>> {
-    let mut closed = false;
-    while !closed {
-        let mut events: Vec<Event> = subscriber.pop().await.into_iter().collect();
-        events.sort_by_key(|e| e.timestamp);
-        for event in events {
-            let json = serde_json::to_string(&event)?;
-            let message = Message::Text(json.into());
-            socket.send(message).await?;
-            if let EventName::SessionDeleted = event.name {
-                closed = true;
-                break;
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let (mut sender, mut receiver) = socket.split();
+    let deliver = async {
+        let mut delivered = Delivered::default();
+        let mut events = missed;
+        let start = Instant::now() + heartbeat.interval;
+        let mut ping = time::interval_at(start, heartbeat.interval);
+        ping.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        loop {
+            for event in events {
+                let json = serde_json::to_string(&event)?;
+                sender.send(Message::Text(json.into())).await?;
+                if let EventKind::SessionDeleted = event.kind {
+                    return Ok(());
+                }
             }
+            events = tokio::select! {
+                popped = subscriber.pop() => match popped {
+                    Ok(popped) => popped.into_iter().collect(),
+                    Err(Lagged(dropped)) => {
+                        event!(Level::WARN, "WebSocket client lagged behind by {dropped} events");
+                        vec![service.resync()]
+                    }
+                },
+                _ = ping.tick() => {
+                    sender.send(Message::Ping(Bytes::new())).await?;
+                    Vec::new()
+                }
+            };
+            events.sort_by_key(|e| e.id);
+            events.retain(|e| delivered.admit(e));
         }
-    }
-    socket.close().await?;
+    };
+    let listen = async {
+        loop {
+            match time::timeout(heartbeat.timeout, receiver.next()).await {
+                Err(_) => {
+                    event!(Level::DEBUG, "WebSocket client timed out");
+                    return Ok(());
+                }
+                Ok(None | Some(Ok(Message::Close(_)))) => return Ok(()),
+                Ok(Some(Err(e))) => {
+                    event!(Level::DEBUG, "WebSocket client disconnected: {e}");
+                    return Ok(());
+                }
+                // Pongs and anything else the client sends show that it is still there.
+                Ok(Some(Ok(_))) => (),
+            }
+        }
+    };
+    let res: Result<(), Box<dyn Error + Send + Sync>> = tokio::select! {
+        res = deliver => res,
+        res = listen => res,
+    };
+    // Unregisters right away rather than when the close handshake is done.
+    drop(subscriber);
+    res?;
+    // The client may already be gone, in which case there is nothing left to close.
+    let _ = sender.close().await;
     Ok(())
 }
 
-async fn check_auth_key<R: SessionRepository>(
+/// Latest event delivered to a client, so that its events only ever move forward. Events
+/// published around telling a lagging client to resync may still be buffered afterwards,
+/// although the resync covers them already.
+#[derive(Default)]
+pub(super) struct Delivered(Option<EventId>);
+
+impl Delivered {
+    /// Whether the event is to be delivered, recording it if so. Session deletions are
+    /// always delivered, since they end the delivery.
+    pub(super) fn admit(&mut self, event: &Event) -> bool {
+        let stale = self.0.is_some_and(|last| match event.kind {
+            EventKind::SessionDeleted => false,
+            EventKind::Resync => event.id < last,
+            _ => event.id <= last,
+        });
+        if !stale {
+            self.0 = Some(self.0.map_or(event.id, |last| last.max(event.id)));
+        }
+        !stale
+    }
+}
+
+pub(super) async fn check_auth_key<R: SessionRepository>(
     service: &Arc<WebSocketService<R>>,
     sid: &SessionId,
     params: &AuthParams,
@@ -102,3 +214,112 @@ async fn check_auth_key<R: SessionRepository>(
         Err(StatusCode::UNAUTHORIZED)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashMap,
+        sync::{LazyLock, Mutex},
+    };
+
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio_tungstenite::{
+        connect_async, tungstenite::Message as ClientMessage, MaybeTlsStream, WebSocketStream,
+    };
+
+    use crate::{
+        models::{object::ObjectId, session::Session},
+        repositories::{
+            memory::MemoryStore,
+            session::{AnySessionRepository, SessionMemoryRepository},
+        },
+    };
+
+    use super::*;
+
+    type Client = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+    static SESSIONS: LazyLock<Arc<AnySessionRepository>> = LazyLock::new(|| {
+        let repository = SessionMemoryRepository::new(MemoryStore::default());
+        Arc::new(AnySessionRepository::Memory(repository))
+    });
+    static SERVICES: LazyLock<Mutex<HashMap<SessionId, Arc<ConcreteWebSocketService>>>> =
+        LazyLock::new(Mutex::default);
+
+    fn websocket_factory(sid: &SessionId) -> Arc<ConcreteWebSocketService> {
+        let mut services = SERVICES.lock().unwrap();
+        let service = services
+            .entry(*sid)
+            .or_insert_with(|| Arc::new(WebSocketService::new(8, Arc::clone(&SESSIONS))));
+        Arc::clone(service)
+    }
+
+    /// Serves the WebSocket of a new session, returning its service and a connected client.
+    async fn connect(heartbeat: Heartbeat) -> (Arc<ConcreteWebSocketService>, Client) {
+        let sess = Session::default();
+        SESSIONS.create(&sess).await.unwrap();
+        let router = WebSocketController::new(websocket_factory)
+            .with_heartbeat(heartbeat)
+            .into_router();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, router).await.unwrap() });
+        let (client, _) = connect_async(format!("ws://{addr}/{}", sess.id))
+            .await
+            .unwrap();
+        (websocket_factory(&sess.id), client)
+    }
+
+    /// Reads until the server is done with the socket, failing if that takes a second.
+    async fn wait_closed(client: &mut Client) {
+        let drain = async { while let Some(Ok(_)) = client.next().await {} };
+        time::timeout(Duration::from_secs(1), drain).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn drop_silent_clients() {
+        let heartbeat = Heartbeat {
+            interval: Duration::from_millis(50),
+            timeout: Duration::from_millis(200),
+        };
+        let (_, mut client) = connect(heartbeat).await;
+        // Pings are only answered while the client reads.
+        time::sleep(Duration::from_millis(400)).await;
+        wait_closed(&mut client).await;
+    }
+
+    #[tokio::test]
+    async fn keep_clients_answering_pings() {
+        let heartbeat = Heartbeat {
+            interval: Duration::from_millis(50),
+            timeout: Duration::from_millis(200),
+        };
+        let (service, mut client) = connect(heartbeat).await;
+        let mut pings = 0;
+        let until = Instant::now() + Duration::from_millis(500);
+        while let Ok(Some(message)) = time::timeout_at(until, client.next()).await {
+            pings += usize::from(matches!(message.unwrap(), ClientMessage::Ping(_)));
+        }
+        assert!(pings >= 3);
+
+        service.publish(Event::new(EventKind::ObjectDeleted(ObjectId::generate())));
+        loop {
+            match time::timeout(Duration::from_secs(1), client.next()).await {
+                Ok(Some(Ok(ClientMessage::Text(text)))) => {
+                    assert!(text.contains("object.deleted"));
+                    break;
+                }
+                Ok(Some(Ok(ClientMessage::Ping(_)))) => (),
+                other => panic!("unexpected {other:?}"),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn close_when_the_client_closes() {
+        let (_, mut client) = connect(Heartbeat::default()).await;
+        client.close(None).await.unwrap();
+        // Answered right away, long before the heartbeat would time out.
+        wait_closed(&mut client).await;
+    }
+}
@@ -1,9 +1,15 @@
 use std::sync::Arc;
 
 use models::session::SessionId;
-use repositories::{object::ObjectFsRepository, session::SessionFsRepository};
-use services::{object::ObjectService, session::SessionService, websocket::WebSocketService};
+use repositories::{
+    object::AnyObjectRepository, session::AnySessionRepository, upload::AnyUploadRepository,
+};
+use services::{
+    object::ObjectService, session::SessionService, upload::UploadService,
+    websocket::WebSocketService,
+};
 
+pub mod config;
 pub mod controllers;
 pub mod models;
 pub mod registries;
@@ -11,12 +17,15 @@ pub mod repositories;
 pub mod services;
 pub mod utils;
 
-pub type ConcreteSessionRepository = SessionFsRepository;
+pub type ConcreteSessionRepository = AnySessionRepository;
 pub type ConcreteSessionService = SessionService<ConcreteSessionRepository>;
 
-pub type ConcreteObjectRepository = ObjectFsRepository;
+pub type ConcreteObjectRepository = AnyObjectRepository;
 pub type ConcreteObjectService = ObjectService<ConcreteObjectRepository, ConcreteSessionRepository>;
 
+pub type ConcreteUploadRepository = AnyUploadRepository;
+pub type ConcreteUploadService = UploadService<ConcreteUploadRepository>;
+
 pub type ConcreteWebSocketService = WebSocketService<ConcreteSessionRepository>;
 
 pub(crate) type WebSocketServiceFactory = fn(&SessionId) -> Arc<ConcreteWebSocketService>;
@@ -1,20 +1,36 @@
-use std::{env, io::ErrorKind, path::PathBuf, str::FromStr, sync::Arc};
+use std::{
+    env,
+    io::ErrorKind,
+    path::PathBuf,
+    process,
+    str::FromStr,
+    sync::{Arc, LazyLock},
+    time::Duration,
+};
 
-use tokio::net::TcpListener;
+use chrono::{TimeDelta, Utc};
+use tokio::{net::TcpListener, time};
 use tower_http::trace::{DefaultMakeSpan, TraceLayer};
 use tracing::{event, Level};
 use tracing_subscriber::{fmt, prelude::*, EnvFilter};
 use webdrop::{
-    controllers::MainController,
+    config::{StorageBackend, CONFIG},
+    controllers::{Heartbeat, MainController},
     models::session::SessionId,
-    registries::{OBJECT_REPOSITORIES, OBJECT_SERVICES, SESSION_REPOSITORY, WEBSOCKET_SERVICES},
-    services::{object::ObjectService, session::SessionService},
+    registries::{
+        OBJECT_REPOSITORIES, OBJECT_SERVICES, SESSION_REPOSITORY, UPLOAD_REPOSITORY,
+        WEBSOCKET_SERVICES,
+    },
+    repositories::{encryption::MasterKey, fs::sweep_staging, fsck::fsck},
+    services::{object::ObjectService, session::SessionService, upload::UploadService},
     ConcreteObjectRepository, ConcreteObjectService, ConcreteSessionRepository,
-    ConcreteWebSocketService, STORAGE_DIR,
+    ConcreteSessionService, ConcreteUploadService, ConcreteWebSocketService, STORAGE_DIR,
 };
 
 const LISTENER_ADDR: &str = "0.0.0.0:8000";
 const NOTIFICATION_BACKLOG_SIZE: usize = 256;
+/// Staging files untouched for this long belong to writes that will never finish.
+const STALE_STAGING_AGE: Duration = Duration::from_secs(60 * 60);
 
 #[tokio::main]
 async fn main() {
@@ -27,16 +43,72 @@ async fn main() {
         )
         .init();
 
-    match std::fs::metadata(STORAGE_DIR) {
-        Ok(meta) if !meta.is_dir() => panic!("Storage directory is not a directory"),
-        Ok(_) => (),
-        Err(e) if e.kind() == ErrorKind::NotFound => std::fs::create_dir(STORAGE_DIR).unwrap(),
-        Err(e) => panic!("Failed to check for storage directory: {e}"),
+    if env::args().nth(1).as_deref() == Some("fsck") {
+        let repair = env::args().skip(2).any(|arg| arg == "--repair");
+        process::exit(run_fsck(repair).await);
+    }
+
+    // Memory mode must never touch the disk, not even to create the storage directory.
+    if CONFIG.storage_backend != StorageBackend::Memory {
+        match std::fs::metadata(STORAGE_DIR) {
+            Ok(meta) if !meta.is_dir() => panic!("Storage directory is not a directory"),
+            Ok(_) => (),
+            Err(e) if e.kind() == ErrorKind::NotFound => std::fs::create_dir(STORAGE_DIR).unwrap(),
+            Err(e) => panic!("Failed to check for storage directory: {e}"),
+        }
+        match sweep_staging(STORAGE_DIR, STALE_STAGING_AGE) {
+            Ok(0) => (),
+            Ok(removed) => event!(Level::INFO, "Removed {removed} stale staging files"),
+            Err(e) => event!(Level::ERROR, "Failed to sweep staging files: {e}"),
+        }
     }
 
-    let service = SessionService::new(session_repository_factory(), websocket_service_factory);
-    let controller =
-        MainController::new(service, websocket_service_factory, object_service_factory);
+    event!(
+        Level::INFO,
+        "Using {:?} storage backend",
+        CONFIG.storage_backend
+    );
+    LazyLock::force(&SESSION_REPOSITORY);
+
+    let session_ttl = CONFIG.session_ttl.map(|secs| {
+        i64::try_from(secs)
+            .ok()
+            .and_then(TimeDelta::try_seconds)
+            .expect("Invalid value for SESSION_TTL")
+    });
+    let service = SessionService::new(session_repository_factory(), websocket_service_factory)
+        .with_default_ttl(session_ttl);
+    tokio::spawn(reap_sessions(SessionService::new(
+        session_repository_factory(),
+        websocket_service_factory,
+    )));
+
+    let upload_expiry = i64::try_from(CONFIG.upload_expiry)
+        .ok()
+        .and_then(TimeDelta::try_seconds)
+        .expect("Invalid value for UPLOAD_EXPIRY");
+    // Shared with the reaper so that uploads being written to are never reaped.
+    let upload = Arc::new(
+        UploadService::new(Arc::clone(&UPLOAD_REPOSITORY), upload_expiry)
+            .with_max_size(CONFIG.upload_max_bytes),
+    );
+    tokio::spawn(reap_uploads(Arc::clone(&upload)));
+
+    let controller = MainController::new(
+        service,
+        upload,
+        websocket_service_factory,
+        object_service_factory,
+    )
+    .with_heartbeat(Heartbeat::from(&*CONFIG))
+    // Partial blobs of resumable uploads would sit on the disk unsealed.
+    .with_resumable_uploads(CONFIG.master_key_file.is_none());
+    if CONFIG.master_key_file.is_some() {
+        event!(
+            Level::INFO,
+            "Resumable uploads are disabled while encryption at rest is enabled"
+        );
+    }
     let router = controller.into_router().layer(
         TraceLayer::new_for_http().make_span_with(DefaultMakeSpan::default().include_headers(true)),
     );
@@ -57,6 +129,89 @@ async fn main() {
     axum::serve(listener, router).await.unwrap();
 }
 
+/// Checks the storage directory for inconsistencies, returning the exit code: 0 when none
+/// remain, 1 when some are left unrepaired and 2 when the check could not be run.
+async fn run_fsck(repair: bool) -> i32 {
+    if CONFIG.storage_backend != StorageBackend::Fs || CONFIG.s3.is_some() {
+        eprintln!("fsck only supports the fs storage backend without S3");
+        return 2;
+    }
+    let master_key = match CONFIG.master_key_file.as_ref().map(MasterKey::load) {
+        Some(Ok(key)) => Some(key),
+        Some(Err(e)) => {
+            eprintln!("Failed to load the master key: {e}");
+            return 2;
+        }
+        None => None,
+    };
+    let issues = match fsck(STORAGE_DIR, master_key, repair).await {
+        Ok(issues) => issues,
+        Err(e) => {
+            eprintln!("Failed to check {STORAGE_DIR}: {e}");
+            return 2;
+        }
+    };
+    for issue in &issues {
+        println!("{issue}");
+    }
+    let remaining = issues
+        .iter()
+        .filter(|issue| !repair || !issue.is_repairable())
+        .count();
+    if repair {
+        println!(
+            "{} issues found, {} repaired",
+            issues.len(),
+            issues.len() - remaining
+        );
+    } else {
+        println!(
+            "{} issues found, run with --repair to repair them",
+            issues.len()
+        );
+    }
+    i32::from(remaining > 0)
+}
+
+async fn reap_sessions(service: ConcreteSessionService) {
+    let mut interval = time::interval(Duration::from_secs(CONFIG.reap_interval.max(1)));
+    loop {
+        interval.tick().await;
+        let reaped = match service.reap_expired(Utc::now()).await {
+            Ok(reaped) => reaped,
+            Err(e) => {
+                event!(Level::ERROR, "Failed to reap expired sessions: {e}");
+                continue;
+            }
+        };
+        for sess in &reaped {
+            event!(
+                Level::INFO,
+                "Reaped expired session {} with {} objects",
+                sess.id,
+                sess.objects.len()
+            );
+        }
+        if !reaped.is_empty() {
+            event!(Level::INFO, "Reaped {} expired sessions", reaped.len());
+        }
+    }
+}
+
+async fn reap_uploads(service: Arc<ConcreteUploadService>) {
+    let mut interval = time::interval(Duration::from_secs(CONFIG.reap_interval.max(1)));
+    loop {
+        interval.tick().await;
+        match service.reap_expired(Utc::now()).await {
+            Ok(reaped) if !reaped.is_empty() => {
+                event!(Level::INFO, "Reaped {} abandoned uploads", reaped.len());
+            }
+            Ok(_) => (),
+            Err(e) => event!(Level::ERROR, "Failed to reap abandoned uploads: {e}"),
+        }
+    }
+}
+
 fn websocket_service_factory(sid: &SessionId) -> Arc<ConcreteWebSocketService> {
     let services = WEBSOCKET_SERVICES.read().unwrap();
     if let Some(service) = services.get(sid) {
@@ -105,7 +260,11 @@ fn object_repository_factory(sid: &SessionId) -> Arc<ConcreteObjectRepository> {
         let dir = PathBuf::from_str(STORAGE_DIR)
             .unwrap()
             .join(sid.to_string());
-        let repository = Arc::new(ConcreteObjectRepository::new(dir));
+        let repository = Arc::new(ConcreteObjectRepository::new(
+            &SESSION_REPOSITORY,
+            *sid,
+            dir,
+        ));
         OBJECT_REPOSITORIES
             .write()
             .unwrap()
@@ -0,0 +1,35 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use super::{object::ObjectDto, session::CreateSessionCrypto};
+
+/// Path of the manifest within a session archive, which comes first so that archives can
+/// be imported while they are streamed.
+pub const MANIFEST_PATH: &str = "manifest.json";
+
+pub const MANIFEST_VERSION: u32 = 1;
+
+/// Describes an exported session and the objects in its archive.
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Manifest {
+    pub version: u32,
+    pub creation_time: DateTime<Utc>,
+    pub expires_at: Option<DateTime<Utc>>,
+    /// Key derivation parameters and auth key of an end-to-end encrypted session.
+    pub crypto: Option<CreateSessionCrypto>,
+    pub objects: Vec<ManifestObject>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ManifestObject {
+    #[serde(flatten)]
+    pub object: ObjectDto,
+    /// Path of the archive entry holding the blob, or the text or link of the object.
+    pub path: Option<String>,
+    /// Set for objects with a download limit, which are listed with only the kind of their
+    /// content and without a blob, and are not restored on import.
+    #[serde(default)]
+    pub omitted: bool,
+}
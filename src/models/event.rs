@@ -1,54 +1,58 @@
-use std::fmt::Display;
-
 use chrono::{DateTime, Utc};
-use serde::Serialize;
-use serde_json::Value;
+use serde::{Deserialize, Serialize};
 
-#[derive(Clone)]
-pub enum EventName {
-    ObjectCreated,
-    ObjectDeleted,
-    SessionDeleted,
-}
+use super::{
+    object::{ObjectDto, ObjectId},
+    session::SessionDto,
+};
 
-impl EventName {
-    pub fn into_event(self) -> Event {
-        Event::new(self, Value::Null)
-    }
-}
+/// Version of the event format, bumped whenever the payload of an event changes
+/// incompatibly.
+pub const EVENT_VERSION: u32 = 1;
 
-impl Display for EventName {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        let s = match self {
-            Self::ObjectCreated => "object.created",
-            Self::ObjectDeleted => "object.deleted",
-            Self::SessionDeleted => "session.deleted",
-        };
-        f.write_str(s)
-    }
-}
+/// Position of an event in the event log of its session, assigned when published.
+pub type EventId = u64;
 
-impl Serialize for EventName {
-    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
-    where
-        S: serde::Serializer,
-    {
-        self.to_string().serialize(serializer)
-    }
+/// What happened, serialized as the `name` of the event along with its `data`.
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(tag = "name", content = "data")]
+pub enum EventKind {
+    #[serde(rename = "object.created")]
+    ObjectCreated(ObjectDto),
+    /// The metadata of the object changed, such as the downloads counted against its limit.
+    #[serde(rename = "object.updated")]
+    ObjectUpdated(ObjectDto),
+    /// The object was downloaded, which counts against its download limit if it has one.
+    #[serde(rename = "object.downloaded")]
+    ObjectDownloaded(ObjectDto),
+    #[serde(rename = "object.deleted")]
+    ObjectDeleted(ObjectId),
+    /// The metadata of the session changed. Sessions are not changed in place so far, so
+    /// this is not published yet, but clients already apply it.
+    #[serde(rename = "session.updated")]
+    SessionUpdated(SessionDto),
+    #[serde(rename = "session.deleted")]
+    SessionDeleted,
+    /// Missed events could not be replayed, so the client has to reload the session.
+    #[serde(rename = "resync")]
+    Resync,
 }
 
-#[derive(Serialize, Clone)]
+#[derive(Serialize, Deserialize, Clone)]
 pub struct Event {
-    pub name: EventName,
-    pub data: Value,
+    pub id: EventId,
+    pub version: u32,
+    #[serde(flatten)]
+    pub kind: EventKind,
     pub timestamp: DateTime<Utc>,
 }
 
 impl Event {
-    pub fn new<T: Serialize>(name: EventName, data: T) -> Self {
+    pub fn new(kind: EventKind) -> Self {
         Self {
-            name,
-            data: serde_json::to_value(data).unwrap(),
+            id: 0,
+            version: EVENT_VERSION,
+            kind,
             timestamp: Utc::now(),
         }
     }
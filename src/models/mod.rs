@@ -1,5 +1,7 @@
+pub mod archive;
 pub mod crypto;
 pub mod event;
 pub mod object;
 pub mod session;
 pub mod snowflake;
+pub mod upload;
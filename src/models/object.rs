@@ -2,7 +2,7 @@ use base64::{prelude::BASE64_STANDARD_NO_PAD, Engine};
 use chrono::{DateTime, Utc};
 use rand::{rngs::StdRng, RngCore, SeedableRng};
 use serde::{Deserialize, Serialize};
-use serde_json::Value;
+use serde_json::{json, Value};
 
 use super::snowflake::SnowflakeId;
 
@@ -16,8 +16,14 @@ pub struct ObjectDto {
     pub id: ObjectId,
     pub timestamp: DateTime<Utc>,
     pub content: Value,
+    pub size: Option<u64>,
+    pub filename: Option<String>,
     pub mime: Option<String>,
     pub auth_key: Option<String>,
+    pub expires_at: Option<DateTime<Utc>>,
+    pub max_downloads: Option<u32>,
+    pub downloads: u32,
+    pub sha256: Option<String>,
 }
 
 #[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
@@ -25,27 +31,93 @@ pub struct Object {
     pub id: ObjectId,
     pub timestamp: DateTime<Utc>,
     pub content: Value,
+    /// Byte length of the blob, recorded when it is uploaded.
+    #[serde(default)]
+    pub size: Option<u64>,
+    /// Name of the uploaded file as reported by the client.
+    #[serde(default)]
+    pub filename: Option<String>,
     pub mime: Option<String>,
     pub auth_key: Option<String>,
+    #[serde(default)]
+    pub expires_at: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub max_downloads: Option<u32>,
+    #[serde(default)]
+    pub downloads: u32,
+    /// Hex SHA-256 digest of the blob, computed while it is uploaded.
+    #[serde(default)]
+    pub sha256: Option<String>,
+    /// Whether the blob is stored zstd compressed, which `size` and `sha256` disregard.
+    #[serde(default)]
+    pub compressed: bool,
+    /// Whether the blob is sealed with the master key of the server.
+    #[serde(default)]
+    pub encrypted: bool,
 }
 
-impl Into<ObjectDto> for Object {
-    fn into(self) -> ObjectDto {
-        ObjectDto {
-            id: self.id,
-            timestamp: self.timestamp,
-            content: self.content,
-            mime: self.mime,
-            auth_key: self.auth_key,
+impl Object {
+    pub fn is_expired(&self, now: DateTime<Utc>) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= now)
+    }
+
+    /// Whether the object has been downloaded as many times as it allows.
+    pub fn is_exhausted(&self) -> bool {
+        self.max_downloads.is_some_and(|max| self.downloads >= max)
+    }
+
+    /// Only uploaded objects have a blob, which have their size recorded or, for objects
+    /// uploaded before that, file content.
+    pub fn has_blob(&self) -> bool {
+        self.size.is_some() || self.content["kind"] == "file"
+    }
+
+    /// Keeps only the kind of the content when the downloads of the object are limited and
+    /// its content is all there is to download, as reading it has to count as a download.
+    /// The content of uploaded objects only describes their blob and is kept.
+    pub fn without_limited_content(mut self) -> Self {
+        if self.max_downloads.is_some() && !self.has_blob() {
+            self.content = json!({ "kind": self.content["kind"] });
+        }
+        self
+    }
+
+    /// What every client of the session is told about the object in its events, which
+    /// leaves out its auth key and any content limited by its downloads.
+    pub fn to_event_dto(&self) -> ObjectDto {
+        let mut dto = ObjectDto::from(self.clone().without_limited_content());
+        dto.auth_key = None;
+        dto
+    }
+}
+
+impl From<Object> for ObjectDto {
+    fn from(obj: Object) -> Self {
+        Self {
+            id: obj.id,
+            timestamp: obj.timestamp,
+            content: obj.content,
+            size: obj.size,
+            filename: obj.filename,
+            mime: obj.mime,
+            auth_key: obj.auth_key,
+            expires_at: obj.expires_at,
+            max_downloads: obj.max_downloads,
+            downloads: obj.downloads,
+            sha256: obj.sha256,
         }
     }
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Clone)]
 #[serde(rename_all = "camelCase")]
 pub struct Upload {
     pub content: Value,
     pub generate_auth_key: Option<bool>,
+    pub expires_at: Option<DateTime<Utc>>,
+    pub max_downloads: Option<u32>,
+    /// Hex SHA-256 digest the uploaded blob must have.
+    pub sha256: Option<String>,
 }
 
 impl Upload {
@@ -53,6 +125,7 @@ impl Upload {
         Self {
             content,
             generate_auth_key: Some(generate_auth_key),
+            ..Default::default()
         }
     }
 }
@@ -62,13 +135,23 @@ impl Default for Upload {
         Self {
             content: Value::Null,
             generate_auth_key: None,
+            expires_at: None,
+            max_downloads: None,
+            sha256: None,
         }
     }
 }
 
-impl Into<Object> for Upload {
-    fn into(self) -> Object {
-        let auth_key = if self.generate_auth_key.unwrap_or_default() {
+/// Details of an uploaded file reported by the client alongside its content.
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct FileInfo {
+    pub filename: Option<String>,
+    pub mime: Option<String>,
+}
+
+impl From<Upload> for Object {
+    fn from(upload: Upload) -> Self {
+        let auth_key = if upload.generate_auth_key.unwrap_or_default() {
             let mut buf = [0u8; 48];
             StdRng::from_os_rng().fill_bytes(&mut buf);
             let encoded = BASE64_STANDARD_NO_PAD.encode(buf);
@@ -76,12 +159,20 @@ impl Into<Object> for Upload {
         } else {
             None
         };
-        Object {
+        Self {
             id: ObjectId::generate(),
             timestamp: Utc::now(),
-            content: self.content,
+            content: upload.content,
+            size: None,
+            filename: None,
             mime: None,
             auth_key,
+            expires_at: upload.expires_at,
+            max_downloads: upload.max_downloads,
+            downloads: 0,
+            sha256: None,
+            compressed: false,
+            encrypted: false,
         }
     }
 }
@@ -4,41 +4,64 @@ use crate::models::{crypto::KDFParams, object::ObjectId};
 
 use super::{object::Object, snowflake::SnowflakeId};
 
-use chrono::{DateTime, Utc};
+use chrono::{DateTime, TimeDelta, Utc};
 use serde::{Deserialize, Serialize};
 
 pub type SessionId = SnowflakeId;
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Clone)]
 #[serde(rename_all = "camelCase")]
 pub struct SessionDto {
     pub id: SessionId,
     pub creation_time: DateTime<Utc>,
+    pub expires_at: Option<DateTime<Utc>>,
     pub crypto: Option<SessionCryptoDto>,
 }
 
-#[derive(Serialize, Deserialize, PartialEq, Eq, Debug)]
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
 pub struct Session {
     pub id: SessionId,
     pub objects: VecDeque<Object>,
     pub creation_time: DateTime<Utc>,
+    #[serde(default)]
+    pub expires_at: Option<DateTime<Utc>>,
     pub crypto: Option<SessionCrypto>,
 }
 
 impl Session {
-    pub fn new(sid: SessionId, req: Option<CreateSession>) -> Self {
+    /// Creates a session that expires after the requested lifetime, or after `default_ttl`
+    /// when the request does not specify one. Without either it lives until deleted.
+    pub fn new(sid: SessionId, req: CreateSession, default_ttl: Option<TimeDelta>) -> Self {
+        let creation_time = Utc::now();
+        let ttl = req
+            .ttl
+            .and_then(|secs| i64::try_from(secs).ok())
+            .and_then(TimeDelta::try_seconds)
+            .or(default_ttl);
         Self {
             id: sid,
             objects: VecDeque::default(),
-            creation_time: Utc::now(),
-            crypto: req.map(Into::into),
+            creation_time,
+            expires_at: ttl.and_then(|ttl| creation_time.checked_add_signed(ttl)),
+            crypto: req.crypto.map(Into::into),
         }
     }
 
+    pub fn is_expired(&self, now: DateTime<Utc>) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= now)
+    }
+
     pub fn add_object(&mut self, obj: Object) {
         self.objects.push_front(obj);
     }
 
+    /// Replaces the stored copy of an object with the same id.
+    pub fn update_object(&mut self, obj: Object) {
+        if let Some(existing) = self.objects.iter_mut().find(|o| o.id == obj.id) {
+            *existing = obj;
+        }
+    }
+
     pub fn remove_object(&mut self, oid: &ObjectId) {
         if let Some(idx) = self.objects.iter().position(|o| &o.id == oid) {
             self.objects.remove(idx);
@@ -48,52 +71,100 @@ impl Session {
 
 impl Default for Session {
     fn default() -> Self {
-        Self::new(SnowflakeId::generate(), None)
+        Self::new(SnowflakeId::generate(), CreateSession::default(), None)
     }
 }
 
-impl Into<SessionDto> for Session {
-    fn into(self) -> SessionDto {
-        SessionDto {
-            id: self.id,
-            creation_time: self.creation_time,
-            crypto: self.crypto.map(Into::into),
+impl From<Session> for SessionDto {
+    fn from(sess: Session) -> Self {
+        Self {
+            id: sess.id,
+            creation_time: sess.creation_time,
+            expires_at: sess.expires_at,
+            crypto: sess.crypto.map(Into::into),
         }
     }
 }
 
+#[derive(Serialize, Deserialize, Default)]
+#[serde(rename_all = "camelCase", try_from = "CreateSessionFields")]
+pub struct CreateSession {
+    #[serde(flatten)]
+    pub crypto: Option<CreateSessionCrypto>,
+    /// Lifetime of the session in seconds.
+    pub ttl: Option<u64>,
+}
+
+/// Fields of a [`CreateSession`] request as sent, so that incomplete crypto parameters are
+/// rejected instead of creating a plaintext session.
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct CreateSessionFields {
+    auth_key: Option<String>,
+    kdf_params: Option<KDFParams>,
+    ttl: Option<u64>,
+}
+
+impl TryFrom<CreateSessionFields> for CreateSession {
+    type Error = &'static str;
+
+    fn try_from(fields: CreateSessionFields) -> Result<Self, Self::Error> {
+        let crypto = match (fields.auth_key, fields.kdf_params) {
+            (Some(auth_key), Some(kdf_params)) => Some(CreateSessionCrypto {
+                auth_key,
+                kdf_params,
+            }),
+            (None, None) => None,
+            _ => return Err("authKey and kdfParams must be given together"),
+        };
+        Ok(Self {
+            crypto,
+            ttl: fields.ttl,
+        })
+    }
+}
+
 #[derive(Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
-pub struct CreateSession {
+pub struct CreateSessionCrypto {
     pub auth_key: String,
     pub kdf_params: KDFParams,
 }
 
-impl Into<SessionCrypto> for CreateSession {
-    fn into(self) -> SessionCrypto {
-        SessionCrypto {
-            kdf_params: self.kdf_params,
-            auth_key: self.auth_key,
+impl From<SessionCrypto> for CreateSessionCrypto {
+    fn from(crypto: SessionCrypto) -> Self {
+        Self {
+            auth_key: crypto.auth_key,
+            kdf_params: crypto.kdf_params,
         }
     }
 }
 
-#[derive(Serialize, Deserialize)]
+impl From<CreateSessionCrypto> for SessionCrypto {
+    fn from(req: CreateSessionCrypto) -> Self {
+        Self {
+            kdf_params: req.kdf_params,
+            auth_key: req.auth_key,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
 #[serde(rename_all = "camelCase")]
 pub struct SessionCryptoDto {
     pub kdf_params: KDFParams,
 }
 
-#[derive(Serialize, Deserialize, PartialEq, Eq, Debug)]
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
 pub struct SessionCrypto {
     pub auth_key: String,
     pub kdf_params: KDFParams,
 }
 
-impl Into<SessionCryptoDto> for SessionCrypto {
-    fn into(self) -> SessionCryptoDto {
-        SessionCryptoDto {
-            kdf_params: self.kdf_params,
+impl From<SessionCrypto> for SessionCryptoDto {
+    fn from(crypto: SessionCrypto) -> Self {
+        Self {
+            kdf_params: crypto.kdf_params,
         }
     }
 }
@@ -13,7 +13,7 @@ const SESSION_EPOCH_SECONDS: u64 = 1717340400;
 type SnowflakeBytes = [u8; 8];
 
 // First 48-bit is timestamp, then the rest is random bytes
-#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
 pub struct SnowflakeId(SnowflakeBytes);
 
 impl SnowflakeId {
@@ -38,21 +38,15 @@ impl SnowflakeId {
     }
 }
 
-impl Default for SnowflakeId {
-    fn default() -> Self {
-        Self(SnowflakeBytes::default())
-    }
-}
-
 impl From<u64> for SnowflakeId {
     fn from(value: u64) -> Self {
         Self::from_u64(value)
     }
 }
 
-impl Into<u64> for SnowflakeId {
-    fn into(self) -> u64 {
-        self.as_u64()
+impl From<SnowflakeId> for u64 {
+    fn from(id: SnowflakeId) -> Self {
+        id.as_u64()
     }
 }
 
@@ -0,0 +1,51 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use super::{
+    object::{FileInfo, Upload},
+    session::SessionId,
+    snowflake::SnowflakeId,
+};
+
+pub type UploadId = SnowflakeId;
+
+/// Resumable upload whose blob is still being received, turned into an object once
+/// `offset` reaches `length`.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct PendingUpload {
+    pub id: UploadId,
+    pub session_id: SessionId,
+    pub length: u64,
+    pub offset: u64,
+    pub upload: Upload,
+    pub file: FileInfo,
+    pub expires_at: DateTime<Utc>,
+}
+
+impl PendingUpload {
+    pub fn new(
+        sid: SessionId,
+        length: u64,
+        upload: Upload,
+        file: FileInfo,
+        expires_at: DateTime<Utc>,
+    ) -> Self {
+        Self {
+            id: UploadId::generate(),
+            session_id: sid,
+            length,
+            offset: 0,
+            upload,
+            file,
+            expires_at,
+        }
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.offset >= self.length
+    }
+
+    pub fn is_expired(&self, now: DateTime<Utc>) -> bool {
+        self.expires_at <= now
+    }
+}
@@ -4,14 +4,23 @@ use std::{
 };
 
 use crate::{
-    models::session::SessionId, ConcreteObjectRepository, ConcreteObjectService,
-    ConcreteSessionRepository, ConcreteWebSocketService, STORAGE_DIR,
+    config::CONFIG, models::session::SessionId, ConcreteObjectRepository, ConcreteObjectService,
+    ConcreteSessionRepository, ConcreteUploadRepository, ConcreteWebSocketService, STORAGE_DIR,
 };
 
 type SessionRegistry<T> = LazyLock<RwLock<HashMap<SessionId, Arc<T>>>>;
 
-pub static SESSION_REPOSITORY: LazyLock<Arc<ConcreteSessionRepository>> =
-    LazyLock::new(|| Arc::new(ConcreteSessionRepository::new(STORAGE_DIR)));
+pub static SESSION_REPOSITORY: LazyLock<Arc<ConcreteSessionRepository>> = LazyLock::new(|| {
+    let repository = ConcreteSessionRepository::open(&CONFIG, STORAGE_DIR)
+        .unwrap_or_else(|e| panic!("Failed to open session repository: {e}"));
+    Arc::new(repository)
+});
+pub static UPLOAD_REPOSITORY: LazyLock<Arc<ConcreteUploadRepository>> = LazyLock::new(|| {
+    Arc::new(ConcreteUploadRepository::new(
+        &SESSION_REPOSITORY,
+        STORAGE_DIR,
+    ))
+});
 pub static OBJECT_REPOSITORIES: SessionRegistry<ConcreteObjectRepository> = register();
 pub static OBJECT_SERVICES: SessionRegistry<ConcreteObjectService> = register();
 pub static WEBSOCKET_SERVICES: SessionRegistry<ConcreteWebSocketService> = register();
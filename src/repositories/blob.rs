@@ -0,0 +1,255 @@
+use std::{
+    fs::Metadata,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use tokio::{fs, sync::Mutex};
+
+use crate::models::{object::Object, session::Session};
+
+use super::{
+    encryption::MasterKey,
+    quota::{staging_path, Quota},
+    Result,
+};
+
+/// Content-addressed store keeping a single copy of identical blobs across all sessions.
+///
+/// Each stored blob is named by its SHA-256 digest, keyed by the master key for sealed
+/// blobs so that their names do not reveal what they hold. The blob files of the objects
+/// are hard links to it, so that the link count of a stored blob doubles as its reference
+/// count and the blob files can still be read like any other. A stored blob is freed once
+/// its own link is the only one left.
+#[derive(Clone)]
+pub struct BlobStore {
+    dir: PathBuf,
+    quota: Quota,
+    master_key: Option<MasterKey>,
+    lock: Arc<Mutex<()>>,
+}
+
+impl BlobStore {
+    pub fn new<D: AsRef<Path>>(dir: D, quota: Quota) -> Self {
+        Self {
+            dir: dir.as_ref().to_path_buf(),
+            quota,
+            master_key: None,
+            lock: Arc::default(),
+        }
+    }
+
+    /// Names sealed blobs with the master key they are sealed with.
+    pub fn with_master_key(mut self, master_key: Option<MasterKey>) -> Self {
+        self.master_key = master_key;
+        self
+    }
+
+    /// Name under which the blob of the object is stored, when its digest is known. Digests
+    /// are of the plain content, so compressed and sealed blobs are stored apart. Sealed
+    /// blobs can only be named with the master key.
+    pub fn name(&self, obj: &Object) -> Option<String> {
+        let sha256 = obj.sha256.as_ref()?;
+        let zst = if obj.compressed { ".zst" } else { "" };
+        if !obj.encrypted {
+            return Some(format!("{sha256}{zst}"));
+        }
+        let name = self.master_key.as_ref()?.name(sha256);
+        Some(format!("{name}{zst}.enc"))
+    }
+
+    /// Names of the stored blobs that the objects of the session may link to.
+    pub fn names(&self, sess: &Session) -> Vec<String> {
+        sess.objects
+            .iter()
+            .filter_map(|obj| self.name(obj))
+            .collect()
+    }
+
+    /// Shares the blob file at `path` through the store, either by storing it or by
+    /// replacing it with a link to an identical blob stored before. The blob file stays
+    /// valid throughout, so a failure only means that it is not shared.
+    pub async fn absorb(&self, path: &Path, name: &str) -> Result<()> {
+        let _lock = self.lock.lock().await;
+        let stored = self.dir.join(name);
+        match fs::metadata(&stored).await {
+            Ok(_) => {
+                let len = fs::metadata(path).await?.len();
+                let tmp = staging_path(path);
+                fs::hard_link(&stored, &tmp).await?;
+                if let Err(e) = fs::rename(&tmp, path).await {
+                    fs::remove_file(&tmp).await.ok();
+                    return Err(Box::new(e));
+                }
+                self.quota.release(len);
+                Ok(())
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                fs::create_dir_all(&self.dir).await?;
+                fs::hard_link(path, &stored).await?;
+                Ok(())
+            }
+            Err(e) => Err(Box::new(e)),
+        }
+    }
+
+    /// Removes the blob file at `path`, freeing the stored blob with the given name when
+    /// nothing else refers to it anymore.
+    pub async fn remove(&self, path: &Path, name: Option<&str>) -> Result<()> {
+        let _lock = self.lock.lock().await;
+        let meta = match fs::metadata(path).await {
+            Ok(meta) => meta,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(Box::new(e)),
+        };
+        fs::remove_file(path).await?;
+        if !is_shared(&meta) {
+            self.quota.release(meta.len());
+        } else if let Some(name) = name {
+            self.collect(&self.dir.join(name)).await?;
+        }
+        Ok(())
+    }
+
+    /// Frees the stored blobs with the given names that nothing refers to anymore, as after
+    /// a whole session directory linking to them has been removed.
+    pub async fn release<I: IntoIterator<Item = String>>(&self, names: I) -> Result<()> {
+        let _lock = self.lock.lock().await;
+        for name in names {
+            self.collect(&self.dir.join(name)).await?;
+        }
+        Ok(())
+    }
+
+    /// Frees every stored blob that nothing refers to anymore. This goes through the whole
+    /// store, so it is left to maintenance like checking the storage directory.
+    pub async fn collect_all(&self) -> Result<()> {
+        let _lock = self.lock.lock().await;
+        let mut entries = match fs::read_dir(&self.dir).await {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(Box::new(e)),
+        };
+        while let Some(entry) = entries.next_entry().await? {
+            self.collect(&entry.path()).await?;
+        }
+        Ok(())
+    }
+
+    async fn collect(&self, stored: &Path) -> Result<()> {
+        match fs::metadata(stored).await {
+            Ok(meta) if !is_shared(&meta) => {
+                fs::remove_file(stored).await?;
+                self.quota.release(meta.len());
+                Ok(())
+            }
+            Err(e) if e.kind() != ErrorKind::NotFound => Err(Box::new(e)),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Whether the file has other hard links, which only stored blobs and the blob files
+/// linked to them have.
+#[cfg(unix)]
+pub(super) fn is_shared(meta: &Metadata) -> bool {
+    use std::os::unix::fs::MetadataExt;
+
+    meta.nlink() > 1
+}
+
+#[cfg(not(unix))]
+pub(super) fn is_shared(_meta: &Metadata) -> bool {
+    false
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use temp_dir::TempDir;
+    use tokio::io::AsyncReadExt;
+
+    use crate::{
+        models::{
+            object::{Object, Upload},
+            session::Session,
+        },
+        repositories::{
+            object::{ObjectFsRepository, ObjectRepository},
+            quota::QuotaLimits,
+            session::{SessionFsRepository, SessionRepository},
+        },
+    };
+
+    use super::*;
+
+    #[tokio::test]
+    async fn deduplicate_identical_blobs() -> Result<()> {
+        let tmpdir = TempDir::new()?;
+        let dir = tmpdir.path();
+        let quota = Quota::new(QuotaLimits::default());
+        let blobs = BlobStore::new(dir.join("blobs"), quota.clone());
+        let sessions = SessionFsRepository::new(dir)
+            .with_quota(quota.clone())
+            .with_blob_store(Some(blobs.clone()));
+
+        let mut uploads = Vec::new();
+        for _ in 0..2 {
+            let sess = Session::default();
+            sessions.create(&sess).await?;
+            let repo = ObjectFsRepository::new(dir.join(sess.id.to_string()))
+                .with_quota(quota.clone())
+                .with_blob_store(Some(blobs.clone()));
+            let mut obj: Object = Upload::default().into();
+            repo.upload(&mut obj, &b"Same installer"[..]).await?;
+            uploads.push((sess.id, repo, obj));
+        }
+        let stored = dir
+            .join("blobs")
+            .join(uploads[0].2.sha256.as_ref().unwrap());
+        assert_eq!(fs::metadata(&stored).await?.len(), 14);
+        assert_eq!(quota.used(), 14);
+        assert_eq!(Quota::scan(QuotaLimits::default(), dir)?.used(), 14);
+
+        let (_, repo, obj) = &uploads[0];
+        repo.delete(&obj.id).await?;
+        assert!(stored.exists());
+        assert_eq!(quota.used(), 14);
+
+        let (sid, repo, obj) = &uploads[1];
+        let mut buf = Vec::new();
+        repo.download(&obj.id, None)
+            .await?
+            .read_to_end(&mut buf)
+            .await?;
+        assert_eq!(buf, b"Same installer");
+        // Only the blobs of the deleted session are looked at, leaving this one for fsck.
+        let unrelated = dir.join("blobs").join("0".repeat(64));
+        fs::write(&unrelated, b"Unrelated").await?;
+        sessions.delete(sid).await?;
+        assert!(!stored.exists());
+        assert!(unrelated.exists());
+        assert_eq!(quota.used(), 0);
+        Ok(())
+    }
+
+    #[test]
+    fn name_sealed_blobs_with_the_master_key() {
+        let mut obj: Object = Upload::default().into();
+        let sha256 = "ab".repeat(32);
+        obj.sha256 = Some(sha256.clone());
+        let store = |key: Option<[u8; 32]>| {
+            BlobStore::new("blobs", Quota::default())
+                .with_master_key(key.as_ref().map(MasterKey::new))
+        };
+        assert_eq!(store(None).name(&obj), Some(sha256.clone()));
+
+        obj.encrypted = true;
+        assert_eq!(store(None).name(&obj), None);
+        let name = store(Some([1; 32])).name(&obj).unwrap();
+        assert!(!name.contains(&sha256));
+        assert!(name.ends_with(".enc"));
+        assert_eq!(store(Some([1; 32])).name(&obj), Some(name.clone()));
+        assert_ne!(store(Some([2; 32])).name(&obj), Some(name));
+    }
+}
@@ -0,0 +1,65 @@
+use std::ops::Range;
+
+use async_compression::tokio::bufread::{ZstdDecoder, ZstdEncoder};
+use tokio::io::{self, AsyncRead, AsyncReadExt, BufReader};
+
+use super::Result;
+
+/// Content types that are compressed already, where compressing again only costs time.
+const COMPRESSED_TYPES: &[&str] = &[
+    "application/gzip",
+    "application/java-archive",
+    "application/pdf",
+    "application/vnd.rar",
+    "application/x-7z-compressed",
+    "application/x-bzip2",
+    "application/x-gzip",
+    "application/x-rar-compressed",
+    "application/x-xz",
+    "application/zip",
+    "application/zstd",
+    "font/woff",
+    "font/woff2",
+];
+
+/// Whether a blob of the given content type is worth compressing at rest. Blobs of
+/// unknown types are, as plain text often comes without one.
+pub fn is_compressible(mime: Option<&str>) -> bool {
+    let Some(mime) = mime else {
+        return true;
+    };
+    let mime = mime.split(';').next().unwrap_or_default().trim();
+    let is_media = ["image/", "video/", "audio/"]
+        .iter()
+        .any(|prefix| mime.starts_with(prefix));
+    // Scalable vector graphics are XML text after all.
+    (!is_media || mime == "image/svg+xml") && !COMPRESSED_TYPES.contains(&mime)
+}
+
+/// Compresses everything read from `reader` with zstd.
+pub fn compress<R: AsyncRead + Unpin + Send>(reader: R) -> impl AsyncRead + Unpin + Send {
+    ZstdEncoder::new(BufReader::new(reader))
+}
+
+/// Streams the decompressed content of a zstd compressed blob, limited to the range if one
+/// is given. Compressed blobs cannot be seeked, so the bytes before the range are
+/// decompressed only to be skipped.
+pub(super) async fn decompress<R>(
+    reader: R,
+    range: Option<Range<u64>>,
+) -> Result<Box<dyn AsyncRead + Unpin + Send + Sync>>
+where
+    R: AsyncRead + Unpin + Send + Sync + 'static,
+{
+    let mut reader = ZstdDecoder::new(BufReader::new(reader));
+    match range {
+        Some(range) => {
+            let skipped = io::copy(&mut (&mut reader).take(range.start), &mut io::sink()).await?;
+            if skipped < range.start {
+                return Err(Box::new(io::Error::from(io::ErrorKind::UnexpectedEof)));
+            }
+            Ok(Box::new(reader.take(range.end - range.start)))
+        }
+        None => Ok(Box::new(reader)),
+    }
+}
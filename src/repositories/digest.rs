@@ -0,0 +1,80 @@
+use std::{
+    error::Error,
+    fmt::Display,
+    io,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use sha2::{Digest, Sha256};
+use tokio::io::{AsyncRead, ReadBuf};
+
+use crate::models::object::Object;
+
+use super::Result;
+
+/// The blob uploaded does not have the digest the uploader expected.
+#[derive(Debug)]
+pub struct DigestMismatch;
+
+impl Display for DigestMismatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("SHA-256 digest mismatch")
+    }
+}
+
+impl Error for DigestMismatch {}
+
+/// Computes the SHA-256 digest and counts the bytes of everything read through it.
+pub struct DigestReader<R> {
+    inner: R,
+    hasher: Sha256,
+    size: u64,
+}
+
+impl<R> DigestReader<R> {
+    pub fn new(inner: R) -> Self {
+        Self {
+            inner,
+            hasher: Sha256::new(),
+            size: 0,
+        }
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Records the hex digest of the blob read in `obj`, failing when it differs from the
+    /// digest the uploader expected there.
+    pub fn verify(self, obj: &mut Object) -> Result<()> {
+        let actual = hex::encode(self.hasher.finalize());
+        match obj.sha256.as_deref() {
+            Some(expected) if !expected.eq_ignore_ascii_case(&actual) => {
+                Err(Box::new(DigestMismatch))
+            }
+            _ => {
+                obj.sha256 = Some(actual);
+                Ok(())
+            }
+        }
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for DigestReader<R> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let filled = buf.filled().len();
+        let poll = Pin::new(&mut this.inner).poll_read(cx, buf);
+        if let Poll::Ready(Ok(())) = poll {
+            let read = &buf.filled()[filled..];
+            this.hasher.update(read);
+            this.size += read.len() as u64;
+        }
+        poll
+    }
+}
@@ -0,0 +1,337 @@
+use std::{
+    error::Error,
+    fmt::Display,
+    io::{self, SeekFrom},
+    ops::Range,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use aes_gcm::{aead::Aead, Aes256Gcm, Key, KeyInit, Nonce};
+use axum::body::Bytes;
+use base64::{prelude::BASE64_STANDARD, Engine};
+use futures::{stream, Stream, StreamExt};
+use hmac::{Hmac, Mac};
+use rand::{rngs::StdRng, RngCore, SeedableRng};
+use sha2::Sha256;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeekExt};
+use tokio_util::io::StreamReader;
+
+use super::Result;
+
+/// Marks files sealed with a master key, which JSON files never start with.
+const MAGIC: &[u8; 4] = b"WDE\x01";
+
+const KEY_SIZE: usize = 32;
+const NONCE_SIZE: usize = 12;
+const TAG_SIZE: usize = 16;
+const PREFIX_SIZE: usize = 7;
+
+/// Magic, nonce and wrapped data key, and the nonce prefix of the chunks.
+const HEADER_SIZE: usize = MAGIC.len() + NONCE_SIZE + KEY_SIZE + TAG_SIZE + PREFIX_SIZE;
+
+/// Plaintext bytes per chunk, each of which is encrypted and authenticated on its own so
+/// that ranges can be decrypted without the chunks before them.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// A sealed file could not be decrypted, as no master key or another one is configured,
+/// or the file was tampered with or truncated.
+#[derive(Debug)]
+pub struct DecryptionError;
+
+impl Display for DecryptionError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("failed to decrypt sealed file")
+    }
+}
+
+impl Error for DecryptionError {}
+
+/// Server key that seals files at rest. Every file gets its own random data key, which is
+/// stored wrapped by the master key in the header of the file. The content follows in
+/// chunks encrypted with AES-256-GCM, whose nonces carry the chunk index and whether the
+/// chunk is the last one so that chunks cannot be reordered or cut off unnoticed.
+#[derive(Clone)]
+pub struct MasterKey {
+    cipher: Arc<Aes256Gcm>,
+    /// Derived from the master key for naming content, apart from the key that seals it.
+    naming_key: Arc<[u8]>,
+}
+
+impl MasterKey {
+    pub fn new(key: &[u8; KEY_SIZE]) -> Self {
+        Self {
+            cipher: Arc::new(Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(key))),
+            naming_key: hmac_sha256(key, b"webdrop blob names").into(),
+        }
+    }
+
+    /// Names content by its digest without giving the digest away, so that the name cannot
+    /// be matched against known content without the master key.
+    pub fn name(&self, digest: &str) -> String {
+        hex::encode(hmac_sha256(&self.naming_key, digest.as_bytes()))
+    }
+
+    /// Reads the master key from a file holding the base64 encoded 32 bytes of the key.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let encoded = std::fs::read_to_string(path)?;
+        let key = BASE64_STANDARD
+            .decode(encoded.trim())
+            .ok()
+            .and_then(|key| <[u8; KEY_SIZE]>::try_from(key).ok())
+            .ok_or_else(|| {
+                format!(
+                    "{} does not hold a base64 encoded 32-byte key",
+                    path.display()
+                )
+            })?;
+        Ok(Self::new(&key))
+    }
+
+    /// Whether the content was sealed with a master key.
+    pub fn is_sealed(data: &[u8]) -> bool {
+        data.starts_with(MAGIC)
+    }
+
+    /// Seals the whole of `data` at once, as is done for metadata files.
+    pub fn seal(&self, data: &[u8]) -> Result<Vec<u8>> {
+        let (mut sealed, mut chunks) = self.start_sealing()?;
+        let mut iter = data.chunks(CHUNK_SIZE).peekable();
+        if iter.peek().is_none() {
+            sealed.extend(chunks.seal(&[], true)?);
+        }
+        while let Some(chunk) = iter.next() {
+            sealed.extend(chunks.seal(chunk, iter.peek().is_none())?);
+        }
+        Ok(sealed)
+    }
+
+    /// Opens the whole of `data` sealed by [`MasterKey::seal`] or [`MasterKey::encrypt`].
+    pub fn open(&self, data: &[u8]) -> Result<Vec<u8>> {
+        let body = data.get(HEADER_SIZE..).ok_or(DecryptionError)?;
+        let chunks = self.open_header(&data[..HEADER_SIZE])?;
+        let count = chunk_count(body.len() as u64);
+        let mut opened = Vec::with_capacity(body.len());
+        for (index, chunk) in body.chunks(CHUNK_SIZE + TAG_SIZE).enumerate() {
+            opened.extend(chunks.open(chunk, index as u64, index as u64 + 1 == count)?);
+        }
+        Ok(opened)
+    }
+
+    /// Seals everything read from `reader` while it is streamed.
+    pub fn encrypt<R>(&self, reader: R) -> Result<impl AsyncRead + Unpin + Send>
+    where
+        R: AsyncRead + Unpin + Send,
+    {
+        let (header, chunks) = self.start_sealing()?;
+        // One byte is read past each chunk to learn whether it is the last one.
+        let body = stream::try_unfold(
+            Some((reader, chunks, Vec::with_capacity(CHUNK_SIZE + 1))),
+            |state| async move {
+                let Some((mut reader, mut chunks, mut buf)) = state else {
+                    return Ok(None);
+                };
+                while buf.len() <= CHUNK_SIZE {
+                    let mut limited = (&mut reader).take((CHUNK_SIZE + 1 - buf.len()) as u64);
+                    if limited.read_buf(&mut buf).await? == 0 {
+                        break;
+                    }
+                }
+                if buf.len() > CHUNK_SIZE {
+                    let rest = buf.split_off(CHUNK_SIZE);
+                    let sealed = chunks.seal(&buf, false).map_err(io::Error::other)?;
+                    Ok(Some((Bytes::from(sealed), Some((reader, chunks, rest)))))
+                } else {
+                    let sealed = chunks.seal(&buf, true).map_err(io::Error::other)?;
+                    Ok(Some((Bytes::from(sealed), None)))
+                }
+            },
+        );
+        let stream = stream::once(async { Ok::<_, io::Error>(Bytes::from(header)) }).chain(body);
+        Ok(StreamReader::new(Box::pin(stream)))
+    }
+
+    /// Opens a blob file sealed by [`MasterKey::encrypt`] for streaming its decrypted
+    /// content, limited to the range if one is given. Only the chunks overlapping the
+    /// range are read.
+    pub(super) async fn open_blob(
+        &self,
+        path: PathBuf,
+        range: Option<Range<u64>>,
+    ) -> Result<Box<dyn AsyncRead + Unpin + Send + Sync>> {
+        let mut file = tokio::fs::File::open(path).await?;
+        let len = file.metadata().await?.len();
+        let mut header = [0u8; HEADER_SIZE];
+        file.read_exact(&mut header).await?;
+        let chunks = self.open_header(&header)?;
+        let count = chunk_count(len - HEADER_SIZE as u64);
+
+        let (first, skip) = match &range {
+            Some(range) => (
+                range.start / CHUNK_SIZE as u64,
+                range.start % CHUNK_SIZE as u64,
+            ),
+            None => (0, 0),
+        };
+        let offset = HEADER_SIZE as u64 + first * (CHUNK_SIZE + TAG_SIZE) as u64;
+        file.seek(SeekFrom::Start(offset)).await?;
+
+        let stream = stream::try_unfold(
+            (file, chunks, first),
+            move |(mut file, chunks, index)| async move {
+                if index >= count {
+                    return Ok(None);
+                }
+                let mut buf = Vec::with_capacity(CHUNK_SIZE + TAG_SIZE);
+                (&mut file)
+                    .take((CHUNK_SIZE + TAG_SIZE) as u64)
+                    .read_to_end(&mut buf)
+                    .await?;
+                let opened = chunks
+                    .open(&buf, index, index + 1 == count)
+                    .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, DecryptionError))?;
+                Ok(Some((Bytes::from(opened), (file, chunks, index + 1))))
+            },
+        );
+        let mut reader = StreamReader::new(Box::pin(stream) as ChunkStream);
+        if skip > 0 {
+            let skipped =
+                tokio::io::copy(&mut (&mut reader).take(skip), &mut tokio::io::sink()).await?;
+            if skipped < skip {
+                return Err(Box::new(io::Error::from(io::ErrorKind::UnexpectedEof)));
+            }
+        }
+        match range {
+            Some(range) => Ok(Box::new(reader.take(range.end - range.start))),
+            None => Ok(Box::new(reader)),
+        }
+    }
+
+    /// Generates a data key, returning the header storing it wrapped and the cipher that
+    /// seals the chunks following the header.
+    fn start_sealing(&self) -> Result<(Vec<u8>, Chunks)> {
+        let mut rng = StdRng::from_os_rng();
+        let mut key = [0u8; KEY_SIZE];
+        let mut nonce = [0u8; NONCE_SIZE];
+        let mut prefix = [0u8; PREFIX_SIZE];
+        rng.fill_bytes(&mut key);
+        rng.fill_bytes(&mut nonce);
+        rng.fill_bytes(&mut prefix);
+
+        let wrapped = self
+            .cipher
+            .encrypt(Nonce::from_slice(&nonce), &key[..])
+            .map_err(|_| "failed to wrap data key")?;
+        let mut header = Vec::with_capacity(HEADER_SIZE);
+        header.extend_from_slice(MAGIC);
+        header.extend_from_slice(&nonce);
+        header.extend_from_slice(&wrapped);
+        header.extend_from_slice(&prefix);
+        Ok((header, Chunks::new(&key, prefix)))
+    }
+
+    fn open_header(&self, header: &[u8]) -> Result<Chunks> {
+        if !Self::is_sealed(header) {
+            return Err(Box::new(DecryptionError));
+        }
+        let (nonce, rest) = header[MAGIC.len()..].split_at(NONCE_SIZE);
+        let (wrapped, prefix) = rest.split_at(KEY_SIZE + TAG_SIZE);
+        let key = self
+            .cipher
+            .decrypt(Nonce::from_slice(nonce), wrapped)
+            .map_err(|_| DecryptionError)?;
+        let key = <[u8; KEY_SIZE]>::try_from(key).map_err(|_| DecryptionError)?;
+        let prefix = <[u8; PREFIX_SIZE]>::try_from(prefix).map_err(|_| DecryptionError)?;
+        Ok(Chunks::new(&key, prefix))
+    }
+}
+
+type ChunkStream = std::pin::Pin<Box<dyn Stream<Item = io::Result<Bytes>> + Send + Sync>>;
+
+/// Number of chunks in a sealed body of the given length, which always has at least one.
+fn chunk_count(body_len: u64) -> u64 {
+    body_len.div_ceil((CHUNK_SIZE + TAG_SIZE) as u64).max(1)
+}
+
+/// Cipher of the chunks of a single sealed file.
+struct Chunks {
+    cipher: Aes256Gcm,
+    prefix: [u8; PREFIX_SIZE],
+    index: u64,
+}
+
+impl Chunks {
+    fn new(key: &[u8; KEY_SIZE], prefix: [u8; PREFIX_SIZE]) -> Self {
+        Self {
+            cipher: Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(key)),
+            prefix,
+            index: 0,
+        }
+    }
+
+    fn nonce(&self, index: u64, last: bool) -> Result<[u8; NONCE_SIZE]> {
+        let index = u32::try_from(index).map_err(|_| "sealed file is too large")?;
+        let mut nonce = [0u8; NONCE_SIZE];
+        nonce[..PREFIX_SIZE].copy_from_slice(&self.prefix);
+        nonce[PREFIX_SIZE..NONCE_SIZE - 1].copy_from_slice(&index.to_be_bytes());
+        nonce[NONCE_SIZE - 1] = u8::from(last);
+        Ok(nonce)
+    }
+
+    fn seal(&mut self, chunk: &[u8], last: bool) -> Result<Vec<u8>> {
+        let nonce = self.nonce(self.index, last)?;
+        self.index += 1;
+        Ok(self
+            .cipher
+            .encrypt(Nonce::from_slice(&nonce), chunk)
+            .map_err(|_| "failed to encrypt chunk")?)
+    }
+
+    fn open(&self, chunk: &[u8], index: u64, last: bool) -> Result<Vec<u8>> {
+        let nonce = self.nonce(index, last)?;
+        Ok(self
+            .cipher
+            .decrypt(Nonce::from_slice(&nonce), chunk)
+            .map_err(|_| DecryptionError)?)
+    }
+}
+
+fn hmac_sha256(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut mac = <Hmac<Sha256> as hmac::KeyInit>::new_from_slice(key)
+        .expect("HMAC accepts keys of any size");
+    mac.update(data);
+    mac.finalize().into_bytes().to_vec()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn seal_and_open() -> Result<()> {
+        let key = MasterKey::new(&[7; KEY_SIZE]);
+        let content: Vec<u8> = (0..CHUNK_SIZE * 2 + 100).map(|i| i as u8).collect();
+
+        let sealed = key.seal(&content)?;
+        assert!(MasterKey::is_sealed(&sealed));
+        assert_eq!(key.open(&sealed)?, content);
+        assert_eq!(key.open(&key.seal(b"")?)?, b"");
+
+        let mut streamed = Vec::new();
+        key.encrypt(&content[..])?
+            .read_to_end(&mut streamed)
+            .await?;
+        assert_eq!(streamed.len(), sealed.len());
+        assert_eq!(key.open(&streamed)?, content);
+
+        // Dropping the last chunk must not go unnoticed.
+        let cut = HEADER_SIZE + 2 * (CHUNK_SIZE + TAG_SIZE);
+        assert!(key.open(&streamed[..cut]).is_err());
+        let mut tampered = streamed.clone();
+        tampered[HEADER_SIZE + 1] ^= 1;
+        assert!(key.open(&tampered).is_err());
+        assert!(MasterKey::new(&[8; KEY_SIZE]).open(&streamed).is_err());
+        Ok(())
+    }
+}
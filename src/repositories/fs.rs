@@ -1,37 +1,321 @@
 use std::{
+    collections::HashMap,
+    ffi::OsStr,
     fs,
-    io::{Read, Write},
-    path::Path,
+    future::Future,
+    io::{Error as IoError, ErrorKind, SeekFrom, Write},
+    ops::Range,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{Arc, LazyLock, Mutex as StdMutex, Weak},
+    time::{Duration, SystemTime},
 };
 
+use chrono::Utc;
+use rand::{rngs::SmallRng, RngCore, SeedableRng};
 use serde::{de::DeserializeOwned, Serialize};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncSeekExt},
+    sync::{Mutex, OwnedMutexGuard},
+};
+use tracing::{event, Level};
+
+use crate::models::{
+    object::{Object, ObjectId},
+    session::{Session, SessionId},
+};
+
+use super::{
+    encryption::{DecryptionError, MasterKey},
+    Result, SESSION_AUTH_KEY_FILE, SESSION_FILE, SESSION_LOCK_FILE, STAGING_SUFFIX, UPLOADS_DIR,
+};
+
+const TEMP_SUFFIX: &str = ".tmp";
+
+pub trait BaseFsRepository: Sync {
+    /// Key that metadata files are sealed with, which are stored as is when unset.
+    fn master_key(&self) -> Option<&MasterKey> {
+        None
+    }
+
+    /// Atomically replaces the file at `path` with the serialized data.
+    fn save<P: AsRef<Path>, T: Serialize>(
+        &self,
+        path: P,
+        data: &T,
+    ) -> impl Future<Output = Result<()>> + Send {
+        let path = path.as_ref().to_path_buf();
+        let buf = self.serialize(data);
+        async move { write_atomic(path, buf?, true).await }
+    }
+
+    /// Atomically creates the file at `path`, failing if it already exists.
+    fn save_new<P: AsRef<Path>, T: Serialize>(
+        &self,
+        path: P,
+        data: &T,
+    ) -> impl Future<Output = Result<()>> + Send {
+        let path = path.as_ref().to_path_buf();
+        let buf = self.serialize(data);
+        async move { write_atomic(path, buf?, false).await }
+    }
+
+    /// Loads a metadata file, which may have been stored as is before a master key was
+    /// configured.
+    fn load<P: AsRef<Path>, T: DeserializeOwned>(
+        &self,
+        path: P,
+    ) -> impl Future<Output = Result<T>> + Send {
+        let path = path.as_ref().to_path_buf();
+        let key = self.master_key().cloned();
+        async move {
+            let buf = tokio::fs::read(path).await?;
+            if !MasterKey::is_sealed(&buf) {
+                return Ok(serde_json::from_slice(&buf)?);
+            }
+            let key = key.ok_or(DecryptionError)?;
+            Ok(serde_json::from_slice(&key.open(&buf)?)?)
+        }
+    }
+
+    fn serialize<T: Serialize>(&self, data: &T) -> Result<Vec<u8>> {
+        let json = serde_json::to_vec(data)?;
+        match self.master_key() {
+            Some(key) => key.seal(&json),
+            None => Ok(json),
+        }
+    }
+
+    fn read<P: AsRef<Path>>(&self, path: P) -> impl Future<Output = Result<Vec<u8>>> + Send {
+        let path = path.as_ref().to_path_buf();
+        async move { Ok(tokio::fs::read(path).await?) }
+    }
+
+    fn read_string<P: AsRef<Path>>(&self, path: P) -> impl Future<Output = Result<String>> + Send {
+        let path = path.as_ref().to_path_buf();
+        async move { Ok(tokio::fs::read_to_string(path).await?) }
+    }
+
+    /// Loads `session.json` from the session directory, rebuilding it from the object
+    /// metadata files next to it if it can no longer be parsed. Sessions that are end-to-end
+    /// encrypted are never rebuilt, as their key derivation parameters are only stored there.
+    fn load_session<P: AsRef<Path>>(&self, dir: P) -> impl Future<Output = Result<Session>> + Send {
+        let dir = dir.as_ref().to_path_buf();
+        async move {
+            let path = dir.join(SESSION_FILE);
+            let err = match self.load(&path).await {
+                Ok(sess) => return Ok(sess),
+                Err(err) => err,
+            };
+            if !err.is::<serde_json::Error>() {
+                return Err(err);
+            }
+            event!(
+                Level::WARN,
+                "Rebuilding corrupted session file {}: {err}",
+                path.display()
+            );
+            let sess = self.rebuild_session(&dir).await?;
+            self.save(&path, &sess).await?;
+            Ok(sess)
+        }
+    }
+
+    fn rebuild_session(&self, dir: &Path) -> impl Future<Output = Result<Session>> + Send {
+        let dir = dir.to_path_buf();
+        async move {
+            let sid = dir
+                .file_name()
+                .and_then(OsStr::to_str)
+                .and_then(|name| SessionId::from_str(name).ok())
+                .ok_or_else(|| IoError::new(ErrorKind::InvalidInput, "not a session directory"))?;
 
-use super::Result;
+            let mut objects: Vec<Object> = Vec::new();
+            let mut entries = tokio::fs::read_dir(&dir).await?;
+            while let Some(entry) = entries.next_entry().await? {
+                let path = entry.path();
+                let is_object = path.extension() == Some(OsStr::new("json"))
+                    && path
+                        .file_stem()
+                        .and_then(OsStr::to_str)
+                        .is_some_and(|stem| ObjectId::from_str(stem).is_ok());
+                if !is_object {
+                    continue;
+                }
+                match self.load(&path).await {
+                    Ok(obj) => objects.push(obj),
+                    Err(e) => event!(
+                        Level::WARN,
+                        "Skipping unreadable object metadata {}: {e}",
+                        path.display()
+                    ),
+                }
+            }
+            objects.sort_by_key(|obj| std::cmp::Reverse(obj.timestamp));
 
-pub trait BaseFsRepository {
-    fn save<T: Serialize>(&self, mut file: fs::File, data: &T) -> Result<()> {
-        let json = serde_json::to_string(data)?;
-        file.write_all(json.as_bytes())?;
-        Ok(())
+            // Rebuilt without its key derivation parameters, an end-to-end encrypted session
+            // would be served as a plaintext one.
+            if tokio::fs::try_exists(dir.join(SESSION_AUTH_KEY_FILE)).await? {
+                let msg = format!("key derivation parameters of session {sid} are lost");
+                return Err(IoError::new(ErrorKind::InvalidData, msg).into());
+            }
+            let creation_time = objects.last().map_or_else(Utc::now, |obj| obj.timestamp);
+            Ok(Session {
+                id: sid,
+                objects: objects.into(),
+                creation_time,
+                expires_at: None,
+                crypto: None,
+            })
+        }
     }
+}
+
+/// Exclusive right to mutate the metadata of a session directory, held until dropped.
+pub(super) struct SessionLock {
+    file: fs::File,
+    _guard: OwnedMutexGuard<()>,
+}
+
+impl Drop for SessionLock {
+    fn drop(&mut self) {
+        self.file.unlock().ok();
+    }
+}
 
-    fn load<P: AsRef<Path>, T: DeserializeOwned>(&self, path: P) -> Result<T> {
-        let mut file = fs::File::open(path)?;
-        let data = serde_json::from_reader(&mut file)?;
-        Ok(data)
+/// In-process mutexes of the session directories, kept for as long as anything holds one.
+static SESSION_MUTEXES: LazyLock<StdMutex<HashMap<PathBuf, Weak<Mutex<()>>>>> =
+    LazyLock::new(StdMutex::default);
+
+/// Mutex shared by everything in the process locking the session directory at `dir`, to be
+/// passed to [`lock_session`].
+pub(super) fn session_mutex(dir: &Path) -> Arc<Mutex<()>> {
+    let mut mutexes = SESSION_MUTEXES.lock().unwrap();
+    if let Some(mutex) = mutexes.get(dir).and_then(Weak::upgrade) {
+        return mutex;
     }
+    mutexes.retain(|_, mutex| mutex.strong_count() > 0);
+    let mutex = Arc::default();
+    mutexes.insert(dir.to_path_buf(), Arc::downgrade(&mutex));
+    mutex
+}
+
+/// Serializes metadata writers of a session directory, first among the tasks sharing the
+/// in-process mutex and then through an advisory lock on `session.lock` so that other
+/// processes using the same storage directory are excluded as well.
+pub(super) async fn lock_session(dir: &Path, mutex: &Arc<Mutex<()>>) -> Result<SessionLock> {
+    let guard = Arc::clone(mutex).lock_owned().await;
+    let path = dir.join(SESSION_LOCK_FILE);
+    let file = tokio::task::spawn_blocking(move || {
+        let file = fs::OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(path)?;
+        file.lock()?;
+        Ok::<_, IoError>(file)
+    })
+    .await??;
+    Ok(SessionLock {
+        file,
+        _guard: guard,
+    })
+}
 
-    fn read<P: AsRef<Path>>(&self, path: P) -> Result<Vec<u8>> {
-        let mut buf = Vec::new();
-        let mut file = fs::File::open(path)?;
-        file.read_to_end(&mut buf)?;
-        Ok(buf)
+/// Opens a blob file for reading, positioned at and limited to the range if one is given.
+pub(super) async fn open_blob(
+    path: PathBuf,
+    range: Option<Range<u64>>,
+) -> Result<Box<dyn AsyncRead + Unpin + Send + Sync>> {
+    let mut file = tokio::fs::File::open(path).await?;
+    match range {
+        Some(range) => {
+            file.seek(SeekFrom::Start(range.start)).await?;
+            Ok(Box::new(file.take(range.end - range.start)))
+        }
+        None => Ok(Box::new(file)),
     }
+}
 
-    fn read_string<P: AsRef<Path>>(&self, path: P) -> Result<String> {
-        let mut buf = String::new();
-        let mut file = fs::File::open(path)?;
-        file.read_to_string(&mut buf)?;
-        Ok(buf)
+/// Removes the staging and temporary files left behind in the storage directory by writes
+/// that never finished, returning how many were removed. Only files untouched for
+/// `max_age` are removed, as another process may still be writing to the others.
+pub fn sweep_staging<D: AsRef<Path>>(dir: D, max_age: Duration) -> Result<usize> {
+    let mut removed = 0;
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let has_blobs = path
+            .file_name()
+            .and_then(OsStr::to_str)
+            .is_some_and(|name| name == UPLOADS_DIR || SessionId::from_str(name).is_ok());
+        if !has_blobs || !path.is_dir() {
+            continue;
+        }
+        for entry in fs::read_dir(&path)? {
+            let entry = entry?;
+            let is_staging = entry.file_name().to_str().is_some_and(|name| {
+                name.starts_with('.')
+                    && (name.ends_with(STAGING_SUFFIX) || name.ends_with(TEMP_SUFFIX))
+            });
+            if !is_staging {
+                continue;
+            }
+            let modified = entry.metadata()?.modified()?;
+            let age = SystemTime::now()
+                .duration_since(modified)
+                .unwrap_or_default();
+            if age >= max_age {
+                fs::remove_file(entry.path())?;
+                removed += 1;
+            }
+        }
     }
+    Ok(removed)
+}
+
+/// Writes into a temporary sibling, syncs it and then moves it into place, so readers
+/// only ever observe either the previous or the new content.
+pub(super) async fn write_atomic(path: PathBuf, data: Vec<u8>, overwrite: bool) -> Result<()> {
+    tokio::task::spawn_blocking(move || write_atomic_blocking(&path, &data, overwrite)).await?
+}
+
+fn write_atomic_blocking(path: &Path, data: &[u8], overwrite: bool) -> Result<()> {
+    let tmp = temp_path(path);
+    let result = (|| {
+        let mut file = fs::File::create_new(&tmp)?;
+        file.write_all(data)?;
+        file.sync_all()?;
+        drop(file);
+        if overwrite {
+            fs::rename(&tmp, path)
+        } else {
+            // Linking refuses to replace an existing file, unlike renaming.
+            fs::hard_link(&tmp, path).and_then(|_| fs::remove_file(&tmp))
+        }
+    })();
+    if let Err(e) = result {
+        fs::remove_file(&tmp).ok();
+        return Err(Box::new(e));
+    }
+    sync_parent(path)?;
+    Ok(())
+}
+
+fn temp_path(path: &Path) -> PathBuf {
+    let name = path.file_name().and_then(OsStr::to_str).unwrap_or_default();
+    let suffix = SmallRng::from_os_rng().next_u32();
+    path.with_file_name(format!(".{name}.{suffix:08x}{TEMP_SUFFIX}"))
+}
+
+#[cfg(unix)]
+fn sync_parent(path: &Path) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::File::open(parent)?.sync_all()?;
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn sync_parent(_path: &Path) -> Result<()> {
+    Ok(())
 }
@@ -0,0 +1,388 @@
+use std::{
+    collections::HashMap,
+    ffi::OsStr,
+    fmt::Display,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::{Duration, SystemTime},
+};
+
+use tokio::fs;
+
+use crate::models::{
+    object::{Object, ObjectId},
+    session::{Session, SessionId},
+};
+
+use super::{
+    blob::BlobStore,
+    encryption::{DecryptionError, MasterKey},
+    fs::{lock_session, session_mutex, BaseFsRepository},
+    quota::Quota,
+    Result, BLOBS_DIR, SESSION_AUTH_KEY_FILE, SESSION_FILE, UPLOADS_DIR,
+};
+
+/// Age a blob without metadata must reach before it counts as an orphan, leaving time for
+/// the metadata of an upload that is still being completed to be written.
+const ORPHAN_MIN_AGE: Duration = Duration::from_secs(10 * 60);
+
+/// Inconsistency found in the storage directory of the fs backend.
+#[derive(PartialEq, Eq, Debug)]
+pub enum Issue {
+    /// Directory whose name is not a session id. These are never repaired, since they
+    /// may hold anything.
+    UnknownDirectory(PathBuf),
+    /// `session.json` is missing or cannot be parsed, repaired by rebuilding it from the
+    /// object metadata.
+    UnreadableSession(SessionId),
+    /// `session.json` of an end-to-end encrypted session is missing or cannot be parsed.
+    /// Never repaired, since the key derivation parameters it held cannot be rebuilt, and
+    /// the rest of the session is left unchecked.
+    UnrecoverableSession(SessionId),
+    /// `{oid}.json` cannot be parsed, repaired by restoring it from `session.json` or by
+    /// deleting the object when it is not listed there.
+    UnreadableMetadata(SessionId, ObjectId),
+    /// Blob without `{oid}.json` that is older than a few minutes, repaired by deleting the
+    /// blob.
+    OrphanBlob(SessionId, ObjectId),
+    /// Uploaded object whose blob is gone, repaired by deleting the object.
+    MissingBlob(SessionId, ObjectId),
+    /// Object missing from `session.json`, repaired by adding it there.
+    UnlistedObject(SessionId, ObjectId),
+    /// Entry of `session.json` without `{oid}.json`, repaired by restoring the metadata from it.
+    MissingMetadata(SessionId, ObjectId),
+}
+
+impl Display for Issue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::UnknownDirectory(path) => {
+                write!(f, "{}: not a session directory", path.display())
+            }
+            Self::UnreadableSession(sid) => write!(f, "{sid}: unreadable {SESSION_FILE}"),
+            Self::UnrecoverableSession(sid) => write!(
+                f,
+                "{sid}: unreadable {SESSION_FILE} of an end-to-end encrypted session"
+            ),
+            Self::UnreadableMetadata(sid, oid) => write!(f, "{sid}/{oid}: unreadable metadata"),
+            Self::OrphanBlob(sid, oid) => write!(f, "{sid}/{oid}: blob without metadata"),
+            Self::MissingBlob(sid, oid) => write!(f, "{sid}/{oid}: metadata without blob"),
+            Self::UnlistedObject(sid, oid) => {
+                write!(f, "{sid}/{oid}: object missing from {SESSION_FILE}")
+            }
+            Self::MissingMetadata(sid, oid) => {
+                write!(f, "{sid}/{oid}: {SESSION_FILE} entry without metadata")
+            }
+        }
+    }
+}
+
+impl Issue {
+    /// Whether repairing the storage directory takes care of the issue.
+    pub fn is_repairable(&self) -> bool {
+        !matches!(
+            self,
+            Self::UnknownDirectory(_) | Self::UnrecoverableSession(_)
+        )
+    }
+}
+
+/// Checks every session directory in the storage directory of the fs backend, returning
+/// the issues found. When `repair` is set, the issues are also repaired where possible
+/// while holding the lock of each session, which a server running on the same storage
+/// directory respects, and the shared blobs nothing refers to anymore are freed.
+pub async fn fsck<D: AsRef<Path>>(
+    dir: D,
+    master_key: Option<MasterKey>,
+    repair: bool,
+) -> Result<Vec<Issue>> {
+    let dir = dir.as_ref();
+    let checker = Checker {
+        master_key,
+        repair,
+        blobs: BlobStore::new(dir.join(BLOBS_DIR), Quota::default()),
+    };
+    let mut issues = Vec::new();
+    let mut entries = fs::read_dir(dir).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        let path = entry.path();
+        if !entry.file_type().await?.is_dir() {
+            continue;
+        }
+        let name = path.file_name().and_then(OsStr::to_str).unwrap_or_default();
+        if name == UPLOADS_DIR || name == BLOBS_DIR {
+            continue;
+        }
+        match SessionId::from_str(name) {
+            Ok(sid) => issues.extend(checker.check_session(sid, &path).await?),
+            Err(_) => issues.push(Issue::UnknownDirectory(path)),
+        }
+    }
+    if repair {
+        checker.blobs.collect_all().await?;
+    }
+    Ok(issues)
+}
+
+struct Checker {
+    master_key: Option<MasterKey>,
+    repair: bool,
+    blobs: BlobStore,
+}
+
+impl Checker {
+    async fn check_session(&self, sid: SessionId, dir: &Path) -> Result<Vec<Issue>> {
+        let _lock = lock_session(dir, &session_mutex(dir)).await?;
+        let mut issues = Vec::new();
+
+        let path = dir.join(SESSION_FILE);
+        let mut sess: Session = match self.load(&path).await {
+            Ok(sess) => sess,
+            Err(e) if e.is::<DecryptionError>() => return Err(e),
+            Err(_) if fs::try_exists(dir.join(SESSION_AUTH_KEY_FILE)).await? => {
+                return Ok(vec![Issue::UnrecoverableSession(sid)]);
+            }
+            Err(_) => {
+                issues.push(Issue::UnreadableSession(sid));
+                self.rebuild_session(dir).await?
+            }
+        };
+        let mut dirty = !issues.is_empty();
+
+        let mut metadata = HashMap::new();
+        let mut unreadable = Vec::new();
+        let mut blobs = HashMap::new();
+        let mut entries = fs::read_dir(dir).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let path = entry.path();
+            let name = path.file_name().and_then(OsStr::to_str).unwrap_or_default();
+            if let Some(oid) = name.strip_suffix(".json").and_then(parse_oid) {
+                match self.load::<_, Object>(&path).await {
+                    Ok(obj) => metadata.insert(oid, obj),
+                    // Repairing would throw away what another master key could still read.
+                    Err(e) if e.is::<DecryptionError>() => return Err(e),
+                    Err(_) => {
+                        unreadable.push(oid);
+                        None
+                    }
+                };
+            } else if let Some(oid) = parse_oid(name) {
+                let modified = entry.metadata().await?.modified()?;
+                let age = SystemTime::now()
+                    .duration_since(modified)
+                    .unwrap_or_default();
+                blobs.insert(oid, age);
+            }
+        }
+
+        let listed: HashMap<_, _> = sess
+            .objects
+            .iter()
+            .map(|obj| (obj.id, obj.clone()))
+            .collect();
+        for oid in unreadable {
+            issues.push(Issue::UnreadableMetadata(sid, oid));
+            match listed.get(&oid) {
+                Some(obj) => {
+                    self.write_metadata(dir, obj).await?;
+                    metadata.insert(oid, obj.clone());
+                }
+                None => self.remove(metadata_path(dir, &oid)).await?,
+            }
+        }
+        for (oid, obj) in &listed {
+            if !metadata.contains_key(oid) {
+                issues.push(Issue::MissingMetadata(sid, *oid));
+                self.write_metadata(dir, obj).await?;
+                metadata.insert(*oid, obj.clone());
+            }
+        }
+        for (oid, obj) in &metadata {
+            if !listed.contains_key(oid) {
+                issues.push(Issue::UnlistedObject(sid, *oid));
+                sess.add_object(obj.clone());
+                dirty = true;
+            }
+        }
+        for (oid, age) in &blobs {
+            if !metadata.contains_key(oid) && *age >= ORPHAN_MIN_AGE {
+                issues.push(Issue::OrphanBlob(sid, *oid));
+                if self.repair {
+                    self.blobs.remove(&dir.join(oid.to_string()), None).await?;
+                }
+            }
+        }
+        for (oid, obj) in &metadata {
+            if obj.has_blob() && !blobs.contains_key(oid) {
+                issues.push(Issue::MissingBlob(sid, *oid));
+                self.remove(metadata_path(dir, oid)).await?;
+                sess.remove_object(oid);
+                dirty = true;
+            }
+        }
+
+        if dirty && self.repair {
+            // Objects added back out of order would otherwise break the newest-first listing.
+            sess.objects
+                .make_contiguous()
+                .sort_by_key(|obj| std::cmp::Reverse(obj.timestamp));
+            self.save(&path, &sess).await?;
+        }
+        Ok(issues)
+    }
+
+    async fn write_metadata(&self, dir: &Path, obj: &Object) -> Result<()> {
+        if self.repair {
+            self.save(metadata_path(dir, &obj.id), obj).await?;
+        }
+        Ok(())
+    }
+
+    async fn remove(&self, path: PathBuf) -> Result<()> {
+        if !self.repair {
+            return Ok(());
+        }
+        match fs::remove_file(path).await {
+            Err(e) if e.kind() != ErrorKind::NotFound => Err(Box::new(e)),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl BaseFsRepository for Checker {
+    fn master_key(&self) -> Option<&MasterKey> {
+        self.master_key.as_ref()
+    }
+}
+
+fn parse_oid(name: &str) -> Option<ObjectId> {
+    ObjectId::from_str(name).ok()
+}
+
+fn metadata_path(dir: &Path, oid: &ObjectId) -> PathBuf {
+    dir.join(format!("{oid}.json"))
+}
+
+#[cfg(test)]
+mod tests {
+    use temp_dir::TempDir;
+
+    use crate::{
+        models::object::Upload,
+        repositories::{
+            object::{ObjectFsRepository, ObjectRepository},
+            session::{SessionFsRepository, SessionRepository},
+        },
+    };
+
+    use super::*;
+
+    #[tokio::test]
+    async fn check_and_repair_storage() -> Result<()> {
+        let tmpdir = TempDir::new()?;
+        let dir = tmpdir.path();
+        let sess = Session::default();
+        let sid = sess.id;
+        SessionFsRepository::new(dir).create(&sess).await?;
+
+        let session_dir = dir.join(sid.to_string());
+        let repo = ObjectFsRepository::new(&session_dir);
+        let mut objects = Vec::new();
+        for _ in 0..4 {
+            let mut obj: Object = Upload::default().into();
+            repo.upload(&mut obj, &b"Blob"[..]).await?;
+            objects.push(obj.id);
+        }
+        let text: Object = Upload::default().into();
+        repo.put(&text).await?;
+
+        let orphan = ObjectId::generate();
+        fs::write(session_dir.join(orphan.to_string()), b"Orphan").await?;
+        std::fs::File::options()
+            .write(true)
+            .open(session_dir.join(orphan.to_string()))?
+            .set_modified(SystemTime::now() - ORPHAN_MIN_AGE)?;
+        // Looks like an upload whose metadata is about to be written.
+        let fresh = ObjectId::generate();
+        fs::write(session_dir.join(fresh.to_string()), b"Fresh").await?;
+        fs::remove_file(session_dir.join(objects[0].to_string())).await?;
+        fs::write(metadata_path(&session_dir, &objects[1]), b"{").await?;
+        fs::remove_file(metadata_path(&session_dir, &objects[2])).await?;
+        let mut listed: Session = Checker {
+            master_key: None,
+            repair: false,
+            blobs: BlobStore::new(dir.join(BLOBS_DIR), Quota::default()),
+        }
+        .load(session_dir.join(SESSION_FILE))
+        .await?;
+        listed.remove_object(&objects[3]);
+        Checker {
+            master_key: None,
+            repair: true,
+            blobs: BlobStore::new(dir.join(BLOBS_DIR), Quota::default()),
+        }
+        .save(session_dir.join(SESSION_FILE), &listed)
+        .await?;
+        fs::create_dir(dir.join("stray")).await?;
+        fs::create_dir(dir.join(UPLOADS_DIR)).await?;
+
+        let expected = vec![
+            Issue::UnreadableMetadata(sid, objects[1]),
+            Issue::MissingMetadata(sid, objects[2]),
+            Issue::UnlistedObject(sid, objects[3]),
+            Issue::OrphanBlob(sid, orphan),
+            Issue::MissingBlob(sid, objects[0]),
+        ];
+        let mut issues = fsck(dir, None, false).await?;
+        issues.retain(|issue| !matches!(issue, Issue::UnknownDirectory(_)));
+        assert_eq!(issues, expected);
+        assert_eq!(fsck(dir, None, true).await?.len(), expected.len() + 1);
+        assert_eq!(
+            fsck(dir, None, false).await?,
+            vec![Issue::UnknownDirectory(dir.join("stray"))]
+        );
+
+        let mut ids: Vec<_> = repo.list().await?.into_iter().map(|obj| obj.id).collect();
+        ids.sort_by_key(|oid| oid.as_u64());
+        let mut expected = vec![objects[1], objects[2], objects[3], text.id];
+        expected.sort_by_key(|oid| oid.as_u64());
+        assert_eq!(ids, expected);
+        assert!(!session_dir.join(orphan.to_string()).exists());
+        assert!(session_dir.join(fresh.to_string()).exists());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn continue_past_unrecoverable_sessions() -> Result<()> {
+        let tmpdir = TempDir::new()?;
+        let dir = tmpdir.path();
+        let mut sids = Vec::new();
+        for _ in 0..2 {
+            let sess = Session::default();
+            SessionFsRepository::new(dir).create(&sess).await?;
+            let session_dir = dir.join(sess.id.to_string());
+            fs::write(session_dir.join(SESSION_FILE), b"{").await?;
+            sids.push(sess.id);
+        }
+        // Marks the first session as end-to-end encrypted.
+        let encrypted = dir.join(sids[0].to_string());
+        fs::write(encrypted.join(SESSION_AUTH_KEY_FILE), b"a2V5").await?;
+
+        let mut issues = fsck(dir, None, true).await?;
+        issues.sort_by_key(|issue| issue.is_repairable());
+        assert_eq!(
+            issues,
+            vec![
+                Issue::UnrecoverableSession(sids[0]),
+                Issue::UnreadableSession(sids[1]),
+            ]
+        );
+        assert_eq!(fs::read(encrypted.join(SESSION_FILE)).await?, b"{");
+        assert_eq!(
+            fsck(dir, None, false).await?,
+            vec![Issue::UnrecoverableSession(sids[0])]
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,89 @@
+use std::{
+    collections::HashMap,
+    io::{Error as IoError, ErrorKind},
+    sync::{Arc, Mutex, MutexGuard},
+};
+
+use crate::models::{
+    object::ObjectId,
+    session::{Session, SessionId},
+    upload::{PendingUpload, UploadId},
+};
+
+use super::Result;
+
+pub(super) struct MemorySession {
+    pub(super) session: Session,
+    pub(super) blobs: HashMap<ObjectId, Arc<[u8]>>,
+}
+
+pub(super) struct MemoryUpload {
+    pub(super) upload: PendingUpload,
+    pub(super) data: Vec<u8>,
+}
+
+#[derive(Default)]
+pub(super) struct MemoryData {
+    pub(super) sessions: HashMap<SessionId, MemorySession>,
+    pub(super) uploads: HashMap<UploadId, MemoryUpload>,
+    used: u64,
+}
+
+impl MemoryData {
+    pub(super) fn session(&self, sid: &SessionId) -> Result<&MemorySession> {
+        self.sessions.get(sid).ok_or_else(not_found)
+    }
+
+    pub(super) fn session_mut(&mut self, sid: &SessionId) -> Result<&mut MemorySession> {
+        self.sessions.get_mut(sid).ok_or_else(not_found)
+    }
+}
+
+/// Process-local storage shared by the in-memory repositories, optionally capped in total blob bytes.
+#[derive(Clone, Default)]
+pub struct MemoryStore {
+    data: Arc<Mutex<MemoryData>>,
+    limit: Option<u64>,
+}
+
+impl MemoryStore {
+    pub fn new(limit: Option<u64>) -> Self {
+        Self {
+            data: Default::default(),
+            limit,
+        }
+    }
+
+    pub(super) fn lock(&self) -> MutexGuard<'_, MemoryData> {
+        self.data.lock().unwrap()
+    }
+
+    /// Accounts for blob bytes about to be stored, failing once the cap would be exceeded.
+    pub(super) fn reserve(&self, len: u64) -> Result<()> {
+        let mut data = self.lock();
+        let used = data.used + len;
+        if self.limit.is_some_and(|limit| used > limit) {
+            return Err(Box::new(IoError::from(ErrorKind::StorageFull)));
+        }
+        data.used = used;
+        Ok(())
+    }
+
+    /// Accounts blob bytes that are stored already, which the cap cannot turn away.
+    pub(super) fn hold(&self, len: u64) {
+        self.lock().used += len;
+    }
+
+    pub(super) fn release(&self, len: u64) {
+        let mut data = self.lock();
+        data.used = data.used.saturating_sub(len);
+    }
+
+    pub fn used(&self) -> u64 {
+        self.lock().used
+    }
+}
+
+pub(super) fn not_found() -> Box<dyn std::error::Error + Send + Sync> {
+    Box::new(IoError::from(ErrorKind::NotFound))
+}
@@ -1,10 +1,32 @@
+pub mod blob;
+pub mod compression;
+pub mod digest;
+pub mod encryption;
 pub mod fs;
+pub mod fsck;
+pub mod memory;
 pub mod object;
+pub mod quota;
+pub mod s3;
 pub mod session;
+pub mod sqlite;
+pub mod upload;
 
 use std::{error::Error, result::Result as StdResult};
 
-pub type Result<T> = StdResult<T, Box<dyn Error>>;
+pub type Result<T> = StdResult<T, Box<dyn Error + Send + Sync>>;
 
 const SESSION_FILE: &str = "session.json";
 const SESSION_AUTH_KEY_FILE: &str = "authkey.txt";
+const SESSION_LOCK_FILE: &str = "session.lock";
+
+/// Suffix of the files that blobs are streamed into before being moved into place.
+const STAGING_SUFFIX: &str = ".staging";
+
+/// Directory within the storage directory holding the blobs shared by deduplication.
+const BLOBS_DIR: &str = "blobs";
+
+/// Directory within the storage directory holding resumable uploads still in progress.
+const UPLOADS_DIR: &str = "uploads";
+
+pub const SQLITE_DATABASE_FILE: &str = "webdrop.db";
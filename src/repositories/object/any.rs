@@ -0,0 +1,110 @@
+use std::{ops::Range, path::Path};
+
+use tokio::io::AsyncRead;
+
+use crate::{
+    models::{
+        object::{Object, ObjectId},
+        session::SessionId,
+    },
+    repositories::{fs::BaseFsRepository, session::AnySessionRepository, Result},
+};
+
+use super::{
+    ObjectFsRepository, ObjectMemoryRepository, ObjectRepository, ObjectS3Repository,
+    ObjectSqliteRepository,
+};
+
+macro_rules! dispatch {
+    ($self:ident, $repo:ident => $expr:expr) => {
+        match $self {
+            Self::Fs($repo) => $expr,
+            Self::Sqlite($repo) => $expr,
+            Self::Memory($repo) => $expr,
+            Self::FsS3($repo) => $expr,
+            Self::SqliteS3($repo) => $expr,
+        }
+    };
+}
+
+/// Object repository matching the backend of the session repository.
+pub enum AnyObjectRepository {
+    Fs(ObjectFsRepository),
+    Sqlite(ObjectSqliteRepository),
+    Memory(ObjectMemoryRepository),
+    FsS3(ObjectS3Repository<ObjectFsRepository>),
+    SqliteS3(ObjectS3Repository<ObjectSqliteRepository>),
+}
+
+impl AnyObjectRepository {
+    pub fn new<D: AsRef<Path>>(session: &AnySessionRepository, sid: SessionId, dir: D) -> Self {
+        match session {
+            AnySessionRepository::Fs(repo) => Self::Fs(
+                ObjectFsRepository::new(dir)
+                    .with_quota(repo.quota().clone())
+                    .with_blob_store(repo.blob_store().cloned())
+                    .with_compression(repo.compression())
+                    .with_master_key(repo.master_key().cloned()),
+            ),
+            AnySessionRepository::Sqlite(repo) => Self::Sqlite(
+                ObjectSqliteRepository::new(repo.database().clone(), sid, dir)
+                    .with_quota(repo.quota().clone())
+                    .with_blob_store(repo.blob_store().cloned()),
+            ),
+            AnySessionRepository::Memory(repo) => {
+                Self::Memory(ObjectMemoryRepository::new(repo.store().clone(), sid))
+            }
+            AnySessionRepository::FsS3(repo) => Self::FsS3(ObjectS3Repository::new(
+                repo.client().clone(),
+                sid,
+                ObjectFsRepository::new(dir),
+            )),
+            AnySessionRepository::SqliteS3(repo) => Self::SqliteS3(ObjectS3Repository::new(
+                repo.client().clone(),
+                sid,
+                ObjectSqliteRepository::new(repo.inner().database().clone(), sid, dir),
+            )),
+        }
+    }
+}
+
+impl ObjectRepository for AnyObjectRepository {
+    async fn list(&self) -> Result<Vec<Object>> {
+        dispatch!(self, repo => repo.list().await)
+    }
+
+    async fn put(&self, obj: &Object) -> Result<()> {
+        dispatch!(self, repo => repo.put(obj).await)
+    }
+
+    async fn upload<R>(&self, obj: &mut Object, reader: R) -> Result<()>
+    where
+        R: AsyncRead + Unpin + Send + Sync,
+    {
+        dispatch!(self, repo => repo.upload(obj, reader).await)
+    }
+
+    async fn get(&self, oid: &ObjectId) -> Result<Object> {
+        dispatch!(self, repo => repo.get(oid).await)
+    }
+
+    async fn download(
+        &self,
+        oid: &ObjectId,
+        range: Option<Range<u64>>,
+    ) -> Result<Box<dyn AsyncRead + Unpin + Send + Sync>> {
+        dispatch!(self, repo => repo.download(oid, range).await)
+    }
+
+    async fn record_download(&self, oid: &ObjectId) -> Result<Object> {
+        dispatch!(self, repo => repo.record_download(oid).await)
+    }
+
+    async fn delete(&self, oid: &ObjectId) -> Result<()> {
+        dispatch!(self, repo => repo.delete(oid).await)
+    }
+
+    async fn auth_key(&self, oid: &ObjectId) -> Result<Option<Vec<u8>>> {
+        dispatch!(self, repo => repo.auth_key(oid).await)
+    }
+}
@@ -1,33 +1,80 @@
 use std::{
-    fs,
     io::ErrorKind,
+    ops::Range,
     path::{Path, PathBuf},
+    sync::Arc,
 };
 
 use base64::{prelude::BASE64_STANDARD, Engine};
-use tokio::io::AsyncRead;
+use tokio::{fs, io::AsyncRead, sync::Mutex};
+use tracing::{event, Level};
 
 use crate::{
     models::{
         object::{Object, ObjectId},
         session::Session,
     },
-    repositories::{fs::BaseFsRepository, Result, SESSION_AUTH_KEY_FILE, SESSION_FILE},
+    repositories::{
+        blob::BlobStore,
+        compression::{compress, decompress, is_compressible},
+        digest::DigestReader,
+        encryption::{DecryptionError, MasterKey},
+        fs::{lock_session, open_blob, session_mutex, BaseFsRepository},
+        quota::{session_usage, Quota, StagedBlob},
+        Result, SESSION_AUTH_KEY_FILE, SESSION_FILE,
+    },
 };
 
 use super::ObjectRepository;
 
 pub struct ObjectFsRepository {
     dir: PathBuf,
+    lock: Arc<Mutex<()>>,
+    quota: Quota,
+    blobs: Option<BlobStore>,
+    compression: bool,
+    master_key: Option<MasterKey>,
 }
 
 impl ObjectFsRepository {
     pub fn new<D: AsRef<Path>>(dir: D) -> Self {
         Self {
             dir: dir.as_ref().to_path_buf(),
+            lock: session_mutex(dir.as_ref()),
+            quota: Quota::default(),
+            blobs: None,
+            compression: false,
+            master_key: None,
         }
     }
 
+    pub fn with_quota(mut self, quota: Quota) -> Self {
+        self.quota = quota;
+        self
+    }
+
+    /// Shares identical blobs through the store, stored separately when unset.
+    pub fn with_blob_store(mut self, blobs: Option<BlobStore>) -> Self {
+        self.blobs = blobs;
+        self
+    }
+
+    /// Stores blobs zstd compressed unless their content type is compressed already.
+    pub fn with_compression(mut self, compression: bool) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    /// Seals blobs and metadata files with the key, stored as is when unset.
+    pub fn with_master_key(mut self, master_key: Option<MasterKey>) -> Self {
+        self.master_key = master_key;
+        self
+    }
+
+    fn require_master_key(&self) -> Result<&MasterKey> {
+        Ok(self.master_key.as_ref().ok_or(DecryptionError)?)
+    }
+
     fn session_file_path(&self) -> PathBuf {
         self.dir.join(SESSION_FILE)
     }
@@ -44,92 +91,169 @@ impl ObjectFsRepository {
         self.dir.join(oid.to_string())
     }
 
-    fn get_object(&self, oid: &ObjectId) -> Result<Object> {
-        self.load(self.object_metadata_path(oid))
+    async fn get_object(&self, oid: &ObjectId) -> Result<Object> {
+        self.load(self.object_metadata_path(oid)).await
     }
 
-    fn put_object(&self, obj: &Object) -> Result<()> {
+    /// Adds the object to the session, persisting its staged blob first if it has one.
+    async fn put_object(&self, obj: &Object, staged: Option<&mut StagedBlob<'_>>) -> Result<()> {
         let oid = &obj.id;
+        let _lock = lock_session(&self.dir, &self.lock).await?;
+        let mut sess = self.load_session(&self.dir).await?;
+        self.quota.check_objects(sess.objects.len())?;
+
+        // Blobs are only ever persisted under the lock, so the usage cannot change until the
+        // blob is in place.
+        if let Some(staged) = staged {
+            let used = session_usage(self.dir.clone()).await?;
+            self.quota.check_session_bytes(used, staged.size())?;
+            staged.persist(self.object_file_path(oid)).await?;
+        }
 
         let path = self.object_metadata_path(oid);
-        let file = fs::File::create_new(path)?;
-        self.save(file, &obj)?;
+        self.save_new(path, &obj).await?;
 
-        let mut sess = self.load_session()?;
         sess.add_object(obj.clone());
-        self.save_session(&sess)?;
+        self.save_session(&sess).await?;
 
         Ok(())
     }
 
-    fn load_session(&self) -> Result<Session> {
-        let path = self.session_file_path();
-        self.load(path)
-    }
-
-    fn save_session(&self, sess: &Session) -> Result<()> {
+    async fn save_session(&self, sess: &Session) -> Result<()> {
         let path = self.session_file_path();
-        let file = fs::File::create(path)?;
-        self.save(file, sess)
+        self.save(path, sess).await
     }
 }
 
 impl ObjectRepository for ObjectFsRepository {
     async fn list(&self) -> Result<Vec<Object>> {
-        let session = self.load_session()?;
-        Ok(session.objects.into_iter().map(Into::into).collect())
+        let session = self.load_session(&self.dir).await?;
+        Ok(session.objects.into_iter().collect())
     }
 
     async fn put(&self, obj: &Object) -> Result<()> {
-        self.put_object(obj)
+        self.put_object(obj, None).await
     }
 
-    async fn upload<R>(&self, obj: &Object, mut reader: R) -> Result<()>
+    async fn upload<R>(&self, obj: &mut Object, reader: R) -> Result<()>
     where
         R: AsyncRead + Send + Unpin,
     {
-        {
-            let path = self.object_file_path(&obj.id);
-            let mut file = tokio::fs::File::create_new(path).await?;
-            tokio::io::copy(&mut reader, &mut file).await?;
+        // Checked again under the session lock, this only avoids streaming in vain.
+        let sess = self.load_session(&self.dir).await?;
+        self.quota.check_objects(sess.objects.len())?;
+
+        let used = session_usage(self.dir.clone()).await?;
+        let path = self.object_file_path(&obj.id);
+        let compressed = self.compression && is_compressible(obj.mime.as_deref());
+        let mut reader = DigestReader::new(reader);
+        let mut staged = {
+            let mut stored: Box<dyn AsyncRead + Unpin + Send + '_> = match compressed {
+                true => Box::new(compress(&mut reader)),
+                false => Box::new(&mut reader),
+            };
+            if let Some(key) = &self.master_key {
+                stored = Box::new(key.encrypt(stored)?);
+            }
+            self.quota.stage_blob(&path, stored, used).await?
+        };
+        obj.size = Some(reader.size());
+        obj.compressed = compressed;
+        obj.encrypted = self.master_key.is_some();
+        let saved = match reader.verify(obj) {
+            Ok(()) => self.put_object(obj, Some(&mut staged)).await,
+            Err(e) => Err(e),
+        };
+        if let Err(e) = saved {
+            staged.discard().await;
+            return Err(e);
+        }
+        staged.keep();
+        let name = self.blobs.as_ref().and_then(|blobs| blobs.name(obj));
+        if let (Some(blobs), Some(name)) = (&self.blobs, name) {
+            if let Err(e) = blobs.absorb(&path, &name).await {
+                event!(
+                    Level::WARN,
+                    "Failed to deduplicate blob {}: {e}",
+                    path.display()
+                );
+            }
         }
-        self.put_object(obj).map(Into::into)
+        Ok(())
     }
 
     async fn get(&self, oid: &ObjectId) -> Result<Object> {
-        Ok(self.get_object(oid)?.into())
+        self.get_object(oid).await
     }
 
-    async fn download(&self, oid: &ObjectId) -> Result<Box<dyn AsyncRead + Unpin + Send + Sync>> {
+    async fn download(
+        &self,
+        oid: &ObjectId,
+        range: Option<Range<u64>>,
+    ) -> Result<Box<dyn AsyncRead + Unpin + Send + Sync>> {
         let path = self.object_file_path(oid);
-        let file = tokio::fs::File::open(path).await?;
-        Ok(Box::new(file))
+        let obj = self.get_object(oid).await?;
+        if !obj.compressed {
+            return match obj.encrypted {
+                true => self.require_master_key()?.open_blob(path, range).await,
+                false => open_blob(path, range).await,
+            };
+        }
+        match obj.encrypted {
+            true => {
+                let reader = self.require_master_key()?.open_blob(path, None).await?;
+                decompress(reader, range).await
+            }
+            false => decompress(fs::File::open(path).await?, range).await,
+        }
+    }
+
+    async fn record_download(&self, oid: &ObjectId) -> Result<Object> {
+        let _lock = lock_session(&self.dir, &self.lock).await?;
+        let mut obj = self.get_object(oid).await?;
+        obj.downloads += 1;
+        self.save(self.object_metadata_path(oid), &obj).await?;
+
+        let mut sess = self.load_session(&self.dir).await?;
+        sess.update_object(obj.clone());
+        self.save_session(&sess).await?;
+
+        Ok(obj)
     }
 
     async fn delete(&self, oid: &ObjectId) -> Result<()> {
+        let _lock = lock_session(&self.dir, &self.lock).await?;
         let path = self.object_file_path(oid);
-        if let Err(e) = fs::remove_file(path) {
-            if e.kind() != ErrorKind::NotFound {
-                return Err(Box::new(e));
+        if let Some(blobs) = &self.blobs {
+            let obj = self.get_object(oid).await?;
+            blobs.remove(&path, blobs.name(&obj).as_deref()).await?;
+        } else {
+            match fs::metadata(&path).await {
+                Ok(meta) => {
+                    fs::remove_file(path).await?;
+                    self.quota.release(meta.len());
+                }
+                Err(e) if e.kind() == ErrorKind::NotFound => (),
+                Err(e) => return Err(Box::new(e)),
             }
         }
-        fs::remove_file(self.object_metadata_path(oid))?;
+        fs::remove_file(self.object_metadata_path(oid)).await?;
 
-        let mut sess = self.load_session()?;
+        let mut sess = self.load_session(&self.dir).await?;
         sess.remove_object(oid);
-        self.save_session(&sess)?;
+        self.save_session(&sess).await?;
 
         Ok(())
     }
 
     async fn auth_key(&self, oid: &ObjectId) -> Result<Option<Vec<u8>>> {
-        let obj = self.get_object(oid)?;
+        let obj = self.get_object(oid).await?;
         let key = if let Some(auth_key) = obj.auth_key {
             auth_key
         } else {
             let key_path = self.session_auth_key_path();
-            if fs::exists(&key_path)? {
-                self.read_string(key_path)?
+            if fs::try_exists(&key_path).await? {
+                self.read_string(key_path).await?
             } else {
                 return Ok(None);
             }
@@ -139,14 +263,30 @@ impl ObjectRepository for ObjectFsRepository {
     }
 }
 
-impl BaseFsRepository for ObjectFsRepository {}
+impl BaseFsRepository for ObjectFsRepository {
+    fn master_key(&self) -> Option<&MasterKey> {
+        self.master_key.as_ref()
+    }
+}
 
 #[cfg(test)]
 mod tests {
+    use std::time::Duration;
+
+    use axum::body::Bytes;
+    use futures::{stream, StreamExt};
     use temp_dir::TempDir;
+    use tokio::{io::AsyncReadExt, time};
+    use tokio_util::io::StreamReader;
 
     use crate::{
-        models::object::Upload,
+        models::{
+            crypto::KDFParams,
+            object::Upload,
+            session::{CreateSession, CreateSessionCrypto, SessionId},
+        },
+        repositories::fs::sweep_staging,
+        repositories::quota::QuotaLimits,
         repositories::session::{SessionFsRepository, SessionRepository},
     };
 
@@ -164,11 +304,406 @@ mod tests {
         };
 
         let repo = ObjectFsRepository::new(dir.join(sid.to_string()));
-        let obj = Upload::default().try_into()?;
+        let obj: Object = Upload::default().into();
         repo.put(&obj).await?;
 
         let obj2 = repo.get(&obj.id).await?;
         assert_eq!(obj2, obj);
         Ok(())
     }
+
+    #[tokio::test]
+    async fn record_download_updates_session() -> Result<()> {
+        let tmpdir = TempDir::new()?;
+        let dir = tmpdir.path();
+
+        let sid = {
+            let sess = Session::default();
+            SessionFsRepository::new(dir).create(&sess).await?;
+            sess.id
+        };
+
+        let repo = ObjectFsRepository::new(dir.join(sid.to_string()));
+        let obj: Object = Upload::default().into();
+        repo.put(&obj).await?;
+
+        let obj = repo.record_download(&obj.id).await?;
+        assert_eq!(obj.downloads, 1);
+        assert_eq!(repo.get(&obj.id).await?, obj);
+        assert_eq!(repo.list().await?, vec![obj]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn upload_respects_quotas() -> Result<()> {
+        let tmpdir = TempDir::new()?;
+        let dir = tmpdir.path();
+        let quota = Quota::new(QuotaLimits {
+            session_bytes: Some(16),
+            session_objects: Some(2),
+            storage_bytes: Some(24),
+        });
+        let sessions = SessionFsRepository::new(dir).with_quota(quota.clone());
+
+        let mut repos = Vec::new();
+        for _ in 0..2 {
+            let sess = Session::default();
+            sessions.create(&sess).await?;
+            let repo = ObjectFsRepository::new(dir.join(sess.id.to_string()));
+            repos.push((sess.id, repo.with_quota(quota.clone())));
+        }
+        let (sid, repo) = &repos[0];
+
+        let mut first: Object = Upload::default().into();
+        repo.upload(&mut first, &b"Hello quota"[..]).await?;
+        let mut too_large: Object = Upload::default().into();
+        let err = repo
+            .upload(&mut too_large, &b"Exceeds session"[..])
+            .await
+            .unwrap_err();
+        let err = err.downcast_ref::<std::io::Error>().unwrap();
+        assert_eq!(err.kind(), ErrorKind::QuotaExceeded);
+        assert!(!dir
+            .join(sid.to_string())
+            .join(too_large.id.to_string())
+            .exists());
+        assert_eq!(quota.used(), 11);
+
+        repo.put(&Upload::default().into()).await?;
+        let err = repo.put(&Upload::default().into()).await.unwrap_err();
+        let err = err.downcast_ref::<std::io::Error>().unwrap();
+        assert_eq!(err.kind(), ErrorKind::QuotaExceeded);
+
+        let (_, other) = &repos[1];
+        let mut full: Object = Upload::default().into();
+        let err = other
+            .upload(&mut full, &b"Fills the disk"[..])
+            .await
+            .unwrap_err();
+        let err = err.downcast_ref::<std::io::Error>().unwrap();
+        assert_eq!(err.kind(), ErrorKind::StorageFull);
+        assert_eq!(quota.used(), 11);
+
+        sessions.delete(sid).await?;
+        assert_eq!(quota.used(), 0);
+        other.upload(&mut full, &b"Fills the disk"[..]).await?;
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn concurrent_uploads_respect_session_quota() -> Result<()> {
+        let tmpdir = TempDir::new()?;
+        let dir = tmpdir.path();
+        let quota = Quota::new(QuotaLimits {
+            session_bytes: Some(16),
+            ..Default::default()
+        });
+
+        let sid = {
+            let sess = Session::default();
+            SessionFsRepository::new(dir).create(&sess).await?;
+            sess.id
+        };
+        let session_dir = dir.join(sid.to_string());
+        let repo = Arc::new(ObjectFsRepository::new(&session_dir).with_quota(quota.clone()));
+        let mut handles = Vec::new();
+        for _ in 0..8 {
+            let repo = Arc::clone(&repo);
+            handles.push(tokio::spawn(async move {
+                let mut obj: Object = Upload::default().into();
+                repo.upload(&mut obj, &b"Ten bytes!"[..]).await.is_ok()
+            }));
+        }
+
+        let mut uploaded = 0;
+        for handle in handles {
+            uploaded += usize::from(handle.await?);
+        }
+        assert_eq!(uploaded, 1);
+        assert_eq!(repo.list().await?.len(), 1);
+        assert_eq!(session_usage(session_dir).await?, 10);
+        assert_eq!(quota.used(), 10);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn cancelled_upload_leaves_no_blob() -> Result<()> {
+        let tmpdir = TempDir::new()?;
+        let dir = tmpdir.path();
+        let quota = Quota::new(QuotaLimits::default());
+
+        let sid = {
+            let sess = Session::default();
+            SessionFsRepository::new(dir).create(&sess).await?;
+            sess.id
+        };
+        let session_dir = dir.join(sid.to_string());
+        let repo = ObjectFsRepository::new(&session_dir).with_quota(quota.clone());
+        let blob_count = || -> Result<usize> {
+            let names = std::fs::read_dir(&session_dir)?.map(|e| e.map(|e| e.file_name()));
+            let names = names.collect::<std::io::Result<Vec<_>>>()?;
+            Ok(names.iter().filter(|name| name != &SESSION_FILE).count())
+        };
+
+        // Stalls after the first chunk, as a client going away mid-upload would.
+        let stalled = StreamReader::new(
+            stream::iter([Ok::<_, std::io::Error>(Bytes::from_static(b"Half of it"))])
+                .chain(stream::pending()),
+        );
+        let mut obj: Object = Upload::default().into();
+        let upload = repo.upload(&mut obj, stalled);
+        assert!(time::timeout(Duration::from_millis(50), upload)
+            .await
+            .is_err());
+        assert_eq!(quota.used(), 0);
+        // The staging file is removed on the blocking pool once the upload is dropped.
+        for _ in 0..50 {
+            if blob_count()? == 0 {
+                break;
+            }
+            time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(blob_count()?, 0);
+        assert_eq!(quota.used(), 0);
+
+        let stale = session_dir.join(format!(".{}.staging", ObjectId::generate()));
+        fs::write(&stale, b"Left behind").await?;
+        assert_eq!(sweep_staging(dir, Duration::from_secs(60))?, 0);
+        assert_eq!(sweep_staging(dir, Duration::ZERO)?, 1);
+        assert!(!stale.exists());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn compressed_blob_round_trip() -> Result<()> {
+        let tmpdir = TempDir::new()?;
+        let dir = tmpdir.path();
+
+        let sid = {
+            let sess = Session::default();
+            SessionFsRepository::new(dir).create(&sess).await?;
+            sess.id
+        };
+
+        let session_dir = dir.join(sid.to_string());
+        let repo = ObjectFsRepository::new(&session_dir).with_compression(true);
+        let content = "2025-01-01 INFO request served\n".repeat(64);
+        let mut text: Object = Upload::default().into();
+        text.mime = Some("text/plain".into());
+        repo.upload(&mut text, content.as_bytes()).await?;
+        let mut image: Object = Upload::default().into();
+        image.mime = Some("image/png".into());
+        repo.upload(&mut image, &b"Not really a PNG"[..]).await?;
+
+        assert!(text.compressed);
+        assert!(!image.compressed);
+        assert_eq!(text.size, Some(content.len() as u64));
+        let stored = fs::metadata(session_dir.join(text.id.to_string())).await?;
+        assert!(stored.len() < content.len() as u64 / 5);
+
+        let mut downloaded = String::new();
+        repo.download(&text.id, None)
+            .await?
+            .read_to_string(&mut downloaded)
+            .await?;
+        assert_eq!(downloaded, content);
+
+        let mut partial = String::new();
+        repo.download(&text.id, Some(40..100))
+            .await?
+            .read_to_string(&mut partial)
+            .await?;
+        assert_eq!(partial, content[40..100]);
+
+        // Blobs stored before compression was turned on stay readable.
+        let plain = ObjectFsRepository::new(&session_dir);
+        let mut old: Object = Upload::default().into();
+        plain.upload(&mut old, &b"Stored as is"[..]).await?;
+        let mut buf = Vec::new();
+        repo.download(&old.id, None)
+            .await?
+            .read_to_end(&mut buf)
+            .await?;
+        assert_eq!(buf, b"Stored as is");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn sealed_blob_round_trip() -> Result<()> {
+        let tmpdir = TempDir::new()?;
+        let dir = tmpdir.path();
+        let key = MasterKey::new(&[42; 32]);
+
+        let sid = {
+            let sess = Session::default();
+            SessionFsRepository::new(dir)
+                .with_master_key(Some(key.clone()))
+                .create(&sess)
+                .await?;
+            sess.id
+        };
+
+        let session_dir = dir.join(sid.to_string());
+        let plain = ObjectFsRepository::new(&session_dir);
+        let repo = ObjectFsRepository::new(&session_dir).with_master_key(Some(key));
+        let content = "Confidential quarterly figures\n".repeat(5000);
+        let mut sealed: Object = Upload::default().into();
+        sealed.filename = Some("figures.txt".into());
+        repo.upload(&mut sealed, content.as_bytes()).await?;
+        let mut packed: Object = Upload::default().into();
+        let repo = repo.with_compression(true);
+        repo.upload(&mut packed, content.as_bytes()).await?;
+
+        assert!(sealed.encrypted && packed.encrypted && packed.compressed);
+        for name in [
+            SESSION_FILE.to_string(),
+            format!("{}.json", sealed.id),
+            sealed.id.to_string(),
+            packed.id.to_string(),
+        ] {
+            let buf = fs::read(session_dir.join(name)).await?;
+            assert!(MasterKey::is_sealed(&buf));
+            assert!(!String::from_utf8_lossy(&buf).contains("figures"));
+        }
+        assert!(plain.get(&sealed.id).await.is_err());
+        assert_eq!(repo.get(&sealed.id).await?, sealed);
+
+        for oid in [sealed.id, packed.id] {
+            let mut downloaded = String::new();
+            repo.download(&oid, None)
+                .await?
+                .read_to_string(&mut downloaded)
+                .await?;
+            assert_eq!(downloaded, content);
+
+            // Spans the boundary between the first two chunks.
+            let mut partial = String::new();
+            repo.download(&oid, Some(65000..70000))
+                .await?
+                .read_to_string(&mut partial)
+                .await?;
+            assert_eq!(partial, content[65000..70000]);
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn rebuild_corrupted_session() -> Result<()> {
+        let tmpdir = TempDir::new()?;
+        let dir = tmpdir.path();
+
+        let sid = {
+            let sess = Session::default();
+            SessionFsRepository::new(dir).create(&sess).await?;
+            sess.id
+        };
+
+        let session_dir = dir.join(sid.to_string());
+        let repo = ObjectFsRepository::new(&session_dir);
+        let first: Object = Upload::default().into();
+        let second: Object = Upload::default().into();
+        repo.put(&first).await?;
+        repo.put(&second).await?;
+
+        let path = session_dir.join(SESSION_FILE);
+        let json = fs::read(&path).await?;
+        fs::write(&path, &json[..json.len() / 2]).await?;
+
+        assert_eq!(repo.list().await?, vec![second, first]);
+        let sess: Session = repo.load(&path).await?;
+        assert_eq!(sess.id, sid);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn keep_corrupted_encrypted_session() -> Result<()> {
+        let tmpdir = TempDir::new()?;
+        let dir = tmpdir.path();
+
+        let sess = Session::new(
+            SessionId::generate(),
+            CreateSession {
+                crypto: Some(CreateSessionCrypto {
+                    auth_key: "a2V5".into(),
+                    kdf_params: KDFParams {
+                        name: "PBKDF2".into(),
+                        hash: "SHA-256".into(),
+                        iterations: 100_000,
+                        salt: "c2FsdA==".into(),
+                    },
+                }),
+                ttl: None,
+            },
+            None,
+        );
+        SessionFsRepository::new(dir).create(&sess).await?;
+
+        let session_dir = dir.join(sess.id.to_string());
+        let path = session_dir.join(SESSION_FILE);
+        let json = fs::read(&path).await?;
+        fs::write(&path, &json[..json.len() / 2]).await?;
+
+        let repo = ObjectFsRepository::new(&session_dir);
+        assert!(repo.list().await.is_err());
+        assert_eq!(fs::read(&path).await?, json[..json.len() / 2]);
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn concurrent_uploads_are_not_lost() -> Result<()> {
+        let tmpdir = TempDir::new()?;
+        let dir = tmpdir.path();
+
+        let sid = {
+            let sess = Session::default();
+            SessionFsRepository::new(dir).create(&sess).await?;
+            sess.id
+        };
+
+        let session_dir = dir.join(sid.to_string());
+        let repo = Arc::new(ObjectFsRepository::new(&session_dir));
+        let mut handles = Vec::new();
+        for idx in 0..32 {
+            // Half of the writers use their own instance, which shares the in-process mutex
+            // of the session all the same.
+            let repo = if idx % 2 == 0 {
+                Arc::clone(&repo)
+            } else {
+                Arc::new(ObjectFsRepository::new(&session_dir))
+            };
+            handles.push(tokio::spawn(async move {
+                let mut obj: Object = Upload::default().into();
+                let content = format!("Upload {idx}");
+                repo.upload(&mut obj, content.as_bytes()).await.unwrap();
+                obj.id
+            }));
+        }
+
+        let mut expected = Vec::new();
+        for handle in handles {
+            expected.push(handle.await?);
+        }
+        let mut actual: Vec<_> = repo.list().await?.into_iter().map(|o| o.id).collect();
+        expected.sort_by_key(|oid| oid.as_u64());
+        actual.sort_by_key(|oid| oid.as_u64());
+        assert_eq!(actual, expected);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn session_lock_excludes_other_processes() -> Result<()> {
+        let tmpdir = TempDir::new()?;
+        let dir = tmpdir.path();
+
+        // Separate mutexes leave only the file lock, as in separate processes.
+        let held = lock_session(dir, &Arc::default()).await?;
+        let other = Arc::default();
+        let waiting = time::timeout(Duration::from_millis(100), lock_session(dir, &other));
+        assert!(waiting.await.is_err());
+
+        drop(held);
+        let acquire = time::timeout(Duration::from_secs(1), lock_session(dir, &other));
+        acquire.await??;
+        Ok(())
+    }
 }
@@ -0,0 +1,215 @@
+use std::{io::Cursor, ops::Range, sync::Arc};
+
+use base64::{prelude::BASE64_STANDARD, Engine};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+use crate::{
+    models::{
+        object::{Object, ObjectId},
+        session::SessionId,
+    },
+    repositories::{
+        digest::DigestReader,
+        memory::{not_found, MemoryStore},
+        Result,
+    },
+};
+
+use super::ObjectRepository;
+
+const CHUNK_SIZE: usize = 64 * 1024;
+
+pub struct ObjectMemoryRepository {
+    store: MemoryStore,
+    sid: SessionId,
+}
+
+impl ObjectMemoryRepository {
+    pub fn new(store: MemoryStore, sid: SessionId) -> Self {
+        Self { store, sid }
+    }
+
+    fn put_object(&self, obj: &Object, blob: Option<Arc<[u8]>>) -> Result<()> {
+        let mut data = self.store.lock();
+        let entry = data.session_mut(&self.sid)?;
+        if let Some(blob) = blob {
+            entry.blobs.insert(obj.id, blob);
+        }
+        entry.session.add_object(obj.clone());
+        Ok(())
+    }
+
+    /// Buffers the whole stream, reserving capacity chunk by chunk so the cap is never overshot.
+    async fn read_blob<R>(&self, mut reader: R) -> Result<Vec<u8>>
+    where
+        R: AsyncRead + Unpin,
+    {
+        let mut buf = Vec::new();
+        let mut chunk = vec![0u8; CHUNK_SIZE];
+        loop {
+            let n = match reader.read(&mut chunk).await {
+                Ok(0) => return Ok(buf),
+                Ok(n) => n,
+                Err(e) => {
+                    self.store.release(buf.len() as u64);
+                    return Err(Box::new(e));
+                }
+            };
+            if let Err(e) = self.store.reserve(n as u64) {
+                self.store.release(buf.len() as u64);
+                return Err(e);
+            }
+            buf.extend_from_slice(&chunk[..n]);
+        }
+    }
+}
+
+impl ObjectRepository for ObjectMemoryRepository {
+    async fn list(&self) -> Result<Vec<Object>> {
+        let data = self.store.lock();
+        Ok(data
+            .session(&self.sid)?
+            .session
+            .objects
+            .iter()
+            .cloned()
+            .collect())
+    }
+
+    async fn put(&self, obj: &Object) -> Result<()> {
+        self.put_object(obj, None)
+    }
+
+    async fn upload<R>(&self, obj: &mut Object, reader: R) -> Result<()>
+    where
+        R: AsyncRead + Unpin + Send + Sync,
+    {
+        self.store.lock().session(&self.sid)?;
+        let mut reader = DigestReader::new(reader);
+        let blob: Arc<[u8]> = self.read_blob(&mut reader).await?.into();
+        let len = blob.len() as u64;
+        if let Err(e) = reader.verify(obj) {
+            self.store.release(len);
+            return Err(e);
+        }
+        obj.size = Some(len);
+        self.put_object(obj, Some(blob)).inspect_err(|_| {
+            self.store.release(len);
+        })
+    }
+
+    async fn get(&self, oid: &ObjectId) -> Result<Object> {
+        let data = self.store.lock();
+        let entry = data.session(&self.sid)?;
+        let obj = entry.session.objects.iter().find(|obj| &obj.id == oid);
+        obj.cloned().ok_or_else(not_found)
+    }
+
+    async fn download(
+        &self,
+        oid: &ObjectId,
+        range: Option<Range<u64>>,
+    ) -> Result<Box<dyn AsyncRead + Unpin + Send + Sync>> {
+        let data = self.store.lock();
+        let blob = data
+            .session(&self.sid)?
+            .blobs
+            .get(oid)
+            .ok_or_else(not_found)?;
+        let mut cursor = Cursor::new(Arc::clone(blob));
+        match range {
+            Some(range) => {
+                cursor.set_position(range.start);
+                Ok(Box::new(cursor.take(range.end - range.start)))
+            }
+            None => Ok(Box::new(cursor)),
+        }
+    }
+
+    async fn record_download(&self, oid: &ObjectId) -> Result<Object> {
+        let mut data = self.store.lock();
+        let entry = data.session_mut(&self.sid)?;
+        let obj = entry.session.objects.iter_mut().find(|obj| &obj.id == oid);
+        let obj = obj.ok_or_else(not_found)?;
+        obj.downloads += 1;
+        Ok(obj.clone())
+    }
+
+    async fn delete(&self, oid: &ObjectId) -> Result<()> {
+        let len = {
+            let mut data = self.store.lock();
+            let entry = data.session_mut(&self.sid)?;
+            if !entry.session.objects.iter().any(|obj| &obj.id == oid) {
+                return Err(not_found());
+            }
+            entry.session.remove_object(oid);
+            entry.blobs.remove(oid).map_or(0, |blob| blob.len() as u64)
+        };
+        self.store.release(len);
+        Ok(())
+    }
+
+    async fn auth_key(&self, oid: &ObjectId) -> Result<Option<Vec<u8>>> {
+        let key = {
+            let data = self.store.lock();
+            let sess = &data.session(&self.sid)?.session;
+            let obj = sess.objects.iter().find(|obj| &obj.id == oid);
+            match obj.ok_or_else(not_found)?.auth_key.as_ref() {
+                Some(auth_key) => auth_key.clone(),
+                None => match sess.crypto.as_ref() {
+                    Some(crypto) => crypto.auth_key.clone(),
+                    None => return Ok(None),
+                },
+            }
+        };
+        Ok(Some(BASE64_STANDARD.decode(key)?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::ErrorKind;
+
+    use crate::{
+        models::{object::Upload, session::Session},
+        repositories::session::{SessionMemoryRepository, SessionRepository},
+    };
+
+    use super::*;
+
+    #[tokio::test]
+    async fn upload_respects_byte_limit() -> Result<()> {
+        let store = MemoryStore::new(Some(16));
+        let sess = Session::default();
+        SessionMemoryRepository::new(store.clone())
+            .create(&sess)
+            .await?;
+
+        let repo = ObjectMemoryRepository::new(store.clone(), sess.id);
+        let mut obj: Object = Upload::default().into();
+        repo.upload(&mut obj, &b"Hello memory"[..]).await?;
+        assert_eq!(store.used(), 12);
+        assert_eq!(obj.size, Some(12));
+
+        let mut buf = String::new();
+        repo.download(&obj.id, None)
+            .await?
+            .read_to_string(&mut buf)
+            .await?;
+        assert_eq!(buf, "Hello memory");
+
+        let mut too_large: Object = Upload::default().into();
+        let err = repo
+            .upload(&mut too_large, &b"Exceeds the limit"[..])
+            .await
+            .unwrap_err();
+        let err = err.downcast_ref::<std::io::Error>().unwrap();
+        assert_eq!(err.kind(), ErrorKind::StorageFull);
+        assert_eq!(store.used(), 12);
+        assert_eq!(repo.list().await?, vec![obj.clone()]);
+
+        repo.delete(&obj.id).await?;
+        assert_eq!(store.used(), 0);
+        Ok(())
+    }
+}
@@ -1,6 +1,10 @@
+mod any;
 mod fs;
+mod memory;
+mod s3;
+mod sqlite;
 
-use std::future::Future;
+use std::{future::Future, ops::Range};
 
 use tokio::io::AsyncRead;
 
@@ -8,24 +12,37 @@ use crate::models::object::{Object, ObjectId};
 
 use super::Result;
 
+pub use any::AnyObjectRepository;
 pub use fs::ObjectFsRepository;
+pub use memory::ObjectMemoryRepository;
+pub(super) use s3::object_key;
+pub use s3::ObjectS3Repository;
+pub use sqlite::ObjectSqliteRepository;
 
 pub trait ObjectRepository: Unpin + Send + Sync {
     fn list(&self) -> impl Future<Output = Result<Vec<Object>>>;
 
     fn put(&self, obj: &Object) -> impl Future<Output = Result<()>>;
 
-    fn upload<R>(&self, obj: &Object, reader: R) -> impl Future<Output = Result<()>>
+    /// Stores the blob read from `reader` along with the object, recording its size and
+    /// digest. Fails without storing anything when `obj.sha256` is set to another digest.
+    fn upload<R>(&self, obj: &mut Object, reader: R) -> impl Future<Output = Result<()>>
     where
         R: AsyncRead + Unpin + Send + Sync;
 
     fn get(&self, oid: &ObjectId) -> impl Future<Output = Result<Object>>;
 
+    /// Streams the blob of an object, or only the given byte range of it. The range must
+    /// lie within the blob.
     fn download(
         &self,
         oid: &ObjectId,
+        range: Option<Range<u64>>,
     ) -> impl Future<Output = Result<Box<dyn AsyncRead + Unpin + Send + Sync>>>;
 
+    /// Atomically increments the download counter of an object, returning the updated object.
+    fn record_download(&self, oid: &ObjectId) -> impl Future<Output = Result<Object>>;
+
     fn delete(&self, oid: &ObjectId) -> impl Future<Output = Result<()>>;
 
     fn auth_key(&self, oid: &ObjectId) -> impl Future<Output = Result<Option<Vec<u8>>>>;
@@ -0,0 +1,159 @@
+use std::ops::Range;
+
+use tokio::io::AsyncRead;
+
+use crate::{
+    models::{
+        object::{Object, ObjectId},
+        session::SessionId,
+    },
+    repositories::{digest::DigestReader, s3::S3Client, Result},
+};
+
+use super::ObjectRepository;
+
+/// Stores blobs in an S3 bucket while delegating metadata to the wrapped repository.
+pub struct ObjectS3Repository<O> {
+    client: S3Client,
+    sid: SessionId,
+    inner: O,
+}
+
+impl<O> ObjectS3Repository<O> {
+    pub fn new(client: S3Client, sid: SessionId, inner: O) -> Self {
+        Self { client, sid, inner }
+    }
+
+    fn object_key(&self, oid: &ObjectId) -> String {
+        object_key(&self.sid, oid)
+    }
+}
+
+pub(in crate::repositories) fn object_key(sid: &SessionId, oid: &ObjectId) -> String {
+    format!("{sid}/{oid}")
+}
+
+impl<O: ObjectRepository> ObjectRepository for ObjectS3Repository<O> {
+    async fn list(&self) -> Result<Vec<Object>> {
+        self.inner.list().await
+    }
+
+    async fn put(&self, obj: &Object) -> Result<()> {
+        self.inner.put(obj).await
+    }
+
+    async fn upload<R>(&self, obj: &mut Object, reader: R) -> Result<()>
+    where
+        R: AsyncRead + Unpin + Send + Sync,
+    {
+        let key = self.object_key(&obj.id);
+        let mut reader = DigestReader::new(reader);
+        obj.size = Some(self.client.upload(&key, &mut reader).await?);
+        let result = match reader.verify(obj) {
+            Ok(_) => self.inner.put(obj).await,
+            Err(e) => Err(e),
+        };
+        if let Err(e) = result {
+            self.client.delete_object(&key).await.ok();
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    async fn get(&self, oid: &ObjectId) -> Result<Object> {
+        self.inner.get(oid).await
+    }
+
+    async fn download(
+        &self,
+        oid: &ObjectId,
+        range: Option<Range<u64>>,
+    ) -> Result<Box<dyn AsyncRead + Unpin + Send + Sync>> {
+        self.client.get_object(&self.object_key(oid), range).await
+    }
+
+    async fn record_download(&self, oid: &ObjectId) -> Result<Object> {
+        self.inner.record_download(oid).await
+    }
+
+    async fn delete(&self, oid: &ObjectId) -> Result<()> {
+        self.client.delete_object(&self.object_key(oid)).await?;
+        self.inner.delete(oid).await
+    }
+
+    async fn auth_key(&self, oid: &ObjectId) -> Result<Option<Vec<u8>>> {
+        self.inner.auth_key(oid).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use temp_dir::TempDir;
+    use tokio::io::AsyncReadExt;
+
+    use crate::{
+        models::{object::Upload, session::Session},
+        repositories::{
+            object::ObjectFsRepository,
+            s3::mock::MockS3,
+            session::{SessionFsRepository, SessionRepository},
+        },
+    };
+
+    use super::*;
+
+    #[tokio::test]
+    async fn upload_download_and_delete_object() -> Result<()> {
+        let s3 = MockS3::start().await;
+        let tmpdir = TempDir::new()?;
+        let dir = tmpdir.path();
+
+        let sid = {
+            let sess = Session::default();
+            SessionFsRepository::new(dir).create(&sess).await?;
+            sess.id
+        };
+
+        let client = s3.client().with_part_size(16);
+        let inner = ObjectFsRepository::new(dir.join(sid.to_string()));
+        let repo = ObjectS3Repository::new(client, sid, inner);
+
+        let mut small: Object = Upload::default().into();
+        let mut large: Object = Upload::default().into();
+        let content = "multipart upload split into several parts".repeat(4);
+        repo.upload(&mut small, &b"single part"[..]).await?;
+        repo.upload(&mut large, content.as_bytes()).await?;
+
+        assert_eq!(large.size, Some(content.len() as u64));
+        assert_eq!(repo.list().await?, vec![large.clone(), small.clone()]);
+        assert_eq!(s3.pending_uploads(), 0);
+        assert_eq!(
+            s3.object(&object_key(&sid, &large.id)),
+            Some(content.clone().into_bytes())
+        );
+        assert!(!dir
+            .join(sid.to_string())
+            .join(large.id.to_string())
+            .exists());
+
+        let mut downloaded = String::new();
+        repo.download(&large.id, None)
+            .await?
+            .read_to_string(&mut downloaded)
+            .await?;
+        assert_eq!(downloaded, content);
+
+        let mut partial = String::new();
+        repo.download(&large.id, Some(9..24))
+            .await?
+            .read_to_string(&mut partial)
+            .await?;
+        assert_eq!(partial, content[9..24]);
+
+        repo.delete(&small.id).await?;
+        assert_eq!(s3.object(&object_key(&sid, &small.id)), None);
+        assert!(repo.download(&small.id, None).await.is_err());
+        assert_eq!(repo.list().await?, vec![large]);
+        Ok(())
+    }
+}
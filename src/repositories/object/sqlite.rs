@@ -0,0 +1,334 @@
+use std::{
+    io::ErrorKind,
+    ops::Range,
+    path::{Path, PathBuf},
+};
+
+use base64::{prelude::BASE64_STANDARD, Engine};
+use rusqlite::{params, OptionalExtension};
+use tokio::{io::AsyncRead, sync::Mutex};
+use tracing::{event, Level};
+
+use crate::{
+    models::{
+        object::{Object, ObjectId},
+        session::{Session, SessionId},
+    },
+    repositories::{
+        blob::BlobStore,
+        digest::DigestReader,
+        fs::open_blob,
+        quota::{session_usage, Quota, StagedBlob},
+        sqlite::{from_json_error, not_found, sql_id, SqliteDatabase},
+        Result,
+    },
+};
+
+use super::ObjectRepository;
+
+/// Keeps object metadata in the database while blobs stay in the session directory.
+pub struct ObjectSqliteRepository {
+    db: SqliteDatabase,
+    sid: SessionId,
+    dir: PathBuf,
+    lock: Mutex<()>,
+    quota: Quota,
+    blobs: Option<BlobStore>,
+}
+
+impl ObjectSqliteRepository {
+    pub fn new<D: AsRef<Path>>(db: SqliteDatabase, sid: SessionId, dir: D) -> Self {
+        Self {
+            db,
+            sid,
+            dir: dir.as_ref().to_path_buf(),
+            lock: Mutex::default(),
+            quota: Quota::default(),
+            blobs: None,
+        }
+    }
+
+    pub fn with_quota(mut self, quota: Quota) -> Self {
+        self.quota = quota;
+        self
+    }
+
+    /// Shares identical blobs through the store, stored separately when unset.
+    pub fn with_blob_store(mut self, blobs: Option<BlobStore>) -> Self {
+        self.blobs = blobs;
+        self
+    }
+
+    fn object_file_path(&self, oid: &ObjectId) -> PathBuf {
+        self.dir.join(oid.to_string())
+    }
+
+    async fn get_object(&self, oid: &ObjectId) -> Result<Object> {
+        let sid = sql_id(&self.sid);
+        let oid = sql_id(oid);
+        self.db
+            .call(move |conn| {
+                let data: String = conn.query_row(
+                    "SELECT data FROM objects WHERE id = ?1 AND session_id = ?2",
+                    [oid, sid],
+                    |row| row.get(0),
+                )?;
+                serde_json::from_str(&data).map_err(from_json_error)
+            })
+            .await
+    }
+
+    async fn count_objects(&self) -> Result<usize> {
+        let sid = sql_id(&self.sid);
+        self.db
+            .call(move |conn| {
+                conn.query_row(
+                    "SELECT COUNT(*) FROM objects WHERE session_id = ?1",
+                    [sid],
+                    |row| row.get::<_, i64>(0),
+                )
+            })
+            .await
+            .map(|count| count as usize)
+    }
+
+    /// Adds the object to the session, persisting its staged blob first if it has one.
+    async fn put_object(&self, obj: &Object, staged: Option<&mut StagedBlob<'_>>) -> Result<()> {
+        let _lock = self.lock.lock().await;
+        self.quota.check_objects(self.count_objects().await?)?;
+        // Blobs are only ever persisted under the lock, so the usage cannot change until the
+        // blob is in place.
+        if let Some(staged) = staged {
+            let used = session_usage(self.dir.clone()).await?;
+            self.quota.check_session_bytes(used, staged.size())?;
+            staged.persist(self.object_file_path(&obj.id)).await?;
+        }
+        let sid = sql_id(&self.sid);
+        let oid = sql_id(&obj.id);
+        let data = serde_json::to_string(obj)?;
+        self.db
+            .call(move |conn| {
+                let inserted = conn.execute(
+                    "INSERT INTO objects (id, session_id, data)
+                    SELECT ?1, id, ?3 FROM sessions WHERE id = ?2",
+                    params![oid, sid, data],
+                )?;
+                match inserted {
+                    0 => Err(not_found()),
+                    _ => Ok(()),
+                }
+            })
+            .await
+    }
+}
+
+impl ObjectRepository for ObjectSqliteRepository {
+    async fn list(&self) -> Result<Vec<Object>> {
+        let sid = sql_id(&self.sid);
+        self.db
+            .call(move |conn| {
+                let tx = conn.transaction()?;
+                let exists: bool = tx.query_row(
+                    "SELECT EXISTS (SELECT 1 FROM sessions WHERE id = ?1)",
+                    [sid],
+                    |row| row.get(0),
+                )?;
+                if !exists {
+                    return Err(not_found());
+                }
+                let mut stmt =
+                    tx.prepare("SELECT data FROM objects WHERE session_id = ?1 ORDER BY seq DESC")?;
+                let rows = stmt.query_map([sid], |row| row.get::<_, String>(0))?;
+                let mut objects = Vec::new();
+                for data in rows {
+                    objects.push(serde_json::from_str(&data?).map_err(from_json_error)?);
+                }
+                Ok(objects)
+            })
+            .await
+    }
+
+    async fn put(&self, obj: &Object) -> Result<()> {
+        self.put_object(obj, None).await
+    }
+
+    async fn upload<R>(&self, obj: &mut Object, reader: R) -> Result<()>
+    where
+        R: AsyncRead + Send + Unpin,
+    {
+        self.quota.check_objects(self.count_objects().await?)?;
+        tokio::fs::create_dir_all(&self.dir).await?;
+        let used = session_usage(self.dir.clone()).await?;
+        let path = self.object_file_path(&obj.id);
+        let mut reader = DigestReader::new(reader);
+        let mut staged = self.quota.stage_blob(&path, &mut reader, used).await?;
+        obj.size = Some(staged.size());
+        let saved = match reader.verify(obj) {
+            Ok(()) => self.put_object(obj, Some(&mut staged)).await,
+            Err(e) => Err(e),
+        };
+        if let Err(e) = saved {
+            staged.discard().await;
+            return Err(e);
+        }
+        staged.keep();
+        let name = self.blobs.as_ref().and_then(|blobs| blobs.name(obj));
+        if let (Some(blobs), Some(name)) = (&self.blobs, name) {
+            if let Err(e) = blobs.absorb(&path, &name).await {
+                event!(
+                    Level::WARN,
+                    "Failed to deduplicate blob {}: {e}",
+                    path.display()
+                );
+            }
+        }
+        Ok(())
+    }
+
+    async fn get(&self, oid: &ObjectId) -> Result<Object> {
+        self.get_object(oid).await
+    }
+
+    async fn download(
+        &self,
+        oid: &ObjectId,
+        range: Option<Range<u64>>,
+    ) -> Result<Box<dyn AsyncRead + Unpin + Send + Sync>> {
+        let path = self.object_file_path(oid);
+        open_blob(path, range).await
+    }
+
+    async fn record_download(&self, oid: &ObjectId) -> Result<Object> {
+        let sid = sql_id(&self.sid);
+        let oid = sql_id(oid);
+        self.db
+            .call(move |conn| {
+                let tx = conn.transaction()?;
+                let data: String = tx.query_row(
+                    "SELECT data FROM objects WHERE id = ?1 AND session_id = ?2",
+                    [oid, sid],
+                    |row| row.get(0),
+                )?;
+                let mut obj: Object = serde_json::from_str(&data).map_err(from_json_error)?;
+                obj.downloads += 1;
+                let data = serde_json::to_string(&obj)
+                    .map_err(|e| rusqlite::Error::ToSqlConversionFailure(Box::new(e)))?;
+                tx.execute(
+                    "UPDATE objects SET data = ?1 WHERE id = ?2",
+                    params![data, oid],
+                )?;
+                tx.commit()?;
+                Ok(obj)
+            })
+            .await
+    }
+
+    async fn delete(&self, oid: &ObjectId) -> Result<()> {
+        // Shared blobs are named after the object, which is read before its row is gone.
+        let name = match &self.blobs {
+            Some(blobs) => blobs.name(&self.get_object(oid).await?),
+            None => None,
+        };
+
+        // The row goes first, so that it never points at a missing blob.
+        let sid = sql_id(&self.sid);
+        let id = sql_id(oid);
+        self.db
+            .call(move |conn| {
+                let deleted = conn.execute(
+                    "DELETE FROM objects WHERE id = ?1 AND session_id = ?2",
+                    [id, sid],
+                )?;
+                match deleted {
+                    0 => Err(not_found()),
+                    _ => Ok(()),
+                }
+            })
+            .await?;
+
+        let path = self.object_file_path(oid);
+        if let Some(blobs) = &self.blobs {
+            blobs.remove(&path, name.as_deref()).await?;
+        } else {
+            match tokio::fs::metadata(&path).await {
+                Ok(meta) => {
+                    tokio::fs::remove_file(path).await?;
+                    self.quota.release(meta.len());
+                }
+                Err(e) if e.kind() == ErrorKind::NotFound => (),
+                Err(e) => return Err(Box::new(e)),
+            }
+        }
+        Ok(())
+    }
+
+    async fn auth_key(&self, oid: &ObjectId) -> Result<Option<Vec<u8>>> {
+        let obj = self.get_object(oid).await?;
+        let key = if let Some(auth_key) = obj.auth_key {
+            auth_key
+        } else {
+            let sid = sql_id(&self.sid);
+            let data = self
+                .db
+                .call(move |conn| {
+                    conn.query_row("SELECT data FROM sessions WHERE id = ?1", [sid], |row| {
+                        row.get::<_, String>(0)
+                    })
+                    .optional()
+                })
+                .await?;
+            let crypto = match data {
+                Some(data) => serde_json::from_str::<Session>(&data)?.crypto,
+                None => None,
+            };
+            match crypto {
+                Some(crypto) => crypto.auth_key,
+                None => return Ok(None),
+            }
+        };
+        let buf = BASE64_STANDARD.decode(key)?;
+        Ok(Some(buf))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use temp_dir::TempDir;
+
+    use crate::{
+        models::object::Upload,
+        repositories::session::{SessionRepository, SessionSqliteRepository},
+    };
+
+    use super::*;
+
+    #[tokio::test]
+    async fn upload_list_and_delete_object() -> Result<()> {
+        let tmpdir = TempDir::new()?;
+        let dir = tmpdir.path();
+        let db = SqliteDatabase::open(dir.join("webdrop.db"))?;
+
+        let sid = {
+            let sess = Session::default();
+            SessionSqliteRepository::new(db.clone(), dir)
+                .create(&sess)
+                .await?;
+            sess.id
+        };
+
+        let repo = ObjectSqliteRepository::new(db, sid, dir.join(sid.to_string()));
+        let first: Object = Upload::default().into();
+        let mut second: Object = Upload::default().into();
+        repo.put(&first).await?;
+        repo.upload(&mut second, &b"Hello sqlite"[..]).await?;
+
+        assert_eq!(second.size, Some(12));
+        let objects = repo.list().await?;
+        assert_eq!(objects, vec![second.clone(), first.clone()]);
+        assert_eq!(repo.get(&second.id).await?, second);
+
+        repo.delete(&first.id).await?;
+        assert_eq!(repo.list().await?, vec![second]);
+        Ok(())
+    }
+}
@@ -0,0 +1,283 @@
+use std::{
+    ffi::OsStr,
+    fs,
+    io::{Error as IoError, ErrorKind},
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+
+use crate::{
+    config::Config,
+    models::{object::ObjectId, session::SessionId},
+};
+
+use super::{blob::is_shared, Result, BLOBS_DIR, STAGING_SUFFIX, UPLOADS_DIR};
+
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Limits on the blobs kept in the storage directory, unlimited where unset.
+#[derive(Clone, Copy, Default, Debug)]
+pub struct QuotaLimits {
+    pub session_bytes: Option<u64>,
+    pub session_objects: Option<u64>,
+    pub storage_bytes: Option<u64>,
+}
+
+impl From<&Config> for QuotaLimits {
+    fn from(config: &Config) -> Self {
+        Self {
+            session_bytes: config.session_max_bytes,
+            session_objects: config.session_max_objects,
+            storage_bytes: config.storage_max_bytes,
+        }
+    }
+}
+
+/// Accounts the blob bytes of the whole storage directory, shared by every repository
+/// writing into it.
+#[derive(Clone, Default)]
+pub struct Quota {
+    limits: QuotaLimits,
+    used: Arc<AtomicU64>,
+}
+
+impl Quota {
+    pub fn new(limits: QuotaLimits) -> Self {
+        Self {
+            limits,
+            used: Arc::default(),
+        }
+    }
+
+    /// Starts accounting from the blobs already present in the storage directory, including
+    /// the partial blobs of resumable uploads. Blobs shared by deduplication count once.
+    pub fn scan<D: AsRef<Path>>(limits: QuotaLimits, dir: D) -> Result<Self> {
+        let quota = Self::new(limits);
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            if !path.is_dir() {
+                continue;
+            }
+            let name = path.file_name().and_then(OsStr::to_str).unwrap_or_default();
+            let used = if name == BLOBS_DIR {
+                fs::read_dir(&path)?.try_fold(0, |total, entry| {
+                    Ok::<_, IoError>(total + entry?.metadata()?.len())
+                })?
+            } else if name == UPLOADS_DIR || SessionId::from_str(name).is_ok() {
+                blob_usage(&path, false)?
+            } else {
+                continue;
+            };
+            quota.used.fetch_add(used, Ordering::Relaxed);
+        }
+        Ok(quota)
+    }
+
+    pub fn used(&self) -> u64 {
+        self.used.load(Ordering::Relaxed)
+    }
+
+    /// Accounts bytes that are stored already, which no limit can turn away.
+    pub fn hold(&self, len: u64) {
+        self.used.fetch_add(len, Ordering::Relaxed);
+    }
+
+    pub fn release(&self, len: u64) {
+        self.used
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |used| {
+                Some(used.saturating_sub(len))
+            })
+            .ok();
+    }
+
+    /// Fails if a session already holding `count` objects may not take another one.
+    pub fn check_objects(&self, count: usize) -> Result<()> {
+        match self.limits.session_objects {
+            Some(max) if count as u64 >= max => Err(quota_exceeded("session object limit")),
+            _ => Ok(()),
+        }
+    }
+
+    /// Fails if a session already holding `used` blob bytes may not take `len` more.
+    pub fn check_session_bytes(&self, used: u64, len: u64) -> Result<()> {
+        match self.limits.session_bytes {
+            Some(max) if used + len > max => Err(quota_exceeded("session byte limit")),
+            _ => Ok(()),
+        }
+    }
+
+    /// Streams `reader` into a staging file next to `path`, given the blob bytes the session
+    /// already holds. The staged blob is discarded and its bytes released when this fails,
+    /// including when a limit is crossed or the future is dropped midway. Concurrent uploads
+    /// each stream against the bytes held before them, so the session byte limit has to be
+    /// checked again before the blob is persisted.
+    pub async fn stage_blob<P, R>(
+        &self,
+        path: P,
+        mut reader: R,
+        session_used: u64,
+    ) -> Result<StagedBlob<'_>>
+    where
+        P: AsRef<Path>,
+        R: AsyncRead + Unpin,
+    {
+        let path = staging_path(path.as_ref());
+        let mut file = tokio::fs::File::create_new(&path).await?;
+        let mut staged = StagedBlob {
+            quota: self,
+            path,
+            len: 0,
+            kept: false,
+        };
+        let written = async {
+            let mut chunk = vec![0u8; CHUNK_SIZE];
+            loop {
+                let n = reader.read(&mut chunk).await?;
+                if n == 0 {
+                    break;
+                }
+                self.check_session_bytes(session_used, staged.len + n as u64)?;
+                self.reserve(n as u64)?;
+                staged.len += n as u64;
+                file.write_all(&chunk[..n]).await?;
+            }
+            file.flush().await?;
+            file.sync_data().await?;
+            Ok(())
+        };
+        match written.await {
+            Ok(()) => Ok(staged),
+            Err(e) => {
+                staged.discard().await;
+                Err(e)
+            }
+        }
+    }
+
+    /// Accounts for blob bytes about to be written, failing once the storage would be full.
+    pub(super) fn reserve(&self, len: u64) -> Result<()> {
+        let Some(max) = self.limits.storage_bytes else {
+            self.used.fetch_add(len, Ordering::Relaxed);
+            return Ok(());
+        };
+        self.used
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |used| {
+                used.checked_add(len).filter(|&total| total <= max)
+            })
+            .map(drop)
+            .map_err(|_| IoError::new(ErrorKind::StorageFull, "storage byte limit").into())
+    }
+}
+
+/// Blob written by [`Quota::stage_blob`], which is removed along with its bytes when dropped
+/// unless it has been kept. Failing paths should [`discard`](Self::discard) it instead, as
+/// dropping it can only hand the removal off to the blocking pool.
+pub struct StagedBlob<'a> {
+    quota: &'a Quota,
+    path: PathBuf,
+    len: u64,
+    kept: bool,
+}
+
+impl StagedBlob<'_> {
+    pub fn size(&self) -> u64 {
+        self.len
+    }
+
+    /// Moves the blob into place at `path`, failing if a file already exists there. It is
+    /// still removed from its new place when dropped without being kept.
+    pub async fn persist<P: AsRef<Path>>(&mut self, path: P) -> Result<()> {
+        let path = path.as_ref().to_path_buf();
+        // Linking refuses to replace an existing file, unlike renaming.
+        tokio::fs::hard_link(&self.path, &path).await?;
+        tokio::fs::remove_file(&self.path).await.ok();
+        self.path = path;
+        Ok(())
+    }
+
+    /// Keeps the blob for good, once the metadata referencing it has been saved.
+    pub fn keep(mut self) {
+        self.kept = true;
+    }
+
+    /// Removes the blob and releases its bytes right away, which dropping it only gets
+    /// around to on the blocking pool.
+    pub async fn discard(mut self) {
+        self.kept = true;
+        tokio::fs::remove_file(&self.path).await.ok();
+        self.quota.release(self.len);
+    }
+}
+
+impl Drop for StagedBlob<'_> {
+    fn drop(&mut self) {
+        if self.kept {
+            return;
+        }
+        self.quota.release(self.len);
+        let path = std::mem::take(&mut self.path);
+        // Dropped from async code when an upload is cancelled, which must not block.
+        match tokio::runtime::Handle::try_current() {
+            Ok(handle) => drop(handle.spawn_blocking(move || fs::remove_file(path))),
+            Err(_) => drop(fs::remove_file(path)),
+        }
+    }
+}
+
+/// Staging files are hidden siblings of the blob, so moving them into place never crosses
+/// a file system boundary.
+pub(super) fn staging_path(path: &Path) -> PathBuf {
+    let name = path.file_name().and_then(OsStr::to_str).unwrap_or_default();
+    path.with_file_name(format!(".{name}{STAGING_SUFFIX}"))
+}
+
+/// Sums the sizes of the blob files in a session directory on the blocking pool, which is
+/// empty when the directory does not exist.
+pub async fn session_usage(dir: PathBuf) -> Result<u64> {
+    usage_blocking(dir, true).await
+}
+
+/// Like [`session_usage`], but leaving out the blobs shared by deduplication, as these
+/// are accounted for by the blob store.
+pub async fn unshared_usage(dir: PathBuf) -> Result<u64> {
+    usage_blocking(dir, false).await
+}
+
+async fn usage_blocking(dir: PathBuf, shared: bool) -> Result<u64> {
+    tokio::task::spawn_blocking(move || match blob_usage(&dir, shared) {
+        Err(e) if e.downcast_ref::<IoError>().map(IoError::kind) == Some(ErrorKind::NotFound) => {
+            Ok(0)
+        }
+        res => res,
+    })
+    .await?
+}
+
+fn blob_usage(dir: &Path, shared: bool) -> Result<u64> {
+    let mut total = 0;
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let is_blob = entry
+            .file_name()
+            .to_str()
+            .is_some_and(|name| ObjectId::from_str(name).is_ok());
+        if !is_blob {
+            continue;
+        }
+        let meta = entry.metadata()?;
+        if shared || !is_shared(&meta) {
+            total += meta.len();
+        }
+    }
+    Ok(total)
+}
+
+fn quota_exceeded(msg: &str) -> Box<dyn std::error::Error + Send + Sync> {
+    Box::new(IoError::new(ErrorKind::QuotaExceeded, msg))
+}
@@ -0,0 +1,153 @@
+use std::path::Path;
+
+use crate::{
+    config::{Config, StorageBackend},
+    models::session::{Session, SessionId},
+    repositories::{
+        blob::BlobStore, encryption::MasterKey, memory::MemoryStore, quota::Quota, s3::S3Client,
+        sqlite::SqliteDatabase, Result, BLOBS_DIR, SQLITE_DATABASE_FILE,
+    },
+};
+
+use super::{
+    SessionFsRepository, SessionMemoryRepository, SessionRepository, SessionS3Repository,
+    SessionSqliteRepository,
+};
+
+macro_rules! dispatch {
+    ($self:ident, $repo:ident => $expr:expr) => {
+        match $self {
+            Self::Fs($repo) => $expr,
+            Self::Sqlite($repo) => $expr,
+            Self::Memory($repo) => $expr,
+            Self::FsS3($repo) => $expr,
+            Self::SqliteS3($repo) => $expr,
+        }
+    };
+}
+
+/// Session repository selected at startup through `STORAGE_BACKEND` and `S3_ENDPOINT`.
+pub enum AnySessionRepository {
+    Fs(SessionFsRepository),
+    Sqlite(SessionSqliteRepository),
+    Memory(SessionMemoryRepository),
+    FsS3(SessionS3Repository<SessionFsRepository>),
+    SqliteS3(SessionS3Repository<SessionSqliteRepository>),
+}
+
+impl AnySessionRepository {
+    pub fn open<D: AsRef<Path>>(config: &Config, dir: D) -> Result<Self> {
+        let dir = dir.as_ref();
+        check_supported(config)?;
+        let client = config.s3.clone().map(S3Client::new);
+        let repository = match (config.storage_backend, client) {
+            (StorageBackend::Fs, None) => {
+                let quota = Quota::scan(config.into(), dir)?;
+                let master_key = config.master_key_file.as_ref().map(MasterKey::load);
+                let master_key = master_key.transpose()?;
+                let blobs = blob_store(config, dir, &quota)
+                    .map(|blobs| blobs.with_master_key(master_key.clone()));
+                Self::Fs(
+                    SessionFsRepository::new(dir)
+                        .with_quota(quota)
+                        .with_blob_store(blobs)
+                        .with_compression(config.blob_compression)
+                        .with_master_key(master_key),
+                )
+            }
+            (StorageBackend::Fs, Some(client)) => Self::FsS3(SessionS3Repository::new(
+                client,
+                SessionFsRepository::new(dir),
+            )),
+            (StorageBackend::Sqlite, client) => {
+                let db = SqliteDatabase::open(dir.join(SQLITE_DATABASE_FILE))?;
+                let repository = SessionSqliteRepository::new(db, dir);
+                match client {
+                    Some(client) => Self::SqliteS3(SessionS3Repository::new(client, repository)),
+                    None => {
+                        let quota = Quota::scan(config.into(), dir)?;
+                        let blobs = blob_store(config, dir, &quota);
+                        Self::Sqlite(repository.with_quota(quota).with_blob_store(blobs))
+                    }
+                }
+            }
+            (StorageBackend::Memory, None) => {
+                let store = MemoryStore::new(config.memory_limit);
+                Self::Memory(SessionMemoryRepository::new(store))
+            }
+            (StorageBackend::Memory, Some(_)) => {
+                return Err("S3 storage cannot be combined with the memory backend".into())
+            }
+        };
+        Ok(repository)
+    }
+}
+
+/// Rejects settings that the selected storage would silently ignore. Only blobs in the
+/// storage directory are accounted and deduplicated, and only the fs backend compresses
+/// and seals them.
+fn check_supported(config: &Config) -> Result<()> {
+    let memory = config.storage_backend == StorageBackend::Memory;
+    let local = !memory && config.s3.is_none();
+    let fs = local && config.storage_backend == StorageBackend::Fs;
+    let in_memory = [("MEMORY_LIMIT", config.memory_limit.is_some())];
+    let in_storage_dir = [
+        ("SESSION_MAX_BYTES", config.session_max_bytes.is_some()),
+        ("SESSION_MAX_OBJECTS", config.session_max_objects.is_some()),
+        ("STORAGE_MAX_BYTES", config.storage_max_bytes.is_some()),
+        ("BLOB_DEDUP", config.blob_dedup),
+    ];
+    let in_fs = [
+        ("BLOB_COMPRESSION", config.blob_compression),
+        ("MASTER_KEY_FILE", config.master_key_file.is_some()),
+    ];
+    let checks: [(&[_], _, _); 3] = [
+        (&in_memory, memory, "the memory backend"),
+        (
+            &in_storage_dir,
+            local,
+            "the fs and sqlite backends without S3",
+        ),
+        (&in_fs, fs, "the fs backend without S3"),
+    ];
+    for (settings, supported, by) in checks {
+        if supported {
+            continue;
+        }
+        if let Some((name, _)) = settings.iter().find(|(_, set)| *set) {
+            return Err(format!("{name} is only supported by {by}").into());
+        }
+    }
+    Ok(())
+}
+
+/// Deduplication relies on the link counts of files, which are only available on Unix.
+fn blob_store(config: &Config, dir: &Path, quota: &Quota) -> Option<BlobStore> {
+    (cfg!(unix) && config.blob_dedup).then(|| BlobStore::new(dir.join(BLOBS_DIR), quota.clone()))
+}
+
+impl SessionRepository for AnySessionRepository {
+    async fn list(&self) -> Result<Vec<SessionId>> {
+        dispatch!(self, repo => repo.list().await)
+    }
+
+    async fn create(&self, sess: &Session) -> Result<()> {
+        dispatch!(self, repo => repo.create(sess).await)
+    }
+
+    async fn exists(&self, sid: &SessionId) -> Result<bool> {
+        dispatch!(self, repo => repo.exists(sid).await)
+    }
+
+    async fn get(&self, sid: &SessionId) -> Result<Session> {
+        dispatch!(self, repo => repo.get(sid).await)
+    }
+
+    async fn delete(&self, sid: &SessionId) -> Result<()> {
+        dispatch!(self, repo => repo.delete(sid).await)
+    }
+
+    async fn auth_key(&self, sid: &SessionId) -> Result<Option<Vec<u8>>> {
+        dispatch!(self, repo => repo.auth_key(sid).await)
+    }
+}
@@ -1,31 +1,80 @@
 use std::{
     ffi::OsStr,
-    fs,
-    io::{ErrorKind, Write},
+    io::ErrorKind,
     path::{Path, PathBuf},
     str::FromStr,
 };
 
 use base64::{prelude::BASE64_STANDARD, Engine};
+use tokio::fs;
 
 use crate::{
     models::session::{Session, SessionId},
-    repositories::{fs::BaseFsRepository, Result, SESSION_AUTH_KEY_FILE, SESSION_FILE},
+    repositories::{
+        blob::BlobStore,
+        encryption::MasterKey,
+        fs::{write_atomic, BaseFsRepository},
+        quota::{unshared_usage, Quota},
+        Result, SESSION_AUTH_KEY_FILE, SESSION_FILE,
+    },
 };
 
 use super::SessionRepository;
 
 pub struct SessionFsRepository {
     dir: PathBuf,
+    quota: Quota,
+    blobs: Option<BlobStore>,
+    compression: bool,
+    master_key: Option<MasterKey>,
 }
 
 impl SessionFsRepository {
     pub fn new<D: AsRef<Path>>(dir: D) -> Self {
         Self {
             dir: dir.as_ref().to_path_buf(),
+            quota: Quota::default(),
+            blobs: None,
+            compression: false,
+            master_key: None,
         }
     }
 
+    pub fn with_quota(mut self, quota: Quota) -> Self {
+        self.quota = quota;
+        self
+    }
+
+    /// Shares identical blobs through the store, stored separately when unset.
+    pub fn with_blob_store(mut self, blobs: Option<BlobStore>) -> Self {
+        self.blobs = blobs;
+        self
+    }
+
+    /// Compresses the blobs of objects in its sessions at rest where worthwhile.
+    pub fn with_compression(mut self, compression: bool) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    /// Seals the files of its sessions with the key, stored as is when unset.
+    pub fn with_master_key(mut self, master_key: Option<MasterKey>) -> Self {
+        self.master_key = master_key;
+        self
+    }
+
+    pub fn quota(&self) -> &Quota {
+        &self.quota
+    }
+
+    pub fn blob_store(&self) -> Option<&BlobStore> {
+        self.blobs.as_ref()
+    }
+
+    pub fn compression(&self) -> bool {
+        self.compression
+    }
+
     fn session_dir_path(&self, sid: &SessionId) -> PathBuf {
         self.dir.join(sid.to_string())
     }
@@ -42,8 +91,8 @@ impl SessionFsRepository {
 impl SessionRepository for SessionFsRepository {
     async fn list(&self) -> Result<Vec<SessionId>> {
         let mut vec = Vec::default();
-        for result in fs::read_dir(&self.dir)? {
-            let dir = result?;
+        let mut entries = fs::read_dir(&self.dir).await?;
+        while let Some(dir) = entries.next_entry().await? {
             if let Some(name) = dir.path().file_name().and_then(OsStr::to_str) {
                 if let Ok(sid) = SessionId::from_str(name) {
                     vec.push(sid);
@@ -56,24 +105,22 @@ impl SessionRepository for SessionFsRepository {
     async fn create(&self, sess: &Session) -> Result<()> {
         let sid = &sess.id;
         let dir = self.session_dir_path(sid);
-        fs::create_dir(&dir)?;
+        fs::create_dir(&dir).await?;
 
         let path = self.session_file_path(sid);
-        let file = fs::File::create_new(path)?;
-        self.save(file, sess)?;
+        self.save_new(path, sess).await?;
 
         if let Some(crypto) = sess.crypto.as_ref() {
             let path = self.session_auth_key_path(sid);
-            let mut file = fs::File::create_new(path)?;
-            file.write_all(crypto.auth_key.as_bytes())?;
+            write_atomic(path, crypto.auth_key.clone().into_bytes(), false).await?;
         }
 
         Ok(())
     }
 
     async fn exists(&self, sid: &SessionId) -> Result<bool> {
-        let path = self.session_file_path(&sid);
-        match fs::metadata(path) {
+        let path = self.session_file_path(sid);
+        match fs::metadata(path).await {
             Ok(meta) => Ok(meta.is_file()),
             Err(e) if e.kind() == ErrorKind::NotFound => Ok(false),
             Err(e) => Err(Box::new(e)),
@@ -81,19 +128,32 @@ impl SessionRepository for SessionFsRepository {
     }
 
     async fn get(&self, sid: &SessionId) -> Result<Session> {
-        let session: Session = self.load(self.session_file_path(sid))?;
-        Ok(session.into())
+        self.load_session(self.session_dir_path(sid)).await
     }
 
     async fn delete(&self, sid: &SessionId) -> Result<()> {
-        fs::remove_dir_all(self.session_dir_path(sid))?;
+        let dir = self.session_dir_path(sid);
+        // Which stored blobs the session links to is only known from its metadata.
+        let names = match &self.blobs {
+            Some(blobs) => self.get(sid).await.ok().map(|sess| blobs.names(&sess)),
+            None => None,
+        };
+        let used = unshared_usage(dir.clone()).await?;
+        // Runs on the blocking pool so large sessions do not stall the runtime workers.
+        fs::remove_dir_all(dir).await?;
+        self.quota.release(used);
+        match (&self.blobs, names) {
+            (Some(blobs), Some(names)) => blobs.release(names).await?,
+            (Some(blobs), None) => blobs.collect_all().await?,
+            _ => (),
+        }
         Ok(())
     }
 
     async fn auth_key(&self, sid: &SessionId) -> Result<Option<Vec<u8>>> {
         let key_path = self.session_auth_key_path(sid);
-        let key = if fs::exists(&key_path)? {
-            self.read_string(self.session_auth_key_path(sid))?
+        let key = if fs::try_exists(&key_path).await? {
+            self.read_string(key_path).await?
         } else {
             return Ok(None);
         };
@@ -102,7 +162,11 @@ impl SessionRepository for SessionFsRepository {
     }
 }
 
-impl BaseFsRepository for SessionFsRepository {}
+impl BaseFsRepository for SessionFsRepository {
+    fn master_key(&self) -> Option<&MasterKey> {
+        self.master_key.as_ref()
+    }
+}
 
 #[cfg(test)]
 mod tests {
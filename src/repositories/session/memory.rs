@@ -0,0 +1,99 @@
+use std::{
+    collections::HashMap,
+    io::{Error as IoError, ErrorKind},
+};
+
+use base64::{prelude::BASE64_STANDARD, Engine};
+
+use crate::{
+    models::session::{Session, SessionId},
+    repositories::{
+        memory::{MemorySession, MemoryStore},
+        Result,
+    },
+};
+
+use super::SessionRepository;
+
+pub struct SessionMemoryRepository {
+    store: MemoryStore,
+}
+
+impl SessionMemoryRepository {
+    pub fn new(store: MemoryStore) -> Self {
+        Self { store }
+    }
+
+    pub fn store(&self) -> &MemoryStore {
+        &self.store
+    }
+}
+
+impl SessionRepository for SessionMemoryRepository {
+    async fn list(&self) -> Result<Vec<SessionId>> {
+        Ok(self.store.lock().sessions.keys().copied().collect())
+    }
+
+    async fn create(&self, sess: &Session) -> Result<()> {
+        let mut data = self.store.lock();
+        if data.sessions.contains_key(&sess.id) {
+            return Err(Box::new(IoError::from(ErrorKind::AlreadyExists)));
+        }
+        let entry = MemorySession {
+            session: sess.clone(),
+            blobs: HashMap::new(),
+        };
+        data.sessions.insert(sess.id, entry);
+        Ok(())
+    }
+
+    async fn exists(&self, sid: &SessionId) -> Result<bool> {
+        Ok(self.store.lock().sessions.contains_key(sid))
+    }
+
+    async fn get(&self, sid: &SessionId) -> Result<Session> {
+        Ok(self.store.lock().session(sid)?.session.clone())
+    }
+
+    async fn delete(&self, sid: &SessionId) -> Result<()> {
+        let entry = self
+            .store
+            .lock()
+            .sessions
+            .remove(sid)
+            .ok_or_else(|| IoError::from(ErrorKind::NotFound))?;
+        let len = entry.blobs.values().map(|blob| blob.len() as u64).sum();
+        self.store.release(len);
+        Ok(())
+    }
+
+    async fn auth_key(&self, sid: &SessionId) -> Result<Option<Vec<u8>>> {
+        let data = self.store.lock();
+        let Some(entry) = data.sessions.get(sid) else {
+            return Ok(None);
+        };
+        match entry.session.crypto.as_ref() {
+            Some(crypto) => Ok(Some(BASE64_STANDARD.decode(&crypto.auth_key)?)),
+            None => Ok(None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn create_and_delete_session() -> Result<()> {
+        let repo = SessionMemoryRepository::new(MemoryStore::default());
+        let sess = Session::default();
+        let sid = &sess.id;
+        repo.create(&sess).await?;
+        assert!(repo.exists(sid).await?);
+        assert_eq!(repo.get(sid).await?, sess);
+        repo.delete(sid).await?;
+        assert!(!repo.exists(sid).await?);
+        assert!(repo.delete(sid).await.is_err());
+        Ok(())
+    }
+}
@@ -1,4 +1,8 @@
+mod any;
 mod fs;
+mod memory;
+mod s3;
+mod sqlite;
 
 use std::future::Future;
 
@@ -6,7 +10,11 @@ use crate::models::session::{Session, SessionId};
 
 use super::Result;
 
+pub use any::AnySessionRepository;
 pub use fs::SessionFsRepository;
+pub use memory::SessionMemoryRepository;
+pub use s3::SessionS3Repository;
+pub use sqlite::SessionSqliteRepository;
 
 pub trait SessionRepository {
     fn list(&self) -> impl Future<Output = Result<Vec<SessionId>>>;
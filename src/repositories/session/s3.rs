@@ -0,0 +1,98 @@
+use crate::{
+    models::session::{Session, SessionId},
+    repositories::{object::object_key, s3::S3Client, Result},
+};
+
+use super::SessionRepository;
+
+/// Removes the blobs of every object from the bucket before deleting a session.
+pub struct SessionS3Repository<S> {
+    client: S3Client,
+    inner: S,
+}
+
+impl<S> SessionS3Repository<S> {
+    pub fn new(client: S3Client, inner: S) -> Self {
+        Self { client, inner }
+    }
+
+    pub fn client(&self) -> &S3Client {
+        &self.client
+    }
+
+    pub fn inner(&self) -> &S {
+        &self.inner
+    }
+}
+
+impl<S: SessionRepository> SessionRepository for SessionS3Repository<S> {
+    async fn list(&self) -> Result<Vec<SessionId>> {
+        self.inner.list().await
+    }
+
+    async fn create(&self, sess: &Session) -> Result<()> {
+        self.inner.create(sess).await
+    }
+
+    async fn exists(&self, sid: &SessionId) -> Result<bool> {
+        self.inner.exists(sid).await
+    }
+
+    async fn get(&self, sid: &SessionId) -> Result<Session> {
+        self.inner.get(sid).await
+    }
+
+    async fn delete(&self, sid: &SessionId) -> Result<()> {
+        let sess = self.inner.get(sid).await?;
+        for obj in sess.objects {
+            self.client.delete_object(&object_key(sid, &obj.id)).await?;
+        }
+        self.inner.delete(sid).await
+    }
+
+    async fn auth_key(&self, sid: &SessionId) -> Result<Option<Vec<u8>>> {
+        self.inner.auth_key(sid).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use temp_dir::TempDir;
+
+    use crate::{
+        models::object::{Object, Upload},
+        repositories::{
+            object::{ObjectFsRepository, ObjectRepository, ObjectS3Repository},
+            s3::mock::MockS3,
+            session::SessionFsRepository,
+        },
+    };
+
+    use super::*;
+
+    #[tokio::test]
+    async fn delete_session_removes_blobs() -> Result<()> {
+        let s3 = MockS3::start().await;
+        let tmpdir = TempDir::new()?;
+        let dir = tmpdir.path();
+        let repo = SessionS3Repository::new(s3.client(), SessionFsRepository::new(dir));
+
+        let sess = Session::default();
+        let sid = sess.id;
+        repo.create(&sess).await?;
+
+        let objects = ObjectS3Repository::new(
+            s3.client(),
+            sid,
+            ObjectFsRepository::new(dir.join(sid.to_string())),
+        );
+        let mut obj: Object = Upload::default().into();
+        objects.upload(&mut obj, &b"Hello bucket"[..]).await?;
+        assert!(s3.object(&object_key(&sid, &obj.id)).is_some());
+
+        repo.delete(&sid).await?;
+        assert!(!repo.exists(&sid).await?);
+        assert_eq!(s3.object(&object_key(&sid, &obj.id)), None);
+        Ok(())
+    }
+}
@@ -0,0 +1,205 @@
+use std::{
+    io::ErrorKind,
+    path::{Path, PathBuf},
+};
+
+use base64::{prelude::BASE64_STANDARD, Engine};
+use rusqlite::{params, OptionalExtension};
+
+use crate::{
+    models::{
+        object::Object,
+        session::{Session, SessionId},
+    },
+    repositories::{
+        blob::BlobStore,
+        quota::{unshared_usage, Quota},
+        sqlite::{from_json_error, not_found, sql_id, SqliteDatabase},
+        Result,
+    },
+};
+
+use super::SessionRepository;
+
+pub struct SessionSqliteRepository {
+    db: SqliteDatabase,
+    dir: PathBuf,
+    quota: Quota,
+    blobs: Option<BlobStore>,
+}
+
+impl SessionSqliteRepository {
+    pub fn new<D: AsRef<Path>>(db: SqliteDatabase, dir: D) -> Self {
+        Self {
+            db,
+            dir: dir.as_ref().to_path_buf(),
+            quota: Quota::default(),
+            blobs: None,
+        }
+    }
+
+    pub fn with_quota(mut self, quota: Quota) -> Self {
+        self.quota = quota;
+        self
+    }
+
+    /// Shares identical blobs through the store, stored separately when unset.
+    pub fn with_blob_store(mut self, blobs: Option<BlobStore>) -> Self {
+        self.blobs = blobs;
+        self
+    }
+
+    pub fn quota(&self) -> &Quota {
+        &self.quota
+    }
+
+    pub fn blob_store(&self) -> Option<&BlobStore> {
+        self.blobs.as_ref()
+    }
+
+    pub fn database(&self) -> &SqliteDatabase {
+        &self.db
+    }
+
+    fn session_dir_path(&self, sid: &SessionId) -> PathBuf {
+        self.dir.join(sid.to_string())
+    }
+}
+
+impl SessionRepository for SessionSqliteRepository {
+    async fn list(&self) -> Result<Vec<SessionId>> {
+        self.db
+            .call(|conn| {
+                let mut stmt = conn.prepare("SELECT id FROM sessions")?;
+                let rows = stmt.query_map([], |row| row.get::<_, i64>(0))?;
+                rows.map(|id| id.map(|id| SessionId::from_u64(id as u64)))
+                    .collect()
+            })
+            .await
+    }
+
+    async fn create(&self, sess: &Session) -> Result<()> {
+        let id = sql_id(&sess.id);
+        let data = serde_json::to_string(sess)?;
+        self.db
+            .call(move |conn| {
+                conn.execute(
+                    "INSERT INTO sessions (id, data) VALUES (?1, ?2)",
+                    params![id, data],
+                )?;
+                Ok(())
+            })
+            .await
+    }
+
+    async fn exists(&self, sid: &SessionId) -> Result<bool> {
+        let id = sql_id(sid);
+        self.db
+            .call(move |conn| {
+                conn.query_row(
+                    "SELECT EXISTS (SELECT 1 FROM sessions WHERE id = ?1)",
+                    [id],
+                    |row| row.get(0),
+                )
+            })
+            .await
+    }
+
+    async fn get(&self, sid: &SessionId) -> Result<Session> {
+        let id = sql_id(sid);
+        self.db
+            .call(move |conn| {
+                let tx = conn.transaction()?;
+                let data: String =
+                    tx.query_row("SELECT data FROM sessions WHERE id = ?1", [id], |row| {
+                        row.get(0)
+                    })?;
+                let mut sess: Session = serde_json::from_str(&data).map_err(from_json_error)?;
+                // Objects live in their own table, the serialized list is never updated.
+                sess.objects.clear();
+                let mut stmt =
+                    tx.prepare("SELECT data FROM objects WHERE session_id = ?1 ORDER BY seq DESC")?;
+                for data in stmt.query_map([id], |row| row.get::<_, String>(0))? {
+                    let obj: Object = serde_json::from_str(&data?).map_err(from_json_error)?;
+                    sess.objects.push_back(obj);
+                }
+                Ok(sess)
+            })
+            .await
+    }
+
+    async fn delete(&self, sid: &SessionId) -> Result<()> {
+        let id = sql_id(sid);
+        let names = match &self.blobs {
+            Some(blobs) => blobs.names(&self.get(sid).await?),
+            None => Vec::new(),
+        };
+        self.db
+            .call(
+                move |conn| match conn.execute("DELETE FROM sessions WHERE id = ?1", [id])? {
+                    0 => Err(not_found()),
+                    _ => Ok(()),
+                },
+            )
+            .await?;
+        let dir = self.session_dir_path(sid);
+        let used = unshared_usage(dir.clone()).await?;
+        match tokio::fs::remove_dir_all(dir).await {
+            Err(e) if e.kind() != ErrorKind::NotFound => Err(Box::new(e)),
+            _ => {
+                self.quota.release(used);
+                match &self.blobs {
+                    Some(blobs) => blobs.release(names).await,
+                    None => Ok(()),
+                }
+            }
+        }
+    }
+
+    async fn auth_key(&self, sid: &SessionId) -> Result<Option<Vec<u8>>> {
+        let id = sql_id(sid);
+        let sess = self
+            .db
+            .call(move |conn| {
+                let data: Option<String> = conn
+                    .query_row("SELECT data FROM sessions WHERE id = ?1", [id], |row| {
+                        row.get(0)
+                    })
+                    .optional()?;
+                data.map(|data| serde_json::from_str::<Session>(&data))
+                    .transpose()
+                    .map_err(from_json_error)
+            })
+            .await?;
+        let Some(sess) = sess else {
+            return Ok(None);
+        };
+        match sess.crypto {
+            Some(crypto) => Ok(Some(BASE64_STANDARD.decode(crypto.auth_key)?)),
+            None => Ok(None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use temp_dir::TempDir;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn create_and_get_session() -> Result<()> {
+        let tmpdir = TempDir::new()?;
+        let repo = SessionSqliteRepository::new(SqliteDatabase::open_in_memory()?, tmpdir.path());
+        let sess = Session::default();
+        let sid = &sess.id;
+        repo.create(&sess).await?;
+        assert!(repo.exists(sid).await?);
+        assert_eq!(repo.list().await?, vec![*sid]);
+        let sess2 = repo.get(sid).await?;
+        assert_eq!(sess2, sess);
+        repo.delete(sid).await?;
+        assert!(!repo.exists(sid).await?);
+        Ok(())
+    }
+}
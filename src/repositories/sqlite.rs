@@ -0,0 +1,87 @@
+use std::{
+    io::{Error as IoError, ErrorKind},
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+use rusqlite::Connection;
+
+use crate::models::snowflake::SnowflakeId;
+
+use super::Result;
+
+const SCHEMA: &str = "
+PRAGMA journal_mode = WAL;
+PRAGMA foreign_keys = ON;
+
+CREATE TABLE IF NOT EXISTS sessions (
+    id INTEGER PRIMARY KEY,
+    data TEXT NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS objects (
+    seq INTEGER PRIMARY KEY AUTOINCREMENT,
+    id INTEGER NOT NULL UNIQUE,
+    session_id INTEGER NOT NULL REFERENCES sessions (id) ON DELETE CASCADE,
+    data TEXT NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS objects_session_id ON objects (session_id, seq);
+";
+
+#[derive(Clone)]
+pub struct SqliteDatabase {
+    conn: Arc<Mutex<Connection>>,
+}
+
+impl SqliteDatabase {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Self::init(Connection::open(path)?)
+    }
+
+    pub fn open_in_memory() -> Result<Self> {
+        Self::init(Connection::open_in_memory()?)
+    }
+
+    fn init(conn: Connection) -> Result<Self> {
+        conn.execute_batch(SCHEMA)?;
+        Ok(Self {
+            conn: Arc::new(Mutex::new(conn)),
+        })
+    }
+
+    /// Runs the closure against the connection on the blocking thread pool.
+    pub(super) async fn call<F, T>(&self, f: F) -> Result<T>
+    where
+        F: FnOnce(&mut Connection) -> rusqlite::Result<T> + Send + 'static,
+        T: Send + 'static,
+    {
+        let conn = Arc::clone(&self.conn);
+        let result = tokio::task::spawn_blocking(move || {
+            let mut conn = conn.lock().unwrap();
+            f(&mut conn)
+        })
+        .await?;
+        result.map_err(normalize_error)
+    }
+}
+
+/// Maps missing rows to `NotFound` so services can tell them apart from other failures.
+fn normalize_error(err: rusqlite::Error) -> Box<dyn std::error::Error + Send + Sync> {
+    match err {
+        rusqlite::Error::QueryReturnedNoRows => Box::new(IoError::from(ErrorKind::NotFound)),
+        e => Box::new(e),
+    }
+}
+
+pub(super) fn not_found() -> rusqlite::Error {
+    rusqlite::Error::QueryReturnedNoRows
+}
+
+pub(super) fn from_json_error(err: serde_json::Error) -> rusqlite::Error {
+    rusqlite::Error::FromSqlConversionFailure(0, rusqlite::types::Type::Text, Box::new(err))
+}
+
+pub(super) fn sql_id(id: &SnowflakeId) -> i64 {
+    id.as_u64() as i64
+}
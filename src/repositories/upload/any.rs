@@ -0,0 +1,83 @@
+use std::path::Path;
+
+use tokio::io::AsyncRead;
+
+use crate::{
+    models::upload::{PendingUpload, UploadId},
+    repositories::{session::AnySessionRepository, Result, UPLOADS_DIR},
+};
+
+use super::{UploadFsRepository, UploadMemoryRepository, UploadRepository};
+
+macro_rules! dispatch {
+    ($self:ident, $repo:ident => $expr:expr) => {
+        match $self {
+            Self::Fs($repo) => $expr,
+            Self::Memory($repo) => $expr,
+        }
+    };
+}
+
+/// Upload repository keeping partial blobs on the local disk, or in memory when the
+/// session repository does.
+pub enum AnyUploadRepository {
+    Fs(UploadFsRepository),
+    Memory(UploadMemoryRepository),
+}
+
+impl AnyUploadRepository {
+    pub fn new<D: AsRef<Path>>(session: &AnySessionRepository, dir: D) -> Self {
+        let dir = dir.as_ref().join(UPLOADS_DIR);
+        match session {
+            AnySessionRepository::Fs(repo) => {
+                Self::Fs(UploadFsRepository::new(dir).with_quota(repo.quota().clone()))
+            }
+            AnySessionRepository::Sqlite(repo) => {
+                Self::Fs(UploadFsRepository::new(dir).with_quota(repo.quota().clone()))
+            }
+            AnySessionRepository::Memory(repo) => {
+                Self::Memory(UploadMemoryRepository::new(repo.store().clone()))
+            }
+            AnySessionRepository::FsS3(_) | AnySessionRepository::SqliteS3(_) => {
+                Self::Fs(UploadFsRepository::new(dir))
+            }
+        }
+    }
+}
+
+impl UploadRepository for AnyUploadRepository {
+    async fn list(&self) -> Result<Vec<PendingUpload>> {
+        dispatch!(self, repo => repo.list().await)
+    }
+
+    async fn create(&self, upload: &PendingUpload) -> Result<()> {
+        dispatch!(self, repo => repo.create(upload).await)
+    }
+
+    async fn get(&self, uid: &UploadId) -> Result<PendingUpload> {
+        dispatch!(self, repo => repo.get(uid).await)
+    }
+
+    async fn append<R>(&self, upload: &mut PendingUpload, reader: R) -> Result<()>
+    where
+        R: AsyncRead + Unpin + Send,
+    {
+        dispatch!(self, repo => repo.append(upload, reader).await)
+    }
+
+    async fn open(&self, uid: &UploadId) -> Result<Box<dyn AsyncRead + Unpin + Send + Sync>> {
+        dispatch!(self, repo => repo.open(uid).await)
+    }
+
+    async fn delete(&self, uid: &UploadId) -> Result<()> {
+        dispatch!(self, repo => repo.delete(uid).await)
+    }
+
+    fn lend(&self, upload: &PendingUpload) {
+        dispatch!(self, repo => repo.lend(upload))
+    }
+
+    fn reclaim(&self, upload: &PendingUpload) {
+        dispatch!(self, repo => repo.reclaim(upload))
+    }
+}
@@ -0,0 +1,217 @@
+use std::{
+    ffi::OsStr,
+    io::{ErrorKind, SeekFrom},
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use tokio::{
+    fs,
+    io::{AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWriteExt},
+};
+use tracing::{event, Level};
+
+use crate::{
+    models::upload::{PendingUpload, UploadId},
+    repositories::{fs::BaseFsRepository, quota::Quota, Result},
+};
+
+use super::UploadRepository;
+
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Keeps each upload as a metadata file next to its partial blob.
+pub struct UploadFsRepository {
+    dir: PathBuf,
+    quota: Quota,
+}
+
+impl UploadFsRepository {
+    pub fn new<D: AsRef<Path>>(dir: D) -> Self {
+        Self {
+            dir: dir.as_ref().to_path_buf(),
+            quota: Quota::default(),
+        }
+    }
+
+    pub fn with_quota(mut self, quota: Quota) -> Self {
+        self.quota = quota;
+        self
+    }
+
+    fn metadata_path(&self, uid: &UploadId) -> PathBuf {
+        self.dir.join(format!("{uid}.json"))
+    }
+
+    fn blob_path(&self, uid: &UploadId) -> PathBuf {
+        self.dir.join(uid.to_string())
+    }
+
+    /// Writes the whole stream at the end of `file`, returning the bytes written along with
+    /// the error that cut it short, if any.
+    async fn write_chunks<R>(&self, file: &mut fs::File, mut reader: R) -> (u64, Result<()>)
+    where
+        R: AsyncRead + Unpin,
+    {
+        let mut written = 0u64;
+        let mut chunk = vec![0u8; CHUNK_SIZE];
+        loop {
+            let n = match reader.read(&mut chunk).await {
+                Ok(0) => return (written, Ok(())),
+                Ok(n) => n,
+                Err(e) => return (written, Err(Box::new(e))),
+            };
+            if let Err(e) = self.quota.reserve(n as u64) {
+                return (written, Err(e));
+            }
+            if let Err(e) = file.write_all(&chunk[..n]).await {
+                self.quota.release(n as u64);
+                return (written, Err(Box::new(e)));
+            }
+            written += n as u64;
+        }
+    }
+}
+
+impl UploadRepository for UploadFsRepository {
+    async fn list(&self) -> Result<Vec<PendingUpload>> {
+        let mut uploads = Vec::new();
+        let mut entries = match fs::read_dir(&self.dir).await {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(uploads),
+            Err(e) => return Err(Box::new(e)),
+        };
+        while let Some(entry) = entries.next_entry().await? {
+            let path = entry.path();
+            let is_upload = path.extension() == Some(OsStr::new("json"))
+                && path
+                    .file_stem()
+                    .and_then(OsStr::to_str)
+                    .is_some_and(|stem| UploadId::from_str(stem).is_ok());
+            if !is_upload {
+                continue;
+            }
+            match self.load(&path).await {
+                Ok(upload) => uploads.push(upload),
+                Err(e) => event!(
+                    Level::WARN,
+                    "Skipping unreadable upload metadata {}: {e}",
+                    path.display()
+                ),
+            }
+        }
+        Ok(uploads)
+    }
+
+    async fn create(&self, upload: &PendingUpload) -> Result<()> {
+        fs::create_dir_all(&self.dir).await?;
+        fs::File::create_new(self.blob_path(&upload.id)).await?;
+        self.save_new(self.metadata_path(&upload.id), upload).await
+    }
+
+    async fn get(&self, uid: &UploadId) -> Result<PendingUpload> {
+        self.load(self.metadata_path(uid)).await
+    }
+
+    async fn append<R>(&self, upload: &mut PendingUpload, reader: R) -> Result<()>
+    where
+        R: AsyncRead + Unpin + Send,
+    {
+        let path = self.blob_path(&upload.id);
+        let mut file = fs::OpenOptions::new().write(true).open(&path).await?;
+        // Bytes past the saved offset were never acknowledged to the client.
+        let len = file.metadata().await?.len();
+        if len > upload.offset {
+            file.set_len(upload.offset).await?;
+            self.quota.release(len - upload.offset);
+        }
+        file.seek(SeekFrom::Start(upload.offset)).await?;
+
+        let (written, result) = self.write_chunks(&mut file, reader).await;
+        file.flush().await?;
+        file.sync_data().await?;
+        upload.offset += written;
+        self.save(self.metadata_path(&upload.id), upload).await?;
+        result
+    }
+
+    async fn open(&self, uid: &UploadId) -> Result<Box<dyn AsyncRead + Unpin + Send + Sync>> {
+        let file = fs::File::open(self.blob_path(uid)).await?;
+        Ok(Box::new(file))
+    }
+
+    async fn delete(&self, uid: &UploadId) -> Result<()> {
+        let path = self.blob_path(uid);
+        match fs::metadata(&path).await {
+            Ok(meta) => {
+                fs::remove_file(path).await?;
+                self.quota.release(meta.len());
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound => (),
+            Err(e) => return Err(Box::new(e)),
+        }
+        fs::remove_file(self.metadata_path(uid)).await?;
+        Ok(())
+    }
+
+    fn lend(&self, upload: &PendingUpload) {
+        self.quota.release(upload.offset);
+    }
+
+    fn reclaim(&self, upload: &PendingUpload) {
+        self.quota.hold(upload.offset);
+    }
+}
+
+impl BaseFsRepository for UploadFsRepository {}
+
+#[cfg(test)]
+mod tests {
+    use axum::body::Bytes;
+    use chrono::Utc;
+    use futures::stream;
+    use temp_dir::TempDir;
+    use tokio_util::io::StreamReader;
+
+    use crate::models::{
+        object::{FileInfo, Upload},
+        session::SessionId,
+    };
+
+    use super::*;
+
+    #[tokio::test]
+    async fn resume_after_interrupted_append() -> Result<()> {
+        let tmpdir = TempDir::new()?;
+        let repo = UploadFsRepository::new(tmpdir.path());
+        let mut upload = PendingUpload::new(
+            SessionId::generate(),
+            11,
+            Upload::default(),
+            FileInfo::default(),
+            Utc::now(),
+        );
+        repo.create(&upload).await?;
+
+        let broken = StreamReader::new(stream::iter(vec![
+            Ok(Bytes::from_static(b"Hello")),
+            Err(std::io::Error::from(ErrorKind::ConnectionReset)),
+        ]));
+        assert!(repo.append(&mut upload, broken).await.is_err());
+        assert_eq!(repo.get(&upload.id).await?.offset, 5);
+
+        repo.append(&mut upload, &b" world"[..]).await?;
+        assert!(upload.is_complete());
+        let mut buf = String::new();
+        repo.open(&upload.id)
+            .await?
+            .read_to_string(&mut buf)
+            .await?;
+        assert_eq!(buf, "Hello world");
+        assert_eq!(repo.list().await?.len(), 1);
+
+        repo.delete(&upload.id).await?;
+        assert!(repo.list().await?.is_empty());
+        Ok(())
+    }
+}
@@ -0,0 +1,92 @@
+use std::io::Cursor;
+
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+use crate::{
+    models::upload::{PendingUpload, UploadId},
+    repositories::{
+        memory::{not_found, MemoryStore, MemoryUpload},
+        Result,
+    },
+};
+
+use super::UploadRepository;
+
+const CHUNK_SIZE: usize = 64 * 1024;
+
+pub struct UploadMemoryRepository {
+    store: MemoryStore,
+}
+
+impl UploadMemoryRepository {
+    pub fn new(store: MemoryStore) -> Self {
+        Self { store }
+    }
+}
+
+impl UploadRepository for UploadMemoryRepository {
+    async fn list(&self) -> Result<Vec<PendingUpload>> {
+        let data = self.store.lock();
+        Ok(data.uploads.values().map(|u| u.upload.clone()).collect())
+    }
+
+    async fn create(&self, upload: &PendingUpload) -> Result<()> {
+        let entry = MemoryUpload {
+            upload: upload.clone(),
+            data: Vec::new(),
+        };
+        self.store.lock().uploads.insert(upload.id, entry);
+        Ok(())
+    }
+
+    async fn get(&self, uid: &UploadId) -> Result<PendingUpload> {
+        let data = self.store.lock();
+        let entry = data.uploads.get(uid).ok_or_else(not_found)?;
+        Ok(entry.upload.clone())
+    }
+
+    async fn append<R>(&self, upload: &mut PendingUpload, mut reader: R) -> Result<()>
+    where
+        R: AsyncRead + Unpin + Send,
+    {
+        let mut chunk = vec![0u8; CHUNK_SIZE];
+        loop {
+            let n = reader.read(&mut chunk).await?;
+            if n == 0 {
+                return Ok(());
+            }
+            self.store.reserve(n as u64)?;
+            // Every chunk is saved right away, so nothing is lost when the reader fails.
+            let mut data = self.store.lock();
+            let Some(entry) = data.uploads.get_mut(&upload.id) else {
+                drop(data);
+                self.store.release(n as u64);
+                return Err(not_found());
+            };
+            entry.data.extend_from_slice(&chunk[..n]);
+            upload.offset += n as u64;
+            entry.upload = upload.clone();
+        }
+    }
+
+    async fn open(&self, uid: &UploadId) -> Result<Box<dyn AsyncRead + Unpin + Send + Sync>> {
+        let data = self.store.lock();
+        let entry = data.uploads.get(uid).ok_or_else(not_found)?;
+        Ok(Box::new(Cursor::new(entry.data.clone())))
+    }
+
+    async fn delete(&self, uid: &UploadId) -> Result<()> {
+        let entry = self.store.lock().uploads.remove(uid);
+        let entry = entry.ok_or_else(not_found)?;
+        self.store.release(entry.data.len() as u64);
+        Ok(())
+    }
+
+    fn lend(&self, upload: &PendingUpload) {
+        self.store.release(upload.offset);
+    }
+
+    fn reclaim(&self, upload: &PendingUpload) {
+        self.store.hold(upload.offset);
+    }
+}
@@ -0,0 +1,44 @@
+mod any;
+mod fs;
+mod memory;
+
+use std::future::Future;
+
+use tokio::io::AsyncRead;
+
+use crate::models::upload::{PendingUpload, UploadId};
+
+use super::Result;
+
+pub use any::AnyUploadRepository;
+pub use fs::UploadFsRepository;
+pub use memory::UploadMemoryRepository;
+
+pub trait UploadRepository: Send + Sync {
+    fn list(&self) -> impl Future<Output = Result<Vec<PendingUpload>>>;
+
+    fn create(&self, upload: &PendingUpload) -> impl Future<Output = Result<()>>;
+
+    fn get(&self, uid: &UploadId) -> impl Future<Output = Result<PendingUpload>>;
+
+    /// Appends the bytes read from `reader` to the partial blob and saves the advanced
+    /// offset, even when the reader fails midway so that the client can resume from there.
+    fn append<R>(&self, upload: &mut PendingUpload, reader: R) -> impl Future<Output = Result<()>>
+    where
+        R: AsyncRead + Unpin + Send;
+
+    /// Streams the blob received so far.
+    fn open(
+        &self,
+        uid: &UploadId,
+    ) -> impl Future<Output = Result<Box<dyn AsyncRead + Unpin + Send + Sync>>>;
+
+    fn delete(&self, uid: &UploadId) -> impl Future<Output = Result<()>>;
+
+    /// Stops accounting the complete blob of the upload against the storage limits while it
+    /// is copied into an object, which accounts its bytes on its own.
+    fn lend(&self, upload: &PendingUpload);
+
+    /// Accounts the blob lent out by [`Self::lend`] again.
+    fn reclaim(&self, upload: &PendingUpload);
+}
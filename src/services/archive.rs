@@ -0,0 +1,539 @@
+use std::{
+    error::Error as StdError,
+    fmt::Display,
+    io::{self, ErrorKind},
+    result::Result as StdResult,
+    sync::Arc,
+};
+
+use axum::body::Bytes;
+use chrono::Utc;
+use futures::{stream, Stream, StreamExt, TryStreamExt};
+use serde_json::json;
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tracing::{event, Level};
+
+use crate::{
+    models::{
+        archive::{Manifest, ManifestObject, MANIFEST_PATH, MANIFEST_VERSION},
+        object::{Object, ObjectDto, ObjectId},
+        session::{Session, SessionId},
+    },
+    utils::tar::{self, TarReader},
+    ConcreteObjectService, ConcreteSessionService,
+};
+
+use super::{
+    object::{ObjectError, Result},
+    session::SessionError,
+};
+
+/// Largest manifest accepted on import, as it is read into memory.
+const MAX_MANIFEST_SIZE: u64 = 16 * 1024 * 1024;
+
+#[derive(Debug)]
+pub enum ImportError {
+    InvalidArchive(String),
+    Session(SessionError),
+    Object(ObjectError),
+}
+
+impl Display for ImportError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::InvalidArchive(msg) => write!(f, "Invalid archive: {msg}"),
+            Self::Session(e) => e.fmt(f),
+            Self::Object(e) => e.fmt(f),
+        }
+    }
+}
+
+impl StdError for ImportError {}
+
+/// Archive entry of an object, either streamed from its blob or holding its text or link.
+enum Entry {
+    Blob {
+        path: String,
+        oid: ObjectId,
+        size: u64,
+        mtime: i64,
+    },
+    Content {
+        path: String,
+        data: Vec<u8>,
+        mtime: i64,
+    },
+}
+
+/// Streams a tar archive of the session: the manifest first, followed by the blobs of the
+/// objects under their file names and their texts and links as `.txt` and `.url` files,
+/// oldest first. Blobs are only opened once the archive gets to them. Objects of
+/// end-to-end encrypted sessions are archived as the ciphertext and metadata they are
+/// stored as. Objects with a download limit are only listed in the manifest as omitted,
+/// without their content or blob, as reading them for the archive would have to count as a
+/// download.
+pub async fn export_session(
+    service: Arc<ConcreteObjectService>,
+    sess: Session,
+) -> Result<impl Stream<Item = io::Result<Bytes>> + Send> {
+    let objects = service.list().await?;
+    let mut entries = Vec::new();
+    let mut manifest = Manifest {
+        version: MANIFEST_VERSION,
+        creation_time: sess.creation_time,
+        expires_at: sess.expires_at,
+        crypto: sess.crypto.map(Into::into),
+        objects: Vec::with_capacity(objects.len()),
+    };
+    for mut obj in objects {
+        if obj.max_downloads.is_some() {
+            let mut object = obj.to_event_dto();
+            object.content = json!({ "kind": object.content["kind"] });
+            manifest.objects.push(ManifestObject {
+                object,
+                path: None,
+                omitted: true,
+            });
+            continue;
+        }
+        // Blobs uploaded before their size was recorded are measured by reading them.
+        if obj.size.is_none() && obj.has_blob() {
+            obj.size = Some(blob_size(&service, &obj.id).await?);
+        }
+        let entry = archive_entry(&obj);
+        let path = entry.as_ref().map(|entry| match entry {
+            Entry::Blob { path, .. } | Entry::Content { path, .. } => path.clone(),
+        });
+        entries.extend(entry);
+        manifest.objects.push(ManifestObject {
+            object: obj.into(),
+            path,
+            omitted: false,
+        });
+    }
+    let manifest = serde_json::to_vec(&manifest).map_err(|e| ObjectError::Other(Box::new(e)))?;
+    // Importing in archive order then restores the objects in their original order.
+    entries.reverse();
+
+    let entries = stream::iter(entries)
+        .then(move |entry| {
+            let service = Arc::clone(&service);
+            async move {
+                match entry {
+                    Entry::Blob {
+                        path,
+                        oid,
+                        size,
+                        mtime,
+                    } => {
+                        let reader = service.open(&oid).await.map_err(io::Error::other)?;
+                        Ok::<_, io::Error>(tar::entry(&path, size, mtime, reader).boxed())
+                    }
+                    Entry::Content { path, data, mtime } => {
+                        Ok(tar::file_entry(&path, mtime, data).boxed())
+                    }
+                }
+            }
+        })
+        .try_flatten();
+    let end = stream::once(async { Ok(Bytes::from_static(&tar::END_OF_ARCHIVE)) });
+    Ok(
+        tar::file_entry(MANIFEST_PATH, Utc::now().timestamp(), manifest)
+            .chain(entries)
+            .chain(end),
+    )
+}
+
+async fn blob_size(service: &ConcreteObjectService, oid: &ObjectId) -> Result<u64> {
+    let mut reader = service.open(oid).await?;
+    tokio::io::copy(&mut reader, &mut tokio::io::sink())
+        .await
+        .map_err(|e| ObjectError::Other(Box::new(e)))
+}
+
+/// Creates a session from an archive in the export format, read while it is streamed.
+/// The session and its objects get new ids but keep their timestamps, content and the
+/// crypto parameters of the original. Nothing is left behind when the import fails.
+pub async fn import_session<R, F>(
+    sessions: &ConcreteSessionService,
+    objects: F,
+    reader: R,
+) -> StdResult<Session, ImportError>
+where
+    R: AsyncRead + Unpin + Send + Sync,
+    F: Fn(&SessionId) -> Arc<ConcreteObjectService>,
+{
+    let mut archive = TarReader::new(reader);
+    let manifest = read_manifest(&mut archive).await?;
+    let sess = sessions
+        .restore(
+            manifest.creation_time,
+            manifest.expires_at,
+            manifest.crypto.map(Into::into),
+        )
+        .await
+        .map_err(ImportError::Session)?;
+
+    let service = objects(&sess.id);
+    if let Err(e) = import_objects(&service, &mut archive, manifest.objects).await {
+        if let Err(e) = sessions.delete(&sess.id).await {
+            event!(
+                Level::WARN,
+                "Failed to delete partially imported session {}: {e}",
+                sess.id
+            );
+        }
+        return Err(e);
+    }
+    Ok(sess)
+}
+
+async fn read_manifest<R: AsyncRead + Unpin>(
+    archive: &mut TarReader<R>,
+) -> StdResult<Manifest, ImportError> {
+    match archive.next_entry().await.map_err(invalid_archive)? {
+        Some((path, size)) if path == MANIFEST_PATH && size <= MAX_MANIFEST_SIZE => (),
+        _ => {
+            let msg = format!("{MANIFEST_PATH} must come first");
+            return Err(ImportError::InvalidArchive(msg));
+        }
+    }
+    let mut buf = Vec::new();
+    archive
+        .read_to_end(&mut buf)
+        .await
+        .map_err(invalid_archive)?;
+    let manifest: Manifest = serde_json::from_slice(&buf).map_err(invalid_archive)?;
+    if manifest.version != MANIFEST_VERSION {
+        let msg = format!("unsupported manifest version {}", manifest.version);
+        return Err(ImportError::InvalidArchive(msg));
+    }
+    Ok(manifest)
+}
+
+async fn import_objects<R: AsyncRead + Unpin + Send + Sync>(
+    service: &ConcreteObjectService,
+    archive: &mut TarReader<R>,
+    objects: Vec<ManifestObject>,
+) -> StdResult<(), ImportError> {
+    for ManifestObject {
+        object,
+        path,
+        omitted,
+    } in objects.into_iter().rev()
+    {
+        if omitted {
+            continue;
+        }
+        let has_blob = object.size.is_some();
+        match &path {
+            Some(path) => match archive.next_entry().await.map_err(invalid_archive)? {
+                Some((entry_path, _)) if &entry_path == path => (),
+                _ => return Err(ImportError::InvalidArchive(format!("{path} is missing"))),
+            },
+            None if has_blob => {
+                let msg = format!("blob of object {} is missing", object.id);
+                return Err(ImportError::InvalidArchive(msg));
+            }
+            None => (),
+        }
+        // Texts and links are restored from the manifest, their entries only mirror them.
+        let reader = has_blob.then_some(&mut *archive);
+        service
+            .restore(restored_object(object), reader)
+            .await
+            .map_err(|e| match e {
+                ObjectError::Other(e) if is_archive_error(&*e) => invalid_archive(e),
+                e => ImportError::Object(e),
+            })?;
+    }
+    // Also makes sure the content of the last entry was not cut off.
+    match archive.next_entry().await.map_err(invalid_archive)? {
+        Some((path, _)) => Err(ImportError::InvalidArchive(format!("unexpected {path}"))),
+        None => Ok(()),
+    }
+}
+
+fn restored_object(dto: ObjectDto) -> Object {
+    Object {
+        id: ObjectId::generate(),
+        timestamp: dto.timestamp,
+        content: dto.content,
+        size: None,
+        filename: dto.filename,
+        mime: dto.mime,
+        auth_key: dto.auth_key,
+        expires_at: dto.expires_at,
+        max_downloads: dto.max_downloads,
+        downloads: dto.downloads,
+        // Held to the digest it was exported with while the blob is stored.
+        sha256: dto.sha256,
+        compressed: false,
+        encrypted: false,
+    }
+}
+
+/// Whether reading the blob failed on a malformed or truncated archive.
+fn is_archive_error(err: &(dyn StdError + Send + Sync + 'static)) -> bool {
+    err.downcast_ref::<io::Error>()
+        .is_some_and(|e| matches!(e.kind(), ErrorKind::InvalidData | ErrorKind::UnexpectedEof))
+}
+
+fn invalid_archive<E: Display>(err: E) -> ImportError {
+    ImportError::InvalidArchive(err.to_string())
+}
+
+fn archive_entry(obj: &Object) -> Option<Entry> {
+    let mtime = obj.timestamp.timestamp();
+    if let Some(size) = obj.size {
+        let name = obj.filename.as_deref().and_then(sanitize_filename);
+        return Some(Entry::Blob {
+            path: format!("{}/{}", obj.id, name.unwrap_or("blob")),
+            oid: obj.id,
+            size,
+            mtime,
+        });
+    }
+    let (path, data) = match obj.content["kind"].as_str()? {
+        "text" => (
+            format!("{}.txt", obj.id),
+            obj.content["data"].as_str()?.to_owned(),
+        ),
+        "link" => (
+            format!("{}.url", obj.id),
+            format!(
+                "[InternetShortcut]\r\nURL={}\r\n",
+                obj.content["url"].as_str()?
+            ),
+        ),
+        _ => return None,
+    };
+    Some(Entry::Content {
+        path,
+        data: data.into_bytes(),
+        mtime,
+    })
+}
+
+/// Keeps only the last component of a file name reported by the client, so that archive
+/// entries cannot escape the directory of their object.
+fn sanitize_filename(name: &str) -> Option<&str> {
+    let name = name.rsplit(['/', '\\']).next()?;
+    (!matches!(name, "" | "." | "..")).then_some(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use temp_dir::TempDir;
+
+    use crate::{
+        models::{
+            crypto::KDFParams,
+            object::{FileInfo, Upload},
+            session::SessionCrypto,
+        },
+        repositories::{
+            memory::MemoryStore,
+            session::{AnySessionRepository, SessionMemoryRepository, SessionRepository},
+        },
+        services::{
+            session::SessionService,
+            testing::{fs_session, memory_session, memory_sessions, object_service},
+            websocket::WebSocketService,
+        },
+        ConcreteWebSocketService,
+    };
+
+    use super::*;
+
+    fn websocket_factory(_: &SessionId) -> Arc<ConcreteWebSocketService> {
+        let repository = SessionMemoryRepository::new(MemoryStore::default());
+        Arc::new(WebSocketService::new(
+            8,
+            Arc::new(AnySessionRepository::Memory(repository)),
+        ))
+    }
+
+    async fn export(service: Arc<ConcreteObjectService>, sess: Session) -> Vec<u8> {
+        export_session(service, sess)
+            .await
+            .unwrap()
+            .map_ok(|bytes| bytes.to_vec())
+            .try_concat()
+            .await
+            .unwrap()
+    }
+
+    /// Splits an archive made of plain ustar entries into their names and content.
+    fn archive_entries(archive: &[u8]) -> Vec<(String, Vec<u8>)> {
+        let mut entries = Vec::new();
+        let mut offset = 0;
+        while archive[offset] != 0 {
+            let header = &archive[offset..offset + 512];
+            let name = String::from_utf8_lossy(&header[..100]);
+            let size = std::str::from_utf8(&header[124..135]).unwrap();
+            let size = usize::from_str_radix(size, 8).unwrap();
+            let data = &archive[offset + 512..offset + 512 + size];
+            entries.push((name.trim_end_matches('\0').to_owned(), data.to_vec()));
+            offset += 512 + size.div_ceil(512) * 512;
+        }
+        assert_eq!(archive.len(), offset + 1024);
+        entries
+    }
+
+    #[tokio::test]
+    async fn export_session_as_tar() -> Result<()> {
+        let (sess, service) = memory_session(&MemoryStore::default()).await;
+        let file = FileInfo {
+            filename: Some("../../notes.md".into()),
+            mime: None,
+        };
+        let blob = service
+            .upload(Upload::default(), file, &b"# Notes"[..])
+            .await?;
+        let text = service
+            .put(Upload::new(json!({"kind": "text", "data": "Hello"}), false))
+            .await?;
+
+        let entries = archive_entries(&export(service, sess).await);
+        let names: Vec<_> = entries.iter().map(|(name, _)| name.as_str()).collect();
+        let blob_path = format!("{}/notes.md", blob.id);
+        let text_path = format!("{}.txt", text.id);
+        assert_eq!(names, vec![MANIFEST_PATH, &blob_path, &text_path]);
+        assert_eq!(entries[1].1, b"# Notes");
+        assert_eq!(entries[2].1, b"Hello");
+        let manifest: Manifest = serde_json::from_slice(&entries[0].1).unwrap();
+        assert_eq!(manifest.objects.len(), 2);
+        assert_eq!(
+            manifest.objects[1].path.as_deref(),
+            Some(blob_path.as_str())
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn export_legacy_and_limited_objects() -> Result<()> {
+        let tmpdir = TempDir::new().unwrap();
+        let (sess, service) = fs_session(tmpdir.path()).await;
+        // Uploaded before sizes were recorded, so only its content tells it has a blob.
+        let legacy = service
+            .put(Upload::new(json!({"kind": "file"}), false))
+            .await?;
+        let blob_path = tmpdir
+            .path()
+            .join(sess.id.to_string())
+            .join(legacy.id.to_string());
+        tokio::fs::write(&blob_path, b"Old blob").await.unwrap();
+        let limited = Upload {
+            max_downloads: Some(1),
+            ..Upload::new(json!({"kind": "file", "key": "c2VjcmV0"}), true)
+        };
+        let limited = service
+            .upload(limited, FileInfo::default(), &b"Burn after reading"[..])
+            .await?;
+
+        let entries = archive_entries(&export(Arc::clone(&service), sess.clone()).await);
+        let names: Vec<_> = entries.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(names, vec![MANIFEST_PATH, &format!("{}/blob", legacy.id)]);
+        assert_eq!(entries[1].1, b"Old blob");
+        let manifest: Manifest = serde_json::from_slice(&entries[0].1).unwrap();
+        assert_eq!(manifest.objects.len(), 2);
+        assert_eq!(manifest.objects[1].object.size, Some(8));
+        assert!(!manifest.objects[1].omitted);
+        let omitted = &manifest.objects[0];
+        assert!(omitted.omitted);
+        assert_eq!(omitted.object.id, limited.id);
+        assert_eq!(omitted.object.content, json!({"kind": "file"}));
+        assert_eq!((&omitted.path, &omitted.object.auth_key), (&None, &None));
+        assert_eq!(service.get(&limited.id).await?.downloads, 0);
+
+        // A blob that cannot be read fails the export instead of going missing from it.
+        tokio::fs::remove_file(blob_path).await.unwrap();
+        assert!(export_session(service, sess).await.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn import_exported_session() -> Result<()> {
+        let sessions = memory_sessions(&MemoryStore::default());
+        let session_service = SessionService::new(Arc::clone(&sessions), websocket_factory);
+        let crypto = SessionCrypto {
+            auth_key: "a2V5".into(),
+            kdf_params: KDFParams {
+                name: "PBKDF2".into(),
+                hash: "SHA-256".into(),
+                iterations: 100_000,
+                salt: "c2FsdA==".into(),
+            },
+        };
+        let sess = session_service
+            .restore(Utc::now(), None, Some(crypto.clone()))
+            .await
+            .unwrap();
+
+        let service = object_service(&sessions, sess.id);
+        let file = FileInfo {
+            filename: Some("notes.md".into()),
+            mime: Some("text/markdown".into()),
+        };
+        service
+            .upload(Upload::default(), file, &b"# Notes"[..])
+            .await?;
+        service
+            .put(Upload::new(json!({"kind": "text", "data": "Hello"}), false))
+            .await?;
+        service
+            .put(Upload::new(
+                json!({"kind": "link", "url": "https://example.com/"}),
+                false,
+            ))
+            .await?;
+        // Listed in the manifest as omitted, and so not restored.
+        let limited = Upload {
+            max_downloads: Some(1),
+            ..Upload::new(json!({"kind": "text", "data": "Once"}), false)
+        };
+        service.put(limited).await?;
+        let mut originals = service.list().await?;
+        originals.retain(|obj| obj.max_downloads.is_none());
+        let archive = export(service, sess.clone()).await;
+
+        let factory = |sid: &SessionId| object_service(&sessions, *sid);
+        let imported = import_session(&session_service, factory, &archive[..])
+            .await
+            .unwrap();
+        assert_ne!(imported.id, sess.id);
+        assert_eq!(imported.creation_time, sess.creation_time);
+        assert_eq!(imported.crypto, Some(crypto));
+
+        let service = object_service(&sessions, imported.id);
+        let restored = service.list().await?;
+        assert_eq!(restored.len(), originals.len());
+        for (restored, original) in restored.iter().zip(&originals) {
+            assert_ne!(restored.id, original.id);
+            assert_eq!(restored.timestamp, original.timestamp);
+            assert_eq!(restored.content, original.content);
+            assert_eq!(restored.filename, original.filename);
+            assert_eq!(restored.size, original.size);
+            assert_eq!(restored.sha256, original.sha256);
+        }
+        let mut blob = Vec::new();
+        service
+            .open(&restored[2].id)
+            .await?
+            .read_to_end(&mut blob)
+            .await
+            .unwrap();
+        assert_eq!(blob, b"# Notes");
+
+        // A truncated archive is rejected without leaving a session behind.
+        let truncated = &archive[..archive.len() - 1536];
+        let err = import_session(&session_service, factory, truncated)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, ImportError::InvalidArchive(_)), "{err}");
+        assert_eq!(sessions.list().await.unwrap().len(), 2);
+        Ok(())
+    }
+}
@@ -1,3 +1,7 @@
+pub mod archive;
 pub mod object;
 pub mod session;
+#[cfg(test)]
+pub(crate) mod testing;
+pub mod upload;
 pub mod websocket;
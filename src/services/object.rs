@@ -2,19 +2,21 @@ use std::{
     error::Error as StdError,
     fmt::Display,
     io::{Error as IoError, ErrorKind},
+    ops::Range,
     result::Result as StdResult,
     sync::Arc,
 };
 
+use chrono::Utc;
 use futures::TryFutureExt;
 use tokio::io::AsyncRead;
 
 use crate::{
     models::{
-        event::{Event, EventName},
-        object::{Object, ObjectId, Upload},
+        event::{Event, EventKind},
+        object::{FileInfo, Object, ObjectId, Upload},
     },
-    repositories::{object::ObjectRepository, session::SessionRepository},
+    repositories::{digest::DigestMismatch, object::ObjectRepository, session::SessionRepository},
 };
 
 use super::websocket::WebSocketService;
@@ -22,13 +24,19 @@ use super::websocket::WebSocketService;
 #[derive(Debug)]
 pub enum ObjectError {
     NotFound,
-    Other(Box<dyn StdError>),
+    QuotaExceeded,
+    StorageFull,
+    DigestMismatch,
+    Other(Box<dyn StdError + Send + Sync>),
 }
 
 impl Display for ObjectError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         let s = match self {
             Self::NotFound => "Object not found".to_owned(),
+            Self::QuotaExceeded => "Session quota exceeded".to_owned(),
+            Self::StorageFull => "Storage is full".to_owned(),
+            Self::DigestMismatch => "Upload does not match its digest".to_owned(),
             Self::Other(e) => e.to_string(),
         };
         f.write_str(&s)
@@ -41,7 +49,7 @@ pub type Result<T> = StdResult<T, ObjectError>;
 
 pub struct ObjectService<O, S> {
     repository: Arc<O>,
-    websocket: Arc<WebSocketService<S>>,
+    pub(super) websocket: Arc<WebSocketService<S>>,
 }
 
 impl<O, S> ObjectService<O, S> {
@@ -54,21 +62,6 @@ impl<O, S> ObjectService<O, S> {
 }
 
 impl<O: ObjectRepository, S> ObjectService<O, S> {
-    pub async fn list(&self) -> Result<Vec<Object>> {
-        normalize_result(self.repository.list().await)
-    }
-
-    pub async fn get(&self, oid: &ObjectId) -> Result<Object> {
-        normalize_result(self.repository.get(oid).await)
-    }
-
-    pub async fn download(
-        &self,
-        oid: &ObjectId,
-    ) -> Result<Box<dyn AsyncRead + Unpin + Send + Sync>> {
-        normalize_result(self.repository.download(oid).await)
-    }
-
     pub async fn object_auth(&self, oid: &ObjectId, auth_key: &[u8]) -> Result<bool> {
         if let Some(expected) = self
             .repository
@@ -76,7 +69,7 @@ impl<O: ObjectRepository, S> ObjectService<O, S> {
             .map_err(normalize_error)
             .await?
         {
-            Ok(auth_key == &expected)
+            Ok(auth_key == expected)
         } else {
             Ok(true)
         }
@@ -84,6 +77,89 @@ impl<O: ObjectRepository, S> ObjectService<O, S> {
 }
 
 impl<O: ObjectRepository, S: SessionRepository> ObjectService<O, S> {
+    /// Lists the objects of the session, deleting the ones that have expired. Objects
+    /// whose downloads are limited are listed without the content that reading them
+    /// through [`Self::access`] counts for.
+    pub async fn list(&self) -> Result<Vec<Object>> {
+        let now = Utc::now();
+        let mut objects = normalize_result(self.repository.list().await)?;
+        for obj in objects.iter().filter(|obj| obj.is_expired(now)) {
+            self.purge(&obj.id).await?;
+        }
+        objects.retain(|obj| !obj.is_expired(now));
+        Ok(objects
+            .into_iter()
+            .map(Object::without_limited_content)
+            .collect())
+    }
+
+    pub async fn get(&self, oid: &ObjectId) -> Result<Object> {
+        let obj = normalize_result(self.repository.get(oid).await)?;
+        if obj.is_expired(Utc::now()) {
+            self.purge(oid).await?;
+            return Err(ObjectError::NotFound);
+        }
+        Ok(obj)
+    }
+
+    /// Gets the object for a reader of its content, counting it as a download when the
+    /// content is all there is to download. Uploaded objects count once their blob is
+    /// downloaded instead.
+    pub async fn access(&self, oid: &ObjectId) -> Result<Object> {
+        let obj = self.get(oid).await?;
+        if obj.has_blob() {
+            return Ok(obj);
+        }
+        let obj = self.claim(oid).await?;
+        self.publish(EventKind::ObjectDownloaded(obj.to_event_dto()));
+        if obj.is_exhausted() {
+            self.purge(oid).await?;
+        }
+        Ok(obj)
+    }
+
+    /// Opens a reader for each of the byte ranges, or a single one for the whole blob when
+    /// no ranges are given. Reads of objects with a download limit always count against it.
+    /// Other objects count a single download unless none of the ranges start at the
+    /// beginning of the blob, as players seeking through it request many ranges for what is
+    /// only one download.
+    pub async fn download(
+        &self,
+        oid: &ObjectId,
+        ranges: &[Range<u64>],
+    ) -> Result<(Object, Vec<Box<dyn AsyncRead + Unpin + Send + Sync>>)> {
+        let obj = self.get(oid).await?;
+        let counted = obj.max_downloads.is_some()
+            || ranges.is_empty()
+            || ranges.iter().any(|range| range.start == 0);
+        let obj = match counted {
+            true => self.claim(oid).await?,
+            false => obj,
+        };
+        let mut readers = Vec::new();
+        if ranges.is_empty() {
+            readers.push(normalize_result(self.repository.download(oid, None).await)?);
+        }
+        for range in ranges {
+            let reader = self.repository.download(oid, Some(range.clone())).await;
+            readers.push(normalize_result(reader)?);
+        }
+        if !counted {
+            return Ok((obj, readers));
+        }
+        self.publish(EventKind::ObjectDownloaded(obj.to_event_dto()));
+        // Opened readers keep streaming the blob after it has been deleted.
+        if obj.is_exhausted() {
+            self.purge(oid).await?;
+        }
+        Ok((obj, readers))
+    }
+
+    /// Opens the whole blob without counting a download, as archiving the session does.
+    pub async fn open(&self, oid: &ObjectId) -> Result<Box<dyn AsyncRead + Unpin + Send + Sync>> {
+        normalize_result(self.repository.download(oid, None).await)
+    }
+
     pub async fn put(&self, upload: Upload) -> Result<Object> {
         let obj = upload.into();
         normalize_result(
@@ -94,42 +170,318 @@ impl<O: ObjectRepository, S: SessionRepository> ObjectService<O, S> {
         )
     }
 
-    pub async fn upload<R>(&self, upload: Upload, reader: R) -> Result<Object>
+    pub async fn upload<R>(&self, upload: Upload, file: FileInfo, reader: R) -> Result<Object>
     where
         R: AsyncRead + Unpin + Send + Sync,
     {
-        let obj = upload.into();
+        // Only uploads are held to the digest, the repository replaces it with the actual one.
+        let expected = upload.sha256.clone();
+        let mut obj: Object = upload.into();
+        obj.sha256 = expected;
+        obj.filename = file.filename;
+        obj.mime = file.mime;
         normalize_result(
             self.repository
-                .upload(&obj, reader)
+                .upload(&mut obj, reader)
                 .await
                 .map(|_| self.publish_object_created(obj)),
         )
     }
 
+    /// Stores an object restored from an archive as is, along with its blob when a reader
+    /// is given. The blob is held to the digest of the object like uploads are.
+    pub async fn restore<R>(&self, mut obj: Object, reader: Option<R>) -> Result<Object>
+    where
+        R: AsyncRead + Unpin + Send + Sync,
+    {
+        let res = match reader {
+            Some(reader) => self.repository.upload(&mut obj, reader).await,
+            None => self.repository.put(&obj).await,
+        };
+        normalize_result(res.map(|_| self.publish_object_created(obj)))
+    }
+
     pub async fn delete(&self, oid: &ObjectId) -> Result<()> {
-        normalize_result(self.repository.delete(oid).await.map(|_| {
-            let event = Event::new(EventName::ObjectDeleted, *oid);
-            self.websocket.publish(event);
-        }))
+        normalize_result(
+            self.repository
+                .delete(oid)
+                .await
+                .map(|_| self.publish(EventKind::ObjectDeleted(*oid))),
+        )
+    }
+
+    /// Counts a download against the limit of the object, failing once the limit has
+    /// been used up by earlier downloads.
+    async fn claim(&self, oid: &ObjectId) -> Result<Object> {
+        let obj = self.get(oid).await?;
+        if obj.max_downloads.is_none() {
+            return Ok(obj);
+        }
+        let obj = normalize_result(self.repository.record_download(oid).await)?;
+        match obj.max_downloads {
+            Some(max) if obj.downloads > max => Err(ObjectError::NotFound),
+            _ => {
+                self.publish(EventKind::ObjectUpdated(obj.to_event_dto()));
+                Ok(obj)
+            }
+        }
+    }
+
+    /// Deletes an object that is no longer available, tolerating a concurrent deletion.
+    async fn purge(&self, oid: &ObjectId) -> Result<()> {
+        match self.delete(oid).await {
+            Ok(_) | Err(ObjectError::NotFound) => Ok(()),
+            Err(e) => Err(e),
+        }
     }
 
     fn publish_object_created(&self, obj: Object) -> Object {
-        let event = Event::new(EventName::ObjectCreated, obj.id);
-        self.websocket.publish(event);
+        self.publish(EventKind::ObjectCreated(obj.to_event_dto()));
         obj
     }
+
+    fn publish(&self, kind: EventKind) {
+        self.websocket.publish(Event::new(kind));
+    }
 }
 
-fn normalize_result<T>(res: StdResult<T, Box<dyn StdError>>) -> Result<T> {
+fn normalize_result<T>(res: StdResult<T, Box<dyn StdError + Send + Sync>>) -> Result<T> {
     res.map_err(normalize_error)
 }
 
-fn normalize_error(err: Box<dyn StdError>) -> ObjectError {
+fn normalize_error(err: Box<dyn StdError + Send + Sync>) -> ObjectError {
+    if err.is::<DigestMismatch>() {
+        return ObjectError::DigestMismatch;
+    }
     if let Some(e) = err.downcast_ref::<IoError>() {
-        if e.kind() == ErrorKind::NotFound {
-            return ObjectError::NotFound;
+        match e.kind() {
+            ErrorKind::NotFound => return ObjectError::NotFound,
+            ErrorKind::QuotaExceeded => return ObjectError::QuotaExceeded,
+            ErrorKind::StorageFull => return ObjectError::StorageFull,
+            _ => (),
         }
     }
     ObjectError::Other(err)
 }
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeDelta;
+    use serde_json::json;
+    use sha2::{Digest, Sha256};
+    use tokio::io::AsyncReadExt;
+
+    use crate::{repositories::memory::MemoryStore, services::testing::memory_session};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn upload_download_and_delete() -> Result<()> {
+        let store = MemoryStore::default();
+        let (_, service) = memory_session(&store).await;
+        let subscriber = service.websocket.subscribe(None).1;
+
+        let obj = service
+            .upload(
+                Upload::default(),
+                FileInfo::default(),
+                &b"Hello service"[..],
+            )
+            .await?;
+        assert_eq!(service.list().await?, vec![obj.clone()]);
+
+        let mut buf = String::new();
+        let (_, mut readers) = service.download(&obj.id, &[]).await?;
+        let mut reader = readers.remove(0);
+        reader.read_to_string(&mut buf).await.unwrap();
+        assert_eq!(buf, "Hello service");
+
+        service.delete(&obj.id).await?;
+        assert!(matches!(
+            service.get(&obj.id).await,
+            Err(ObjectError::NotFound)
+        ));
+
+        let events = subscriber.pop().await.unwrap();
+        let names: Vec<_> = events
+            .iter()
+            .map(|e| serde_json::to_value(e).unwrap()["name"].clone())
+            .collect();
+        let expected = ["object.created", "object.downloaded", "object.deleted"];
+        assert_eq!(names, expected);
+        assert!(matches!(&events[0].kind, EventKind::ObjectCreated(dto) if dto.id == obj.id));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn verify_upload_digest() -> Result<()> {
+        let store = MemoryStore::default();
+        let (_, service) = memory_session(&store).await;
+
+        let digest = hex::encode(Sha256::digest(b"Hello digest"));
+        let obj = service
+            .upload(Upload::default(), FileInfo::default(), &b"Hello digest"[..])
+            .await?;
+        assert_eq!(obj.sha256.as_ref(), Some(&digest));
+
+        let upload = Upload {
+            sha256: Some(digest.to_uppercase()),
+            ..Default::default()
+        };
+        service
+            .upload(upload, FileInfo::default(), &b"Hello digest"[..])
+            .await?;
+        let upload = Upload {
+            sha256: Some(digest),
+            ..Default::default()
+        };
+        let res = service
+            .upload(upload, FileInfo::default(), &b"Hello d1gest"[..])
+            .await;
+        assert!(matches!(res, Err(ObjectError::DigestMismatch)));
+        assert_eq!(service.list().await?.len(), 2);
+        assert_eq!(store.used(), 24);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn expire_and_exhaust_objects() -> Result<()> {
+        let store = MemoryStore::default();
+        let (_, service) = memory_session(&store).await;
+
+        let once = Upload {
+            max_downloads: Some(1),
+            ..Default::default()
+        };
+        let once = service
+            .upload(once, FileInfo::default(), &b"Burn after reading"[..])
+            .await?;
+        let expired = Upload {
+            expires_at: Some(Utc::now() - TimeDelta::seconds(1)),
+            ..Default::default()
+        };
+        let expired = service.put(expired).await?;
+        assert_eq!(service.list().await?, vec![once.clone()]);
+
+        let secret = Upload {
+            content: json!({ "kind": "text", "data": "Burn after reading" }),
+            max_downloads: Some(1),
+            ..Default::default()
+        };
+        let secret = service.put(secret).await?;
+        let listed = service.list().await?;
+        assert_eq!(listed[0].content, json!({ "kind": "text" }));
+        assert_eq!(listed[1].content, once.content);
+        assert_eq!(service.access(&secret.id).await?.content, secret.content);
+        assert_eq!(service.list().await?, vec![once.clone()]);
+        assert_eq!(service.access(&once.id).await?, once);
+        assert!(matches!(
+            service.get(&expired.id).await,
+            Err(ObjectError::NotFound)
+        ));
+
+        let subscriber = service.websocket.subscribe(None).1;
+        let mut buf = String::new();
+        let (_, mut readers) = service.download(&once.id, &[]).await?;
+        let mut reader = readers.remove(0);
+        reader.read_to_string(&mut buf).await.unwrap();
+        assert_eq!(buf, "Burn after reading");
+        assert!(matches!(
+            service.download(&once.id, &[]).await,
+            Err(ObjectError::NotFound)
+        ));
+        assert!(service.list().await?.is_empty());
+
+        let events = subscriber.pop().await.unwrap();
+        assert!(matches!(&events[0].kind, EventKind::ObjectUpdated(dto) if dto.downloads == 1));
+        assert!(matches!(&events[1].kind, EventKind::ObjectDownloaded(dto) if dto.downloads == 1));
+        assert!(matches!(events[2].kind, EventKind::ObjectDeleted(oid) if oid == once.id));
+        assert_eq!(events.len(), 3);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn keep_secrets_out_of_events() -> Result<()> {
+        let store = MemoryStore::default();
+        let (_, service) = memory_session(&store).await;
+        let subscriber = service.websocket.subscribe(None).1;
+
+        let secret = Upload {
+            content: json!({ "kind": "text", "data": "Burn after reading" }),
+            generate_auth_key: Some(true),
+            max_downloads: Some(1),
+            ..Default::default()
+        };
+        let secret = service.put(secret).await?;
+        let auth_key = secret.auth_key.clone().unwrap();
+        assert_eq!(service.access(&secret.id).await?.content, secret.content);
+
+        let events = subscriber.pop().await.unwrap();
+        let names: Vec<_> = events
+            .iter()
+            .map(|e| serde_json::to_value(e).unwrap()["name"].clone())
+            .collect();
+        let expected = [
+            "object.created",
+            "object.updated",
+            "object.downloaded",
+            "object.deleted",
+        ];
+        assert_eq!(names, expected);
+        for event in events {
+            let json = serde_json::to_string(&event).unwrap();
+            assert!(!json.contains("Burn after reading"));
+            assert!(!json.contains(&auth_key));
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn count_ranged_reads() -> Result<()> {
+        let store = MemoryStore::default();
+        let (_, service) = memory_session(&store).await;
+        let subscriber = service.websocket.subscribe(None).1;
+
+        let video = service
+            .upload(
+                Upload::default(),
+                FileInfo::default(),
+                &b"Seekable video"[..],
+            )
+            .await?;
+        let mut buf = String::new();
+        for range in [0..4, 4..9, 9..14] {
+            let (_, mut readers) = service.download(&video.id, &[range]).await?;
+            readers[0].read_to_string(&mut buf).await.unwrap();
+        }
+        assert_eq!(buf, "Seekable video");
+        let names: Vec<_> = subscriber
+            .pop()
+            .await
+            .unwrap()
+            .iter()
+            .map(|e| serde_json::to_value(e).unwrap()["name"].clone())
+            .collect();
+        assert_eq!(names, ["object.created", "object.downloaded"]);
+
+        // Skipping the first byte must not get around the limit.
+        let upload = Upload {
+            max_downloads: Some(2),
+            ..Default::default()
+        };
+        let obj = service
+            .upload(upload, FileInfo::default(), &b"Burn after reading"[..])
+            .await?;
+        let tail = 1..18;
+        service
+            .download(&obj.id, std::slice::from_ref(&tail))
+            .await?;
+        assert_eq!(service.get(&obj.id).await?.downloads, 1);
+        service.download(&obj.id, &[1..4, 9..18]).await?;
+        assert!(matches!(
+            service.download(&obj.id, &[tail]).await,
+            Err(ObjectError::NotFound)
+        ));
+        Ok(())
+    }
+}
@@ -1,4 +1,5 @@
 use std::{
+    collections::VecDeque,
     error::Error as StdError,
     fmt::Display,
     io::{Error as IoError, ErrorKind},
@@ -6,12 +7,15 @@ use std::{
     sync::Arc,
 };
 
+use chrono::{DateTime, TimeDelta, Utc};
+use tracing::{event, Level};
+
 use crate::{
     models::{
-        event::EventName,
-        session::{CreateSession, Session, SessionId},
+        event::{Event, EventKind},
+        session::{CreateSession, Session, SessionCrypto, SessionId},
     },
-    registries::{OBJECT_SERVICES, WEBSOCKET_SERVICES},
+    registries::{OBJECT_REPOSITORIES, OBJECT_SERVICES, WEBSOCKET_SERVICES},
     repositories::session::SessionRepository,
     WebSocketServiceFactory,
 };
@@ -19,7 +23,7 @@ use crate::{
 #[derive(Debug)]
 pub enum SessionError {
     NotFound,
-    Other(Box<dyn StdError>),
+    Other(Box<dyn StdError + Send + Sync>),
 }
 
 impl Display for SessionError {
@@ -39,6 +43,7 @@ pub type Result<T> = StdResult<T, SessionError>;
 pub struct SessionService<R> {
     repository: Arc<R>,
     websocket: WebSocketServiceFactory,
+    default_ttl: Option<TimeDelta>,
 }
 
 impl<R: SessionRepository> SessionService<R> {
@@ -46,51 +51,115 @@ impl<R: SessionRepository> SessionService<R> {
         Self {
             repository,
             websocket,
+            default_ttl: None,
         }
     }
 
-    pub async fn create(&self, req: Option<CreateSession>) -> Result<Session> {
+    /// Sets the lifetime of sessions created without one.
+    pub fn with_default_ttl(mut self, ttl: Option<TimeDelta>) -> Self {
+        self.default_ttl = ttl;
+        self
+    }
+
+    pub async fn create(&self, req: CreateSession) -> Result<Session> {
         let sid = SessionId::generate();
-        let sess = Session::new(sid, req);
+        let sess = Session::new(sid, req, self.default_ttl);
+        normalize_result(self.repository.create(&sess).await.map(|_| sess))
+    }
+
+    /// Creates a session restored from an archive under a new id, keeping the creation
+    /// time, expiry and crypto parameters of the original.
+    pub async fn restore(
+        &self,
+        creation_time: DateTime<Utc>,
+        expires_at: Option<DateTime<Utc>>,
+        crypto: Option<SessionCrypto>,
+    ) -> Result<Session> {
+        let sess = Session {
+            id: SessionId::generate(),
+            objects: VecDeque::default(),
+            creation_time,
+            expires_at,
+            crypto,
+        };
         normalize_result(self.repository.create(&sess).await.map(|_| sess))
     }
 
+    /// Gets the session, which is treated as gone once it has expired even if it has not
+    /// been reaped yet.
     pub async fn get(&self, sid: &SessionId) -> Result<Session> {
-        normalize_result(self.repository.get(sid).await)
+        let sess = normalize_result(self.repository.get(sid).await)?;
+        if sess.is_expired(Utc::now()) {
+            return Err(SessionError::NotFound);
+        }
+        Ok(sess)
     }
 
+    /// Whether the session exists, treating expired sessions as gone like [`Self::get`] does.
     pub async fn exists(&self, sid: &SessionId) -> Result<bool> {
-        normalize_result(self.repository.exists(sid).await)
+        match self.get(sid).await {
+            Ok(_) => Ok(true),
+            Err(SessionError::NotFound) => Ok(false),
+            Err(e) => Err(e),
+        }
     }
 
     pub async fn delete(&self, sid: &SessionId) -> Result<()> {
         normalize_result(self.repository.delete(sid).await.map(|_| {
             let service = (self.websocket)(sid);
-            service.publish(EventName::SessionDeleted.into_event());
+            service.publish(Event::new(EventKind::SessionDeleted));
+            OBJECT_REPOSITORIES.write().unwrap().remove(sid);
             OBJECT_SERVICES.write().unwrap().remove(sid);
             WEBSOCKET_SERVICES.write().unwrap().remove(sid);
         }))
     }
 
+    /// Deletes every session that has expired by `now`, returning the deleted sessions.
+    pub async fn reap_expired(&self, now: DateTime<Utc>) -> Result<Vec<Session>> {
+        let mut reaped = Vec::new();
+        for sid in normalize_result(self.repository.list().await)? {
+            // A session that cannot be read or deleted must not keep the others from being
+            // reaped, it is tried again on the next sweep.
+            let sess = match normalize_result(self.repository.get(&sid).await) {
+                Ok(sess) => sess,
+                // Deleted concurrently since it was listed.
+                Err(SessionError::NotFound) => continue,
+                Err(e) => {
+                    event!(Level::ERROR, "Failed to load session {sid}: {e}");
+                    continue;
+                }
+            };
+            if !sess.is_expired(now) {
+                continue;
+            }
+            match self.delete(&sid).await {
+                Ok(_) | Err(SessionError::NotFound) => reaped.push(sess),
+                Err(e) => event!(Level::ERROR, "Failed to delete expired session {sid}: {e}"),
+            }
+        }
+        Ok(reaped)
+    }
+
     pub async fn session_auth(&self, sid: &SessionId, auth_key: &[u8]) -> Result<bool> {
+        self.get(sid).await?;
         if let Some(expected) = self
             .repository
             .auth_key(sid)
             .await
             .map_err(normalize_error)?
         {
-            Ok(auth_key == &expected)
+            Ok(auth_key == expected)
         } else {
             Ok(true)
         }
     }
 }
 
-fn normalize_result<T>(res: StdResult<T, Box<dyn StdError>>) -> Result<T> {
+fn normalize_result<T>(res: StdResult<T, Box<dyn StdError + Send + Sync>>) -> Result<T> {
     res.map_err(normalize_error)
 }
 
-fn normalize_error(err: Box<dyn StdError>) -> SessionError {
+fn normalize_error(err: Box<dyn StdError + Send + Sync>) -> SessionError {
     if let Some(e) = err.downcast_ref::<IoError>() {
         if e.kind() == ErrorKind::NotFound {
             return SessionError::NotFound;
@@ -98,3 +167,150 @@ fn normalize_error(err: Box<dyn StdError>) -> SessionError {
     }
     SessionError::Other(err)
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashMap,
+        sync::{LazyLock, Mutex},
+    };
+
+    use serde_json::json;
+    use temp_dir::TempDir;
+
+    use crate::{
+        repositories::{
+            encryption::MasterKey,
+            memory::MemoryStore,
+            session::{AnySessionRepository, SessionFsRepository, SessionMemoryRepository},
+        },
+        ConcreteWebSocketService,
+    };
+
+    use super::*;
+
+    static WEBSOCKETS: LazyLock<Mutex<HashMap<SessionId, Arc<ConcreteWebSocketService>>>> =
+        LazyLock::new(Default::default);
+
+    fn websocket_factory(sid: &SessionId) -> Arc<ConcreteWebSocketService> {
+        let mut services = WEBSOCKETS.lock().unwrap();
+        let service = services.entry(*sid).or_insert_with(|| {
+            let repository = SessionMemoryRepository::new(MemoryStore::default());
+            let repository = Arc::new(AnySessionRepository::Memory(repository));
+            Arc::new(ConcreteWebSocketService::new(8, repository))
+        });
+        Arc::clone(service)
+    }
+
+    #[tokio::test]
+    async fn create_get_and_delete() -> Result<()> {
+        let repository = Arc::new(SessionMemoryRepository::new(MemoryStore::default()));
+        let service = SessionService::new(repository, websocket_factory);
+
+        let sess = service.create(CreateSession::default()).await?;
+        let sid = sess.id;
+        assert!(service.exists(&sid).await?);
+        assert_eq!(service.get(&sid).await?, sess);
+        assert!(service.session_auth(&sid, &[]).await?);
+
+        let subscriber = websocket_factory(&sid).subscribe(None).1;
+        service.delete(&sid).await?;
+        assert!(!service.exists(&sid).await?);
+        assert!(matches!(
+            service.get(&sid).await,
+            Err(SessionError::NotFound)
+        ));
+
+        let events = subscriber.pop().await.unwrap();
+        assert!(matches!(events[0].kind, EventKind::SessionDeleted));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn reap_expired_sessions() -> Result<()> {
+        let repository = Arc::new(SessionMemoryRepository::new(MemoryStore::default()));
+        let service = SessionService::new(repository, websocket_factory)
+            .with_default_ttl(Some(TimeDelta::minutes(10)));
+
+        let short = service
+            .create(CreateSession {
+                ttl: Some(60),
+                ..Default::default()
+            })
+            .await?;
+        let long = service.create(CreateSession::default()).await?;
+        assert_eq!(
+            short.expires_at,
+            Some(short.creation_time + TimeDelta::seconds(60))
+        );
+
+        let expired = service
+            .create(CreateSession {
+                ttl: Some(0),
+                ..Default::default()
+            })
+            .await?;
+        assert!(!service.exists(&expired.id).await?);
+        assert!(matches!(
+            service.get(&expired.id).await,
+            Err(SessionError::NotFound)
+        ));
+        assert!(matches!(
+            service.session_auth(&expired.id, &[]).await,
+            Err(SessionError::NotFound)
+        ));
+
+        let subscriber = websocket_factory(&short.id).subscribe(None).1;
+        let now = short.creation_time + TimeDelta::minutes(5);
+        let mut reaped = service.reap_expired(now).await?;
+        reaped.sort_by_key(|sess| sess.creation_time);
+        assert_eq!(reaped, vec![short.clone(), expired]);
+        assert!(!service.exists(&short.id).await?);
+        assert!(service.exists(&long.id).await?);
+
+        let events = subscriber.pop().await.unwrap();
+        assert!(matches!(events[0].kind, EventKind::SessionDeleted));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn reap_past_unreadable_sessions() -> Result<()> {
+        let tmpdir = TempDir::new().unwrap();
+        let dir = tmpdir.path();
+        // Sealed with a key the service does not have, so it cannot be loaded.
+        SessionFsRepository::new(dir)
+            .with_master_key(Some(MasterKey::new(&[42; 32])))
+            .create(&Session::default())
+            .await
+            .unwrap();
+
+        let repository = Arc::new(SessionFsRepository::new(dir));
+        let service = SessionService::new(repository, websocket_factory);
+        let sess = service
+            .create(CreateSession {
+                ttl: Some(60),
+                ..Default::default()
+            })
+            .await?;
+        let now = sess.creation_time + TimeDelta::minutes(5);
+        assert_eq!(service.reap_expired(now).await?, vec![sess]);
+        Ok(())
+    }
+
+    #[test]
+    fn reject_partial_crypto() {
+        let kdf_params = json!({
+            "name": "PBKDF2",
+            "hash": "SHA-256",
+            "iterations": 100_000,
+            "salt": "c2FsdA==",
+        });
+        let parse = |value| serde_json::from_value::<CreateSession>(value);
+        let req = parse(json!({ "authKey": "a2V5", "kdfParams": kdf_params })).unwrap();
+        assert!(req.crypto.is_some());
+        assert!(parse(json!({ "ttl": 60 })).unwrap().crypto.is_none());
+        assert!(parse(json!({ "authKey": "a2V5" })).is_err());
+        assert!(parse(json!({ "kdfParams": kdf_params })).is_err());
+        assert!(parse(json!({ "authKey": "a2V5", "kdfParams": { "name": "PBKDF2" } })).is_err());
+    }
+}
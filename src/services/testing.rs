@@ -0,0 +1,55 @@
+use std::{path::Path, sync::Arc};
+
+use crate::{
+    models::session::{Session, SessionId},
+    repositories::{
+        memory::MemoryStore,
+        object::AnyObjectRepository,
+        session::{
+            AnySessionRepository, SessionFsRepository, SessionMemoryRepository, SessionRepository,
+        },
+    },
+    ConcreteObjectService,
+};
+
+use super::{object::ObjectService, websocket::WebSocketService};
+
+/// Session repository keeping everything in `store`.
+pub fn memory_sessions(store: &MemoryStore) -> Arc<AnySessionRepository> {
+    let repository = SessionMemoryRepository::new(store.clone());
+    Arc::new(AnySessionRepository::Memory(repository))
+}
+
+/// Object service of the session, publishing to a WebSocket service of its own.
+pub fn object_service(
+    sessions: &Arc<AnySessionRepository>,
+    sid: SessionId,
+) -> Arc<ConcreteObjectService> {
+    service_of(sessions, AnyObjectRepository::new(sessions, sid, ""))
+}
+
+/// Creates a session kept in `store`, returning it along with its object service.
+pub async fn memory_session(store: &MemoryStore) -> (Session, Arc<ConcreteObjectService>) {
+    let sessions = memory_sessions(store);
+    let sess = Session::default();
+    sessions.create(&sess).await.unwrap();
+    let service = object_service(&sessions, sess.id);
+    (sess, service)
+}
+
+/// Creates a session stored in `dir`, returning it along with its object service.
+pub async fn fs_session(dir: &Path) -> (Session, Arc<ConcreteObjectService>) {
+    let sessions = Arc::new(AnySessionRepository::Fs(SessionFsRepository::new(dir)));
+    let sess = Session::default();
+    sessions.create(&sess).await.unwrap();
+    let repository = AnyObjectRepository::new(&sessions, sess.id, dir.join(sess.id.to_string()));
+    (sess, service_of(&sessions, repository))
+}
+
+fn service_of(
+    sessions: &Arc<AnySessionRepository>,
+    repository: AnyObjectRepository,
+) -> Arc<ConcreteObjectService> {
+    let websocket = Arc::new(WebSocketService::new(8, Arc::clone(sessions)));
+    Arc::new(ObjectService::new(Arc::new(repository), websocket))
+}
@@ -0,0 +1,425 @@
+use std::{
+    collections::HashSet,
+    error::Error as StdError,
+    fmt::Display,
+    io::{self, Error as IoError, ErrorKind},
+    pin::Pin,
+    result::Result as StdResult,
+    sync::{Arc, Mutex},
+    task::{ready, Context, Poll},
+};
+
+use chrono::{DateTime, TimeDelta, Utc};
+use tokio::io::{AsyncRead, AsyncReadExt, ReadBuf, Take};
+
+use crate::{
+    models::{
+        object::{FileInfo, Object, Upload},
+        session::SessionId,
+        upload::{PendingUpload, UploadId},
+    },
+    repositories::{
+        object::ObjectRepository, session::SessionRepository, upload::UploadRepository,
+    },
+};
+
+use super::object::{ObjectError, ObjectService};
+
+#[derive(Debug)]
+pub enum UploadError {
+    NotFound,
+    OffsetMismatch,
+    Locked,
+    TooLarge,
+    Object(ObjectError),
+    Other(Box<dyn StdError + Send + Sync>),
+}
+
+impl Display for UploadError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let s = match self {
+            Self::NotFound => "Upload not found".to_owned(),
+            Self::OffsetMismatch => "Upload offset does not match".to_owned(),
+            Self::Locked => "Upload is being written to".to_owned(),
+            Self::TooLarge => "Upload exceeds the maximum size".to_owned(),
+            Self::Object(e) => e.to_string(),
+            Self::Other(e) => e.to_string(),
+        };
+        f.write_str(&s)
+    }
+}
+
+impl StdError for UploadError {}
+
+pub type Result<T> = StdResult<T, UploadError>;
+
+/// Receives blobs over several requests, handing each one to the object service once its
+/// final byte has arrived.
+pub struct UploadService<U> {
+    repository: Arc<U>,
+    expiry: TimeDelta,
+    max_size: Option<u64>,
+    busy: Arc<Mutex<HashSet<UploadId>>>,
+}
+
+impl<U: UploadRepository> UploadService<U> {
+    pub fn new(repository: Arc<U>, expiry: TimeDelta) -> Self {
+        Self {
+            repository,
+            expiry,
+            max_size: None,
+            busy: Arc::default(),
+        }
+    }
+
+    /// Sets the largest blob that may be uploaded, unlimited when unset.
+    pub fn with_max_size(mut self, max_size: Option<u64>) -> Self {
+        self.max_size = max_size;
+        self
+    }
+
+    pub fn max_size(&self) -> Option<u64> {
+        self.max_size
+    }
+
+    /// Starts an upload of `length` bytes, which is completed right away when empty.
+    pub async fn create<O, S>(
+        &self,
+        objects: &ObjectService<O, S>,
+        sid: SessionId,
+        length: u64,
+        upload: Upload,
+        file: FileInfo,
+    ) -> Result<(PendingUpload, Option<Object>)>
+    where
+        O: ObjectRepository,
+        S: SessionRepository,
+    {
+        if self.max_size.is_some_and(|max| length > max) {
+            return Err(UploadError::TooLarge);
+        }
+        let pending = PendingUpload::new(sid, length, upload, file, Utc::now() + self.expiry);
+        normalize_result(self.repository.create(&pending).await)?;
+        if !pending.is_complete() {
+            return Ok((pending, None));
+        }
+        let _guard = self.acquire(&pending.id)?;
+        self.complete(objects, pending).await
+    }
+
+    /// Gets an upload of the session that has not expired yet.
+    pub async fn get(&self, sid: &SessionId, uid: &UploadId) -> Result<PendingUpload> {
+        let pending = normalize_result(self.repository.get(uid).await)?;
+        if &pending.session_id != sid || pending.is_expired(Utc::now()) {
+            return Err(UploadError::NotFound);
+        }
+        Ok(pending)
+    }
+
+    /// Appends the bytes read from `reader` at `offset`, which must be where the upload
+    /// left off. A reader running past the length of the upload is sending something else
+    /// than was announced, so the upload is dropped instead of cutting its bytes off.
+    pub async fn append<O, S, R>(
+        &self,
+        objects: &ObjectService<O, S>,
+        sid: &SessionId,
+        uid: &UploadId,
+        offset: u64,
+        reader: R,
+    ) -> Result<(PendingUpload, Option<Object>)>
+    where
+        O: ObjectRepository,
+        S: SessionRepository,
+        R: AsyncRead + Unpin + Send,
+    {
+        let _guard = self.acquire(uid)?;
+        let mut pending = self.get(sid, uid).await?;
+        if pending.offset != offset {
+            return Err(UploadError::OffsetMismatch);
+        }
+        pending.expires_at = Utc::now() + self.expiry;
+        let reader = BoundedReader {
+            inner: reader.take(pending.length - pending.offset),
+        };
+        match normalize_result(self.repository.append(&mut pending, reader).await) {
+            Err(UploadError::TooLarge) => {
+                normalize_result(self.repository.delete(uid).await)?;
+                return Err(UploadError::TooLarge);
+            }
+            res => res?,
+        }
+        if !pending.is_complete() {
+            return Ok((pending, None));
+        }
+        self.complete(objects, pending).await
+    }
+
+    pub async fn delete(&self, sid: &SessionId, uid: &UploadId) -> Result<()> {
+        let _guard = self.acquire(uid)?;
+        self.get(sid, uid).await?;
+        normalize_result(self.repository.delete(uid).await)
+    }
+
+    /// Deletes every upload that has expired by `now`, returning the deleted uploads.
+    pub async fn reap_expired(&self, now: DateTime<Utc>) -> Result<Vec<PendingUpload>> {
+        let mut reaped = Vec::new();
+        for pending in normalize_result(self.repository.list().await)? {
+            if !pending.is_expired(now) {
+                continue;
+            }
+            // Being written to right now, its expiry is about to be pushed back.
+            let Ok(_guard) = self.acquire(&pending.id) else {
+                continue;
+            };
+            match normalize_result(self.repository.delete(&pending.id).await) {
+                Ok(_) | Err(UploadError::NotFound) => reaped.push(pending),
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(reaped)
+    }
+
+    /// Turns a fully received upload into an object. Uploads that can never be stored
+    /// are dropped, while other failures, a full storage included, keep it around so that
+    /// completion can be retried with an empty append.
+    async fn complete<O, S>(
+        &self,
+        objects: &ObjectService<O, S>,
+        pending: PendingUpload,
+    ) -> Result<(PendingUpload, Option<Object>)>
+    where
+        O: ObjectRepository,
+        S: SessionRepository,
+    {
+        let reader = normalize_result(self.repository.open(&pending.id).await)?;
+        let result = {
+            // The blob would otherwise count twice until the upload is deleted.
+            let _lent = Lent::new(&*self.repository, &pending);
+            objects
+                .upload(pending.upload.clone(), pending.file.clone(), reader)
+                .await
+        };
+        match result {
+            Ok(obj) => {
+                normalize_result(self.repository.delete(&pending.id).await)?;
+                Ok((pending, Some(obj)))
+            }
+            Err(ObjectError::Other(e)) => Err(UploadError::Other(e)),
+            Err(ObjectError::StorageFull) => Err(UploadError::Object(ObjectError::StorageFull)),
+            Err(e) => {
+                normalize_result(self.repository.delete(&pending.id).await)?;
+                Err(UploadError::Object(e))
+            }
+        }
+    }
+
+    /// Claims exclusive use of an upload until the guard is dropped, so that concurrent
+    /// requests cannot interleave their writes.
+    fn acquire(&self, uid: &UploadId) -> Result<UploadGuard> {
+        if !self.busy.lock().unwrap().insert(*uid) {
+            return Err(UploadError::Locked);
+        }
+        Ok(UploadGuard {
+            busy: Arc::clone(&self.busy),
+            uid: *uid,
+        })
+    }
+}
+
+struct UploadGuard {
+    busy: Arc<Mutex<HashSet<UploadId>>>,
+    uid: UploadId,
+}
+
+impl Drop for UploadGuard {
+    fn drop(&mut self) {
+        self.busy.lock().unwrap().remove(&self.uid);
+    }
+}
+
+/// Keeps the blob of an upload lent out of the storage accounting until dropped.
+struct Lent<'a, U: UploadRepository> {
+    repository: &'a U,
+    pending: &'a PendingUpload,
+}
+
+impl<'a, U: UploadRepository> Lent<'a, U> {
+    fn new(repository: &'a U, pending: &'a PendingUpload) -> Self {
+        repository.lend(pending);
+        Self {
+            repository,
+            pending,
+        }
+    }
+}
+
+impl<U: UploadRepository> Drop for Lent<'_, U> {
+    fn drop(&mut self) {
+        self.repository.reclaim(self.pending);
+    }
+}
+
+/// Reads up to the limit of `inner`, failing with [`ErrorKind::FileTooLarge`] when the
+/// underlying reader has more bytes past it.
+struct BoundedReader<R> {
+    inner: Take<R>,
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for BoundedReader<R> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if this.inner.limit() > 0 {
+            return Pin::new(&mut this.inner).poll_read(cx, buf);
+        }
+        let mut probe = [0u8; 1];
+        let mut probe = ReadBuf::new(&mut probe);
+        ready!(Pin::new(this.inner.get_mut()).poll_read(cx, &mut probe))?;
+        match probe.filled().is_empty() {
+            true => Poll::Ready(Ok(())),
+            false => Poll::Ready(Err(ErrorKind::FileTooLarge.into())),
+        }
+    }
+}
+
+fn normalize_result<T>(res: StdResult<T, Box<dyn StdError + Send + Sync>>) -> Result<T> {
+    res.map_err(normalize_error)
+}
+
+fn normalize_error(err: Box<dyn StdError + Send + Sync>) -> UploadError {
+    if let Some(e) = err.downcast_ref::<IoError>() {
+        match e.kind() {
+            ErrorKind::NotFound => return UploadError::NotFound,
+            ErrorKind::StorageFull => return UploadError::Object(ObjectError::StorageFull),
+            ErrorKind::FileTooLarge => return UploadError::TooLarge,
+            _ => (),
+        }
+    }
+    UploadError::Other(err)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        repositories::{memory::MemoryStore, upload::UploadMemoryRepository},
+        services::testing::memory_session,
+    };
+
+    use super::*;
+
+    #[tokio::test]
+    async fn resumable_upload_lifecycle() -> Result<()> {
+        let store = MemoryStore::default();
+        let (sess, objects) = memory_session(&store).await;
+        let subscriber = objects.websocket.subscribe(None).1;
+        let uploads = Arc::new(UploadMemoryRepository::new(store));
+        let service = UploadService::new(uploads, TimeDelta::hours(1)).with_max_size(Some(64));
+
+        assert!(matches!(
+            service
+                .create(
+                    &objects,
+                    sess.id,
+                    65,
+                    Upload::default(),
+                    FileInfo::default()
+                )
+                .await,
+            Err(UploadError::TooLarge)
+        ));
+        let (pending, _) = service
+            .create(
+                &objects,
+                sess.id,
+                11,
+                Upload::default(),
+                FileInfo::default(),
+            )
+            .await?;
+        let uid = pending.id;
+        assert!(matches!(
+            service.get(&SessionId::generate(), &uid).await,
+            Err(UploadError::NotFound)
+        ));
+
+        let (pending, obj) = service
+            .append(&objects, &sess.id, &uid, 0, &b"Hello"[..])
+            .await?;
+        assert_eq!((pending.offset, obj), (5, None));
+        assert!(matches!(
+            service
+                .append(&objects, &sess.id, &uid, 0, &b"Hello"[..])
+                .await,
+            Err(UploadError::OffsetMismatch)
+        ));
+        assert!(objects.list().await.unwrap().is_empty());
+
+        let (_, obj) = service
+            .append(&objects, &sess.id, &uid, 5, &b" world"[..])
+            .await?;
+        let obj = obj.unwrap();
+        assert_eq!(obj.size, Some(11));
+        assert!(matches!(
+            service.get(&sess.id, &uid).await,
+            Err(UploadError::NotFound)
+        ));
+
+        let (pending, _) = service
+            .create(&objects, sess.id, 5, Upload::default(), FileInfo::default())
+            .await?;
+        assert!(matches!(
+            service
+                .append(&objects, &sess.id, &pending.id, 0, &b"Hello world"[..])
+                .await,
+            Err(UploadError::TooLarge)
+        ));
+        assert!(matches!(
+            service.get(&sess.id, &pending.id).await,
+            Err(UploadError::NotFound)
+        ));
+
+        let (pending, _) = service
+            .create(&objects, sess.id, 8, Upload::default(), FileInfo::default())
+            .await?;
+        assert!(service.reap_expired(Utc::now()).await?.is_empty());
+        let later = Utc::now() + TimeDelta::hours(2);
+        let reaped = service.reap_expired(later).await?;
+        assert_eq!(reaped.len(), 1);
+        assert_eq!(reaped[0].id, pending.id);
+
+        let events = subscriber.pop().await.unwrap();
+        let names: Vec<_> = events
+            .iter()
+            .map(|e| serde_json::to_value(e).unwrap()["name"].clone())
+            .collect();
+        assert_eq!(names, ["object.created"]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn complete_close_to_the_storage_limit() -> Result<()> {
+        let store = MemoryStore::new(Some(16));
+        let (sess, objects) = memory_session(&store).await;
+        let uploads = Arc::new(UploadMemoryRepository::new(store.clone()));
+        let service = UploadService::new(uploads, TimeDelta::hours(1));
+
+        let (pending, _) = service
+            .create(
+                &objects,
+                sess.id,
+                11,
+                Upload::default(),
+                FileInfo::default(),
+            )
+            .await?;
+        let (_, obj) = service
+            .append(&objects, &sess.id, &pending.id, 0, &b"Hello world"[..])
+            .await?;
+        assert_eq!(obj.unwrap().size, Some(11));
+        assert_eq!(store.used(), 11);
+        Ok(())
+    }
+}
@@ -1,19 +1,84 @@
-use std::{error::Error, fmt::Display, result::Result as StdResult, sync::Arc};
+use std::{
+    collections::VecDeque,
+    error::Error,
+    fmt::Display,
+    result::Result as StdResult,
+    sync::{Arc, Mutex},
+};
+
+use chrono::Utc;
 
 use crate::{
-    models::{event::Event, session::SessionId},
+    models::{
+        event::{Event, EventId, EventKind},
+        session::SessionId,
+    },
     repositories::session::SessionRepository,
     utils::sync::{PubSub, Subscriber},
 };
 
 pub struct WebSocketService<R> {
     pubsub: PubSub<Event>,
+    log: Mutex<EventLog>,
     repository: Arc<R>,
 }
 
+/// The latest events of a session, replayed to clients that reconnect after missing some.
+struct EventLog {
+    events: VecDeque<Event>,
+    capacity: usize,
+    next_id: EventId,
+}
+
+impl EventLog {
+    fn new(capacity: usize) -> Self {
+        Self {
+            events: VecDeque::with_capacity(capacity),
+            capacity,
+            // Starting from the clock keeps ids increasing across restarts of the server,
+            // which makes ids of a previous run look too old rather than replaying wrongly.
+            next_id: Utc::now().timestamp_micros() as EventId,
+        }
+    }
+
+    fn append(&mut self, mut event: Event) -> Event {
+        event.id = self.next_id;
+        self.next_id += 1;
+        if self.events.len() == self.capacity {
+            self.events.pop_front();
+        }
+        if self.capacity > 0 {
+            self.events.push_back(event.clone());
+        }
+        event
+    }
+
+    /// Events published after `since`, or a single resync event when some of them are no
+    /// longer in the log or `since` was never published.
+    fn replay(&self, since: EventId) -> Vec<Event> {
+        let first = self.events.front().map_or(self.next_id, |event| event.id);
+        if since >= self.next_id || since + 1 < first {
+            return vec![self.resync()];
+        }
+        self.events
+            .iter()
+            .filter(|event| event.id > since)
+            .cloned()
+            .collect()
+    }
+
+    /// Tells a client to reload the session, carrying the id of the latest event so that
+    /// it can resume from there.
+    fn resync(&self) -> Event {
+        let mut resync = Event::new(EventKind::Resync);
+        resync.id = self.next_id - 1;
+        resync
+    }
+}
+
 #[derive(Debug)]
 pub enum WebSocketError {
-    Other(Box<dyn Error>),
+    Other(Box<dyn Error + Send + Sync>),
 }
 
 impl Display for WebSocketError {
@@ -30,18 +95,33 @@ impl Error for WebSocketError {}
 pub type Result<T> = StdResult<T, WebSocketError>;
 
 impl<R: SessionRepository> WebSocketService<R> {
+    /// Creates a service whose subscribers and event log each hold up to `backlog` events.
     pub fn new(backlog: usize, repository: Arc<R>) -> Self {
         Self {
             pubsub: PubSub::new(backlog),
+            log: Mutex::new(EventLog::new(backlog)),
             repository,
         }
     }
 
-    pub fn subscribe(&self) -> Arc<Subscriber<Event>> {
-        self.pubsub.subscribe()
+    /// Subscribes to the events published from now on. When `since` is given, the events
+    /// published after it are returned to be delivered first.
+    pub fn subscribe(&self, since: Option<EventId>) -> (Vec<Event>, Arc<Subscriber<Event>>) {
+        // Holding the log keeps events from being published between the replay and the
+        // subscription, where they would be missed or delivered twice.
+        let log = self.log.lock().unwrap();
+        let missed = since.map(|since| log.replay(since)).unwrap_or_default();
+        (missed, self.pubsub.subscribe())
+    }
+
+    /// Event for a subscriber that lagged behind and missed events.
+    pub fn resync(&self) -> Event {
+        self.log.lock().unwrap().resync()
     }
 
     pub fn publish(&self, event: Event) {
+        let mut log = self.log.lock().unwrap();
+        let event = log.append(event);
         self.pubsub.publish(&event);
     }
 
@@ -52,9 +132,70 @@ impl<R: SessionRepository> WebSocketService<R> {
             .await
             .map_err(WebSocketError::Other)?
         {
-            Ok(auth_key == &expected)
+            Ok(auth_key == expected)
         } else {
             Ok(true)
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        models::{event::EVENT_VERSION, object::ObjectId},
+        repositories::{
+            memory::MemoryStore,
+            session::{AnySessionRepository, SessionMemoryRepository},
+        },
+        utils::sync::Lagged,
+    };
+
+    use super::*;
+
+    #[tokio::test]
+    async fn replay_missed_events() {
+        let repository = SessionMemoryRepository::new(MemoryStore::default());
+        let repository = Arc::new(AnySessionRepository::Memory(repository));
+        let service = WebSocketService::new(2, repository);
+
+        let (missed, subscriber) = service.subscribe(None);
+        assert!(missed.is_empty());
+        for _ in 0..3 {
+            service.publish(Event::new(EventKind::ObjectDeleted(ObjectId::generate())));
+        }
+        // The subscriber lagged behind, and only the latest events fit in the log.
+        assert!(matches!(subscriber.pop().await, Err(Lagged(2))));
+        let last = subscriber.pop().await.unwrap()[0].id;
+        let ids = [last - 1, last];
+
+        let (missed, _) = service.subscribe(Some(ids[0]));
+        assert_eq!(
+            missed.iter().map(|e| e.id).collect::<Vec<_>>(),
+            vec![ids[1]]
+        );
+        let (missed, _) = service.subscribe(Some(ids[1]));
+        assert!(missed.is_empty());
+
+        // The first event fell out of the log, and later ids were never published.
+        for since in [ids[0] - 2, ids[1] + 1] {
+            let (missed, _) = service.subscribe(Some(since));
+            assert_eq!(missed.len(), 1);
+            assert!(matches!(missed[0].kind, EventKind::Resync));
+            assert_eq!(missed[0].id, ids[1]);
+        }
+    }
+
+    #[test]
+    fn serialize_events() {
+        let oid = ObjectId::generate();
+        let event = Event::new(EventKind::ObjectDeleted(oid));
+        let json = serde_json::to_value(&event).unwrap();
+        assert_eq!(json["version"], EVENT_VERSION);
+        assert_eq!(json["name"], "object.deleted");
+        assert_eq!(json["data"], oid.as_u64());
+
+        let json = serde_json::to_string(&Event::new(EventKind::SessionDeleted)).unwrap();
+        let event: Event = serde_json::from_str(&json).unwrap();
+        assert!(matches!(event.kind, EventKind::SessionDeleted));
+    }
+}
@@ -1,19 +1,24 @@
 use std::{
     collections::{HashMap, VecDeque},
-    ops::DerefMut,
-    sync::{
-        atomic::{AtomicUsize, Ordering},
-        Arc, Mutex, RwLock,
-    },
+    error::Error,
+    fmt::Display,
+    io,
+    pin::Pin,
+    sync::{Arc, Mutex, PoisonError, RwLock, Weak},
+    task::{Context, Poll},
 };
 
-use tokio::sync::Notify;
+use tokio::{
+    io::{AsyncRead, ReadBuf},
+    sync::Notify,
+};
 
 pub type ChannelId = usize;
 
 struct InnerPubSub<T> {
     counter: ChannelId,
-    channels: HashMap<ChannelId, Arc<Subscriber<T>>>,
+    /// Subscribers unregister themselves when dropped, so they are not kept alive here.
+    channels: HashMap<ChannelId, Weak<Subscriber<T>>>,
 }
 
 impl<T> InnerPubSub<T> {
@@ -25,13 +30,31 @@ impl<T> InnerPubSub<T> {
     }
 }
 
+/// The subscriber fell behind by more than its backlog, so the given number of values
+/// were dropped and it has to catch up some other way.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Lagged(pub usize);
+
+impl Display for Lagged {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Subscriber lagged behind by {} values", self.0)
+    }
+}
+
+impl Error for Lagged {}
+
+struct Buffer<T> {
+    values: VecDeque<T>,
+    /// Values dropped since the last pop.
+    dropped: usize,
+}
+
 pub struct Subscriber<T> {
     pubsub: Arc<RwLock<InnerPubSub<T>>>,
     id: ChannelId,
-    buf: Mutex<VecDeque<T>>,
+    buf: Mutex<Buffer<T>>,
     notify: Notify,
     backlog: usize,
-    counter: AtomicUsize,
 }
 
 impl<T> Subscriber<T> {
@@ -39,34 +62,47 @@ impl<T> Subscriber<T> {
         Self {
             pubsub,
             id,
-            buf: Mutex::new(VecDeque::with_capacity(backlog)),
+            buf: Mutex::new(Buffer {
+                values: VecDeque::with_capacity(backlog),
+                dropped: 0,
+            }),
             notify: Notify::new(),
             backlog,
-            counter: AtomicUsize::new(0),
         }
     }
 
     fn push(&self, value: T) {
         let mut buf = self.buf.lock().unwrap();
-        if self.counter.load(Ordering::Relaxed) >= self.backlog {
-            buf.pop_front();
-            buf.push_back(value);
-        } else {
-            buf.push_back(value);
-            self.counter.fetch_add(1, Ordering::Relaxed);
+        if buf.values.len() >= self.backlog {
+            // Whatever is buffered is superseded by catching up, so only values published
+            // after the overflow are kept.
+            buf.dropped += buf.values.len();
+            buf.values.clear();
         }
+        buf.values.push_back(value);
+        drop(buf);
         self.notify.notify_one();
     }
 
-    pub async fn pop(&self) -> VecDeque<T> {
-        if self.counter.load(Ordering::Relaxed) <= 0 {
-            self.notify.notified().await;
+    /// Waits for values and takes all of them, or fails with [`Lagged`] once if values had
+    /// to be dropped since the last call. The values published after the overflow are
+    /// returned by the next call.
+    pub async fn pop(&self) -> Result<VecDeque<T>, Lagged> {
+        loop {
+            // Created before checking, so a push in between still wakes it up.
+            let notified = self.notify.notified();
+            {
+                let mut buf = self.buf.lock().unwrap();
+                if buf.dropped > 0 {
+                    return Err(Lagged(std::mem::take(&mut buf.dropped)));
+                }
+                if !buf.values.is_empty() {
+                    let values = VecDeque::with_capacity(self.backlog);
+                    return Ok(std::mem::replace(&mut buf.values, values));
+                }
+            }
+            notified.await;
         }
-        let mut cur = self.buf.lock().unwrap();
-        let mut buf = VecDeque::with_capacity(self.backlog);
-        std::mem::swap(&mut buf, cur.deref_mut());
-        self.counter.store(0, Ordering::Relaxed);
-        buf
     }
 }
 
@@ -82,7 +118,7 @@ pub struct PubSub<T> {
     backlog: usize,
 }
 
-impl<T: Clone + ?Sized> PubSub<T> {
+impl<T: Clone> PubSub<T> {
     pub fn new(backlog: usize) -> Self {
         Self {
             inner: Arc::new(RwLock::new(InnerPubSub::new())),
@@ -94,24 +130,54 @@ impl<T: Clone + ?Sized> PubSub<T> {
         let mut inner = self.inner.write().unwrap();
         let id = inner.counter;
         let ch = Arc::new(Subscriber::new(self.inner.clone(), id, self.backlog));
-        inner.channels.insert(id, ch.clone());
+        inner.channels.insert(id, Arc::downgrade(&ch));
         inner.counter += 1;
         ch
     }
 
     pub fn publish(&self, value: &T) {
-        let inner = self.inner.read().unwrap();
-        for ch in inner.channels.values() {
+        // Released before pushing, as dropping the last reference to a subscriber
+        // unregisters it and that needs the lock for writing.
+        let channels: Vec<_> = {
+            let inner = self.inner.read().unwrap();
+            inner.channels.values().filter_map(Weak::upgrade).collect()
+        };
+        for ch in channels {
             ch.push(value.to_owned());
         }
     }
 }
 
+/// Makes a reader that is only `Send` also `Sync`, which is sound as reading requires
+/// exclusive access anyway. Request bodies are such readers.
+pub struct SyncReader<R>(Mutex<R>);
+
+impl<R> SyncReader<R> {
+    pub fn new(inner: R) -> Self {
+        Self(Mutex::new(inner))
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for SyncReader<R> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let inner = self
+            .get_mut()
+            .0
+            .get_mut()
+            .unwrap_or_else(PoisonError::into_inner);
+        Pin::new(inner).poll_read(cx, buf)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use tokio::task::JoinError;
 
-    use super::PubSub;
+    use super::{Lagged, PubSub};
 
     #[tokio::test]
     async fn test_pubsub() -> Result<(), JoinError> {
@@ -121,7 +187,7 @@ mod tests {
 
         let channel = pubsub.subscribe();
         let handle = tokio::spawn(async move {
-            let mut values = channel.pop().await;
+            let mut values = channel.pop().await.unwrap();
             values.pop_front().unwrap()
         });
 
@@ -132,4 +198,63 @@ mod tests {
 
         Ok(())
     }
+
+    #[tokio::test]
+    async fn test_pubsub_overflow() {
+        let pubsub = PubSub::new(2);
+        let channel = pubsub.subscribe();
+        for value in 0..5 {
+            pubsub.publish(&value);
+        }
+        // Overflowing twice drops everything buffered before the last overflow.
+        assert_eq!(channel.pop().await, Err(Lagged(4)));
+        assert_eq!(channel.pop().await, Ok([4].into()));
+
+        pubsub.publish(&5);
+        pubsub.publish(&6);
+        assert_eq!(channel.pop().await, Ok([5, 6].into()));
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_concurrent_publish_pop() -> Result<(), JoinError> {
+        const COUNT: usize = 10_000;
+        let pubsub = PubSub::new(COUNT);
+        let channel = pubsub.subscribe();
+        let consumer = tokio::spawn(async move {
+            let mut received = Vec::with_capacity(COUNT);
+            while received.len() < COUNT {
+                let values = channel.pop().await.unwrap();
+                assert!(!values.is_empty());
+                received.extend(values);
+            }
+            received
+        });
+        let publisher = tokio::spawn(async move {
+            for value in 0..COUNT {
+                pubsub.publish(&value);
+                if value % 100 == 0 {
+                    tokio::task::yield_now().await;
+                }
+            }
+        });
+
+        publisher.await?;
+        let received = consumer.await?;
+        assert_eq!(received, (0..COUNT).collect::<Vec<_>>());
+        Ok(())
+    }
+
+    #[test]
+    fn test_unsubscribe_on_drop() {
+        let pubsub = PubSub::new(1);
+        let first = pubsub.subscribe();
+        let second = pubsub.subscribe();
+        assert_eq!(pubsub.inner.read().unwrap().channels.len(), 2);
+
+        drop(first);
+        pubsub.publish(&"Hello pubsub".to_owned());
+        assert_eq!(pubsub.inner.read().unwrap().channels.len(), 1);
+        drop(second);
+        assert!(pubsub.inner.read().unwrap().channels.is_empty());
+    }
 }
@@ -0,0 +1,374 @@
+use std::{
+    io,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use axum::body::Bytes;
+use futures::{stream, Stream, StreamExt};
+use tokio::io::{AsyncRead, AsyncReadExt, ReadBuf};
+use tokio_util::io::ReaderStream;
+
+const BLOCK_SIZE: usize = 512;
+const NAME_SIZE: usize = 100;
+
+/// Largest size that fits the 11 octal digits of the size field.
+const MAX_OCTAL_SIZE: u64 = 0o77777777777;
+
+const PAX_HEADER_NAME: &str = "././@PaxHeader";
+
+/// Two empty blocks terminate an archive.
+pub const END_OF_ARCHIVE: [u8; 2 * BLOCK_SIZE] = [0; 2 * BLOCK_SIZE];
+
+/// Streams a file entry of a ustar archive, with the content read from `reader`. The
+/// stream fails when the reader ends before `size` bytes, as the archive would otherwise
+/// be corrupted silently.
+pub fn entry<R>(
+    path: &str,
+    size: u64,
+    mtime: i64,
+    reader: R,
+) -> impl Stream<Item = io::Result<Bytes>> + Send
+where
+    R: AsyncRead + Unpin + Send + 'static,
+{
+    let reader = ExactReader {
+        inner: reader.take(size),
+        remaining: size,
+    };
+    let header = header(path, size, mtime);
+    stream::once(async move { Ok(header) })
+        .chain(ReaderStream::new(reader))
+        .chain(stream::once(async move { Ok(padding(size)) }))
+}
+
+/// Streams a file entry of a ustar archive with the content given in full.
+pub fn file_entry(
+    path: &str,
+    mtime: i64,
+    content: Vec<u8>,
+) -> impl Stream<Item = io::Result<Bytes>> + Send {
+    let size = content.len() as u64;
+    let mut buf = header(path, size, mtime).to_vec();
+    buf.extend(content);
+    buf.extend(padding(size));
+    stream::once(async move { Ok(Bytes::from(buf)) })
+}
+
+/// Header blocks of a regular file, preceded by a pax extended header carrying the path
+/// or size when they do not fit their fields.
+fn header(path: &str, size: u64, mtime: i64) -> Bytes {
+    let mut buf = Vec::with_capacity(3 * BLOCK_SIZE);
+    let mut records = Vec::new();
+    if path.len() > NAME_SIZE {
+        records.extend(pax_record("path", path));
+    }
+    if size > MAX_OCTAL_SIZE {
+        records.extend(pax_record("size", &size.to_string()));
+    }
+    if !records.is_empty() {
+        buf.extend(header_block(
+            PAX_HEADER_NAME,
+            records.len() as u64,
+            mtime,
+            b'x',
+        ));
+        buf.extend(&records);
+        buf.extend(padding(records.len() as u64));
+    }
+    buf.extend(header_block(path, size.min(MAX_OCTAL_SIZE), mtime, b'0'));
+    Bytes::from(buf)
+}
+
+fn header_block(path: &str, size: u64, mtime: i64, typeflag: u8) -> [u8; BLOCK_SIZE] {
+    let mut block = [0u8; BLOCK_SIZE];
+    let name = truncate(path, NAME_SIZE);
+    block[..name.len()].copy_from_slice(name.as_bytes());
+    write_octal(&mut block[100..108], 0o644);
+    write_octal(&mut block[108..116], 0);
+    write_octal(&mut block[116..124], 0);
+    write_octal(&mut block[124..136], size);
+    write_octal(&mut block[136..148], mtime.max(0) as u64);
+    block[148..156].fill(b' ');
+    block[156] = typeflag;
+    block[257..263].copy_from_slice(b"ustar\0");
+    block[263..265].copy_from_slice(b"00");
+    let checksum: u32 = block.iter().map(|&b| u32::from(b)).sum();
+    write_octal(&mut block[148..155], u64::from(checksum));
+    block
+}
+
+/// Writes the value as zero padded octal digits followed by a NUL terminator.
+fn write_octal(field: &mut [u8], value: u64) {
+    let digits = format!("{value:0width$o}", width = field.len() - 1);
+    let digits = &digits.as_bytes()[digits.len() - (field.len() - 1)..];
+    field[..digits.len()].copy_from_slice(digits);
+    field[digits.len()] = 0;
+}
+
+/// Formats a pax record, whose length prefix counts the digits of the length itself.
+fn pax_record(key: &str, value: &str) -> Vec<u8> {
+    let rest = key.len() + value.len() + 3;
+    let mut len = rest + 1;
+    while len != rest + len.to_string().len() {
+        len = rest + len.to_string().len();
+    }
+    format!("{len} {key}={value}\n").into_bytes()
+}
+
+fn truncate(s: &str, max: usize) -> &str {
+    let mut end = s.len().min(max);
+    while !s.is_char_boundary(end) {
+        end -= 1;
+    }
+    &s[..end]
+}
+
+fn padding(size: u64) -> Bytes {
+    let rem = (size % BLOCK_SIZE as u64) as usize;
+    Bytes::from(vec![0; (BLOCK_SIZE - rem) % BLOCK_SIZE])
+}
+
+/// Reads the regular files of a tar archive in order, reading the content of the current
+/// entry through its [`AsyncRead`] implementation. Pax extended headers and GNU long names
+/// are understood, other kinds of entries are skipped.
+pub struct TarReader<R> {
+    inner: R,
+    /// Bytes of the current entry not read yet.
+    remaining: u64,
+    /// Padding following the current entry.
+    padding: u64,
+}
+
+impl<R: AsyncRead + Unpin> TarReader<R> {
+    pub fn new(inner: R) -> Self {
+        Self {
+            inner,
+            remaining: 0,
+            padding: 0,
+        }
+    }
+
+    /// Advances to the next regular file, skipping whatever is left of the current one,
+    /// and returns its path and size. Returns `None` at the end of the archive.
+    pub async fn next_entry(&mut self) -> io::Result<Option<(String, u64)>> {
+        let mut long_path = None;
+        let mut long_size = None;
+        loop {
+            self.skip_rest().await?;
+            let mut block = [0u8; BLOCK_SIZE];
+            match self.inner.read_exact(&mut block).await {
+                Ok(_) => (),
+                // Archives cut off right after an entry are tolerated like empty blocks.
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof && long_path.is_none() => {
+                    return Ok(None)
+                }
+                Err(e) => return Err(e),
+            }
+            if block.iter().all(|&b| b == 0) {
+                return Ok(None);
+            }
+            let mut checksum_block = block;
+            checksum_block[148..156].fill(b' ');
+            let checksum: u64 = checksum_block.iter().map(|&b| u64::from(b)).sum();
+            if parse_number(&block[148..156]) != Some(checksum) {
+                return Err(invalid_data("tar header checksum mismatch"));
+            }
+            let size = parse_number(&block[124..136])
+                .ok_or_else(|| invalid_data("invalid size in tar header"))?;
+            self.remaining = size;
+            self.padding = padding(size).len() as u64;
+
+            match block[156] {
+                b'x' => {
+                    for (key, value) in parse_pax(&self.read_content().await?)? {
+                        match key.as_str() {
+                            "path" => long_path = Some(value),
+                            "size" => {
+                                long_size = Some(
+                                    value
+                                        .parse()
+                                        .map_err(|_| invalid_data("invalid pax size"))?,
+                                );
+                            }
+                            _ => (),
+                        }
+                    }
+                }
+                b'L' => {
+                    let name = self.read_content().await?;
+                    let name = name.split(|&b| b == 0).next().unwrap_or_default();
+                    long_path = Some(String::from_utf8_lossy(name).into_owned());
+                }
+                b'0' | 0 => {
+                    if let Some(size) = long_size {
+                        self.remaining = size;
+                        self.padding = padding(size).len() as u64;
+                    }
+                    let path = match long_path {
+                        Some(path) => path,
+                        None => header_path(&block),
+                    };
+                    return Ok(Some((path, self.remaining)));
+                }
+                _ => {
+                    long_path = None;
+                    long_size = None;
+                }
+            }
+        }
+    }
+
+    async fn read_content(&mut self) -> io::Result<Vec<u8>> {
+        let mut buf = Vec::new();
+        self.read_to_end(&mut buf).await?;
+        Ok(buf)
+    }
+
+    async fn skip_rest(&mut self) -> io::Result<()> {
+        let len = self.remaining + self.padding;
+        let skipped =
+            tokio::io::copy(&mut (&mut self.inner).take(len), &mut tokio::io::sink()).await?;
+        if skipped < len {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        self.remaining = 0;
+        self.padding = 0;
+        Ok(())
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for TarReader<R> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if this.remaining == 0 {
+            return Poll::Ready(Ok(()));
+        }
+        let max = buf
+            .remaining()
+            .min(this.remaining.try_into().unwrap_or(usize::MAX));
+        let mut limited = ReadBuf::new(buf.initialize_unfilled_to(max));
+        let result = Pin::new(&mut this.inner).poll_read(cx, &mut limited);
+        if let Poll::Ready(Ok(())) = result {
+            let read = limited.filled().len();
+            if read == 0 && max > 0 {
+                return Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into()));
+            }
+            buf.advance(read);
+            this.remaining -= read as u64;
+        }
+        result
+    }
+}
+
+/// Path from the name and ustar prefix fields of a header.
+fn header_path(block: &[u8; BLOCK_SIZE]) -> String {
+    let field = |range: std::ops::Range<usize>| {
+        let bytes = &block[range];
+        let end = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+        String::from_utf8_lossy(&bytes[..end]).into_owned()
+    };
+    let name = field(0..NAME_SIZE);
+    // GNU archives use the prefix field for other purposes and mark that differently.
+    let prefix = if &block[257..263] == b"ustar\0" {
+        field(345..500)
+    } else {
+        String::new()
+    };
+    match prefix.is_empty() {
+        true => name,
+        false => format!("{prefix}/{name}"),
+    }
+}
+
+/// Parses a numeric header field, either octal digits or base-256 for large values.
+fn parse_number(field: &[u8]) -> Option<u64> {
+    if field[0] & 0x80 != 0 {
+        let mut value = u64::from(field[0] & 0x7f);
+        for &b in &field[1..] {
+            value = value.checked_mul(256)? | u64::from(b);
+        }
+        return Some(value);
+    }
+    let digits = std::str::from_utf8(field).ok()?;
+    let digits = digits.trim_matches(|c: char| c == '\0' || c == ' ');
+    if digits.is_empty() {
+        return Some(0);
+    }
+    u64::from_str_radix(digits, 8).ok()
+}
+
+fn parse_pax(data: &[u8]) -> io::Result<Vec<(String, String)>> {
+    let mut records = Vec::new();
+    let mut rest = data;
+    while !rest.is_empty() {
+        let space = rest
+            .iter()
+            .position(|&b| b == b' ')
+            .ok_or_else(|| invalid_data("invalid pax record"))?;
+        let len: usize = std::str::from_utf8(&rest[..space])
+            .ok()
+            .and_then(|len| len.parse().ok())
+            .filter(|&len| len > space && len <= rest.len())
+            .ok_or_else(|| invalid_data("invalid pax record length"))?;
+        let record = std::str::from_utf8(&rest[space + 1..len - 1])
+            .map_err(|_| invalid_data("invalid pax record"))?;
+        if let Some((key, value)) = record.split_once('=') {
+            records.push((key.to_owned(), value.to_owned()));
+        }
+        rest = &rest[len..];
+    }
+    Ok(records)
+}
+
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// Fails with [`io::ErrorKind::UnexpectedEof`] when the inner reader ends early.
+struct ExactReader<R> {
+    inner: R,
+    remaining: u64,
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for ExactReader<R> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let filled = buf.filled().len();
+        let result = Pin::new(&mut this.inner).poll_read(cx, buf);
+        if let Poll::Ready(Ok(())) = result {
+            let read = (buf.filled().len() - filled) as u64;
+            if read == 0 && this.remaining > 0 {
+                return Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into()));
+            }
+            this.remaining -= read;
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn large_size_in_pax_header() {
+        let size = 16 * 1024 * 1024 * 1024;
+        let header = header("blob", size, 0);
+        assert_eq!(header.len(), 3 * BLOCK_SIZE);
+        assert_eq!(header[156], b'x');
+        let record = pax_record("size", &size.to_string());
+        assert_eq!(&header[BLOCK_SIZE..BLOCK_SIZE + record.len()], &record[..]);
+        let block = &header[2 * BLOCK_SIZE..];
+        assert_eq!(block[156], b'0');
+        assert_eq!(&block[124..136], b"77777777777\0");
+    }
+}
@@ -36,13 +36,23 @@ export interface ContentCipherParams {
 export interface Upload<C extends Content = Content> {
 	content: C;
 	generateAuthKey?: boolean;
+	expiresAt?: string;
+	maxDownloads?: number;
+	sha256?: string;
 }
 
 export interface FileObjectDto<C extends Content = Content> {
 	id: ObjectID;
 	timestamp: string;
 	content: C;
+	size?: number;
+	filename?: string;
+	mime?: string;
 	authKey?: string;
+	expiresAt?: string;
+	maxDownloads?: number;
+	downloads: number;
+	sha256?: string;
 }
 
 export interface FileObject<C extends Content = Content> {
@@ -55,6 +65,8 @@ export interface FileObject<C extends Content = Content> {
 
 export interface Session {
 	id: SessionID;
+	creationTime: string;
+	expiresAt?: string;
 	crypto?: SessionCrypto;
 }
 
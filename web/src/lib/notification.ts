@@ -1,12 +1,19 @@
 import { parseISO } from 'date-fns';
 
+/** Version of the event format this client understands. */
+export const EVENT_VERSION = 1;
+
 export interface NotificationEventDto<T = unknown> {
+	id: number;
+	version: number;
 	name: string;
 	data: T;
 	timestamp: string;
 }
 
 export interface NotificationEvent<T = unknown> {
+	id: number;
+	version: number;
 	name: string;
 	data: T;
 	timestamp: Date;
@@ -20,6 +27,8 @@ export interface NotificationHandlers {
 
 export const notificationEventFromDto = <T>(dto: NotificationEventDto<T>) => {
 	return {
+		id: dto.id,
+		version: dto.version,
 		name: dto.name,
 		data: dto.data,
 		timestamp: parseISO(dto.timestamp)